@@ -0,0 +1,77 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use smoldot::finality::justification::{decode, verify};
+
+/// Number of justifications verified during each iteration.
+const NUM_JUSTIFICATIONS: u64 = 1000;
+
+fn benchmark_authorities_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("justification_authorities_set");
+    group.throughput(Throughput::Elements(NUM_JUSTIFICATIONS));
+
+    for num_authorities in [10, 300] {
+        let (justification, authorities) =
+            decode::GrandpaJustification::synthetic(num_authorities, 1, 0, 4);
+        let set = verify::AuthoritiesSet::new(authorities.iter().map(|a| &a[..]), [0; 16]);
+
+        // The signatures aren't verified, as their verification would otherwise dwarf the cost
+        // of building the list of authorities.
+        let config = |authorities_set| verify::Config {
+            justification: (&justification).into(),
+            block_number_bytes: 4,
+            authorities_set_id: 0,
+            authorities_list: authorities.iter().map(|a| &a[..]),
+            authorities_list_len_hint: Some(authorities.len()),
+            authorities_set,
+            allowed_foreign_keys: &[],
+            randomness_seed: Some([0; 32]),
+            map_hash_seed: None,
+            skip_threshold_check: false,
+            expected_set_id_range: None,
+            target_header: None,
+            trusted_ancestor: None,
+            collect_all_errors: false,
+            header_hash_fn: None,
+            stop_at_threshold: false,
+            record_transcript: false,
+            verify_signatures: false,
+        };
+
+        group.bench_function(BenchmarkId::new("per_call", num_authorities), |b| {
+            b.iter(|| {
+                for _ in 0..NUM_JUSTIFICATIONS {
+                    verify::verify(config(None)).unwrap();
+                }
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("shared", num_authorities), |b| {
+            b.iter(|| {
+                for _ in 0..NUM_JUSTIFICATIONS {
+                    verify::verify(config(Some(&set))).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish()
+}
+
+criterion_group!(benches, benchmark_authorities_set);
+criterion_main!(benches);
@@ -93,7 +93,20 @@ impl<T> NonFinalizedTree<T> {
                     block_number_bytes: self.block_number_bytes,
                     authorities_set_id,
                     authorities_list,
-                    randomness_seed,
+                    authorities_list_len_hint: None,
+                    authorities_set: None,
+                    allowed_foreign_keys: &[],
+                    randomness_seed: Some(randomness_seed),
+                    map_hash_seed: None,
+                    skip_threshold_check: false,
+                    expected_set_id_range: None,
+                    target_header: None,
+                    trusted_ancestor: None,
+                    collect_all_errors: false,
+                    header_hash_fn: None,
+                    stop_at_threshold: false,
+                    record_transcript: false,
+                    verify_signatures: true,
                 })
                 .map_err(JustificationVerifyError::VerificationFailed)?;
 
@@ -179,6 +179,20 @@ impl StorageChanges {
             .flat_map(|list| list.diff_iter_unordered().map(|(k, v, ())| (k, v)))
     }
 
+    /// Returns an iterator to all the entries of the off-chain storage that are modified by this
+    /// runtime call through the off-chain indexing host functions, ordered by key.
+    ///
+    /// Each value is either `Some` if the runtime overwrites this value, or `None` if it erases
+    /// the underlying value.
+    pub fn offchain_storage_changes_iter_ordered(
+        &'_ self,
+    ) -> impl Iterator<Item = (&'_ [u8], Option<&'_ [u8]>)> + Clone + '_ {
+        self.inner
+            .offchain_storage_changes
+            .iter()
+            .map(|(k, v)| (&k[..], v.as_deref()))
+    }
+
     /// Returns an iterator over the list of all changes performed to the tries (main trie and
     /// child tries included).
     ///
@@ -16,3 +16,6 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod commit;
+pub mod neighbor_packet;
+pub mod signed_message;
+pub mod votes_ancestries;
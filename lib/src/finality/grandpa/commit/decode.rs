@@ -15,16 +15,37 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use alloc::vec::Vec;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{cmp, fmt};
 
 /// Attempt to decode the given SCALE-encoded Grandpa commit.
+///
+/// Returns an error if `block_number_bytes` is larger than 8.
+///
+/// If `block_number_bytes` is 0, it is detected using [`detect_block_number_bytes`]. This
+/// detection is best-effort, and passing the actual value should be preferred whenever it is
+/// known.
 pub fn decode_grandpa_commit(
     scale_encoded: &[u8],
     block_number_bytes: usize,
 ) -> Result<CommitMessageRef, Error> {
-    match nom::combinator::all_consuming(commit_message(block_number_bytes))(scale_encoded) {
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes(scale_encoded)?,
+        n => n,
+    };
+
+    check_block_number_bytes(block_number_bytes)?;
+
+    match nom::combinator::all_consuming(commit_message(
+        block_number_bytes,
+        AuthDataLayout::Interleaved,
+    ))(scale_encoded)
+    {
         Ok((_, commit)) => Ok(commit),
-        Err(err) => Err(Error(err)),
+        Err(err) => Err(Error::Decode(err)),
     }
 }
 
@@ -32,31 +53,356 @@ pub fn decode_grandpa_commit(
 ///
 /// Contrary to [`decode_grandpa_commit`], doesn't return an error if the slice is too long but
 /// returns the remainder.
+///
+/// Also contrary to [`decode_grandpa_commit`], `block_number_bytes` can't be 0, and
+/// [`Error::InvalidBlockNumberBytes`] is returned if it is. The number of bytes can't be detected,
+/// as the end of the commit isn't known.
 pub fn decode_partial_grandpa_commit(
     scale_encoded: &[u8],
     block_number_bytes: usize,
 ) -> Result<(CommitMessageRef, &[u8]), Error> {
-    match commit_message(block_number_bytes)(scale_encoded) {
+    check_block_number_bytes(block_number_bytes)?;
+
+    match commit_message(block_number_bytes, AuthDataLayout::Interleaved)(scale_encoded) {
         Ok((remainder, commit)) => Ok((commit, remainder)),
-        Err(err) => Err(Error(err)),
+        Err(err) => Err(Error::Decode(err)),
+    }
+}
+
+/// Attempt to decode the given Grandpa commit, encoded with signatures and public keys in
+/// separate lists.
+///
+/// Substrate-based chains, and consequently all the chains that smoldot is normally used with,
+/// encode the signatures and public keys of the pre-commits as a single list of
+/// `(signature, public_key)` pairs, which [`decode_grandpa_commit`] expects. This function
+/// instead decodes the list of all the signatures followed with the list of all the public
+/// keys, each prefixed with its length, into the same [`CommitMessageRef`].
+///
+/// > **Note**: No chain known to use this layout is documented here. It has only been tested
+/// >           against commits encoded by hand, and is meant for relaying finality from a
+/// >           GrandPa implementation known by the API user to use this layout.
+///
+/// An error is returned if the two lists don't have the same length. Apart from the layout, the
+/// behavior is identical to [`decode_grandpa_commit`], including the detection of the number of
+/// bytes used to encode block numbers if `block_number_bytes` is 0.
+pub fn decode_grandpa_commit_soa(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+) -> Result<CommitMessageRef, Error> {
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes_with(|n| {
+            nom::combinator::all_consuming::<_, _, nom::error::Error<&[u8]>, _>(commit_message(
+                n,
+                AuthDataLayout::StructOfArrays,
+            ))(scale_encoded)
+            .is_ok()
+        })?,
+        n => n,
+    };
+
+    check_block_number_bytes(block_number_bytes)?;
+
+    match nom::combinator::all_consuming(commit_message(
+        block_number_bytes,
+        AuthDataLayout::StructOfArrays,
+    ))(scale_encoded)
+    {
+        Ok((_, commit)) => Ok(commit),
+        Err(err) => Err(Error::Decode(err)),
+    }
+}
+
+/// Attempt to decode the given SCALE-encoded commit, reading at most the first `max_len` bytes
+/// of the input.
+///
+/// Like [`decode_partial_grandpa_commit`], doesn't return an error if the slice is too long but
+/// returns the remainder. The remainder can start before or after the first `max_len` bytes.
+///
+/// Returns [`Error::ExceedsBound`] if decoding the commit would require reading more than
+/// `max_len` bytes, for example because a length prefix announces more pre-commits than can
+/// possibly fit. No byte past `max_len` is ever read.
+///
+/// Like [`decode_partial_grandpa_commit`], `block_number_bytes` can't be 0.
+pub fn decode_grandpa_commit_bounded(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+    max_len: usize,
+) -> Result<(CommitMessageRef<'_>, &[u8]), Error<'_>> {
+    let bounded = &scale_encoded[..cmp::min(max_len, scale_encoded.len())];
+
+    match decode_partial_grandpa_commit(bounded, block_number_bytes) {
+        Ok((commit, remainder)) => {
+            let consumed = bounded.len() - remainder.len();
+            Ok((commit, &scale_encoded[consumed..]))
+        }
+        Err(Error::Decode(nom::Err::Incomplete(_))) if scale_encoded.len() > max_len => {
+            Err(Error::ExceedsBound)
+        }
+        Err(err) => Err(err),
     }
 }
 
+/// Attempt to decode the given SCALE-encoded list of Grandpa commits.
+///
+/// The input must consist of the number of commits, encoded as a SCALE-compact integer,
+/// followed with the commits themselves. An error is returned if the input contains fewer or
+/// more commits than declared.
+///
+/// If `block_number_bytes` is 0, it is detected similarly to [`decode_grandpa_commit`]. All the
+/// commits must use the same number of bytes.
+pub fn decode_grandpa_commits(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+) -> Result<Vec<CommitMessageRef>, Error> {
+    let decode = |block_number_bytes: usize| -> nom::IResult<_, _> {
+        nom::combinator::all_consuming(nom::combinator::flat_map(
+            crate::util::nom_scale_compact_usize,
+            |num_elems| {
+                nom::multi::many_m_n(
+                    num_elems,
+                    num_elems,
+                    commit_message(block_number_bytes, AuthDataLayout::Interleaved),
+                )
+            },
+        ))(scale_encoded)
+    };
+
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes_with(|n| decode(n).is_ok())?,
+        n => n,
+    };
+
+    check_block_number_bytes(block_number_bytes)?;
+
+    match decode(block_number_bytes) {
+        Ok((_, commits)) => Ok(commits),
+        Err(err) => Err(Error::Decode(err)),
+    }
+}
+
+/// Attempt to decode the given SCALE-encoded Grandpa commit, refusing commits that contain more
+/// than `max_precommits` pre-commits.
+///
+/// Contrary to [`decode_grandpa_commit`], the number of pre-commits that the commit declares is
+/// checked before any pre-commit is decoded. An error is also returned if this number is larger
+/// than the number of pre-commits that the input could possibly contain given its length. This
+/// avoids allocating memory based on a number provided by a potentially malicious source.
+///
+/// Apart from this check, the behavior is identical to [`decode_grandpa_commit`], including the
+/// detection of the number of bytes used to encode block numbers if `block_number_bytes` is 0.
+pub fn decode_grandpa_commit_limited(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+    max_precommits: usize,
+) -> Result<CommitMessageRef, Error> {
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes(scale_encoded)?,
+        n => n,
+    };
+
+    check_block_number_bytes(block_number_bytes)?;
+
+    // Decode the number of pre-commits, which comes after the round number, the set id, and the
+    // target of the commit.
+    let precommits_count_result: nom::IResult<_, _> = nom::sequence::preceded(
+        nom::bytes::streaming::take(8 + 8 + 32 + block_number_bytes),
+        crate::util::nom_scale_compact_usize,
+    )(scale_encoded);
+    let (remainder, num_precommits) = precommits_count_result.map_err(Error::Decode)?;
+
+    let max_precommits_from_len = remainder.len() / (32 + block_number_bytes);
+    if num_precommits > max_precommits || num_precommits > max_precommits_from_len {
+        return Err(Error::TooManyPrecommits);
+    }
+
+    decode_grandpa_commit(scale_encoded, block_number_bytes)
+}
+
+/// Attempt to decode the given SCALE-encoded Grandpa commit, then removes the pre-commits that
+/// are repeated.
+///
+/// A pre-commit is considered repeated if another pre-commit targets the same block and has the
+/// same signature and public key. Each pre-commit stays associated with its signature and public
+/// key. If the number of signatures doesn't match the number of pre-commits, the pre-commits
+/// can't be associated with signatures and nothing is removed.
+///
+/// Returns the decoded commit and the number of pre-commits that have been removed.
+///
+/// Apart from the removal of the duplicates, the behavior is identical to
+/// [`decode_grandpa_commit`].
+pub fn decode_grandpa_commit_dedup(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+) -> Result<(CommitMessageRef, usize), Error> {
+    let mut commit = decode_grandpa_commit(scale_encoded, block_number_bytes)?;
+
+    if commit.message.precommits.len() != commit.message.auth_data.len() {
+        return Ok((commit, 0));
+    }
+
+    let num_precommits_before = commit.message.precommits.len();
+
+    let mut known = BTreeSet::new();
+    let (precommits, auth_data) = commit
+        .message
+        .precommits
+        .drain(..)
+        .zip(commit.message.auth_data.drain(..))
+        .filter(|(precommit, (signature, public_key))| {
+            known.insert((
+                precommit.target_hash,
+                precommit.target_number,
+                *signature,
+                *public_key,
+            ))
+        })
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+    let num_removed = num_precommits_before - precommits.len();
+    commit.message.precommits = precommits;
+    commit.message.auth_data = auth_data;
+    Ok((commit, num_removed))
+}
+
+/// Decodes only the beginning of the given SCALE-encoded Grandpa commit, and returns its
+/// authorities set id and its round number, in that order.
+///
+/// Only the first 16 bytes are read, and the rest of the commit isn't validated. This is
+/// considerably cheaper than [`decode_grandpa_commit`], and can be used in order to sort
+/// commits before deciding which ones to decode entirely.
+pub fn decode_grandpa_commit_header(scale_encoded: &[u8]) -> Result<(u64, u64), Error<'_>> {
+    let result: nom::IResult<_, _> = nom::sequence::tuple((
+        nom::number::streaming::le_u64,
+        nom::number::streaming::le_u64,
+    ))(scale_encoded);
+    let (_, (round_number, set_id)) = result.map_err(Error::Decode)?;
+    Ok((set_id, round_number))
+}
+
+/// Finds the number of bytes used to encode block numbers in the given SCALE-encoded Grandpa
+/// commit.
+///
+/// Returns [`Error::BlockNumberBytesUnknown`] if the commit can't be decoded with any number of
+/// bytes, or if it can be decoded with more than one. The latter is unlikely but possible, as
+/// the commit doesn't contain the number of bytes.
+pub fn detect_block_number_bytes(scale_encoded: &[u8]) -> Result<usize, Error<'_>> {
+    detect_block_number_bytes_with(|n| {
+        nom::combinator::all_consuming::<_, _, nom::error::Error<&[u8]>, _>(commit_message(
+            n,
+            AuthDataLayout::Interleaved,
+        ))(scale_encoded)
+        .is_ok()
+    })
+}
+
+/// Returns the only number of bytes between 1 and 8 for which `decodes` returns `true`, or
+/// [`Error::BlockNumberBytesUnknown`] if there isn't exactly one.
+fn detect_block_number_bytes_with<'a>(
+    mut decodes: impl FnMut(usize) -> bool,
+) -> Result<usize, Error<'a>> {
+    let mut candidates = (1..=8).filter(|n| decodes(*n));
+
+    match (candidates.next(), candidates.next()) {
+        (Some(n), None) => Ok(n),
+        _ => Err(Error::BlockNumberBytesUnknown),
+    }
+}
+
+/// Returns an error if the given number of bytes used to encode block numbers can't be decoded
+/// into a `u64`.
+fn check_block_number_bytes<'a>(block_number_bytes: usize) -> Result<(), Error<'a>> {
+    if block_number_bytes == 0 || block_number_bytes > 8 {
+        return Err(Error::InvalidBlockNumberBytes);
+    }
+
+    Ok(())
+}
+
 /// Error potentially returned by [`decode_grandpa_commit`].
 #[derive(Debug, derive_more::Display)]
-pub struct Error<'a>(nom::Err<nom::error::Error<&'a [u8]>>);
+pub enum Error<'a> {
+    /// Failed to decode the commit.
+    #[display(fmt = "{_0}")]
+    Decode(nom::Err<nom::error::Error<&'a [u8]>>),
+    /// The number of bytes used to encode block numbers is 0 or larger than 8.
+    InvalidBlockNumberBytes,
+    /// The number of bytes used to encode block numbers is 0 and couldn't be detected. See
+    /// [`detect_block_number_bytes`].
+    BlockNumberBytesUnknown,
+    /// The commit contains more pre-commits than the limit passed to
+    /// [`decode_grandpa_commit_limited`], or than the input can possibly contain.
+    TooManyPrecommits,
+    /// Decoding the commit requires reading more bytes than the limit passed to
+    /// [`decode_grandpa_commit_bounded`].
+    ExceedsBound,
+}
 
 // TODO: document and explain
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CommitMessageRef<'a> {
     pub round_number: u64,
     pub set_id: u64,
     pub message: CompactCommitRef<'a>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl<'a> CommitMessageRef<'a> {
+    /// Returns the key used by [`CommitMessageRef::cmp_by_round_and_target`] to order commits,
+    /// in other words the round number, the set id, and the number of the target block, in that
+    /// order.
+    pub fn round_and_target_key(&self) -> (u64, u64, u64) {
+        (self.round_number, self.set_id, self.message.target_number)
+    }
+
+    /// Compares two commits first by round number, then by set id, then by target block number.
+    ///
+    /// This is typically useful in order to only keep the commit with the highest round when
+    /// receiving commits from multiple sources.
+    pub fn cmp_by_round_and_target(&self, other: &CommitMessageRef) -> cmp::Ordering {
+        self.round_and_target_key()
+            .cmp(&other.round_and_target_key())
+    }
+
+    /// Returns the number of bytes of the SCALE encoding of this commit.
+    ///
+    /// When the commit has been decoded with [`decode_partial_grandpa_commit`], this is the
+    /// number of bytes that have been consumed from the input.
+    pub fn encoded_len(&self, block_number_bytes: usize) -> usize {
+        let compact_len = |n: usize| crate::util::encode_scale_compact_usize(n).as_ref().len();
+
+        8 + 8
+            + 32
+            + block_number_bytes
+            + compact_len(self.message.precommits.len())
+            + self.message.precommits.len() * (32 + block_number_bytes)
+            + compact_len(self.message.auth_data.len())
+            + self.message.auth_data.len() * (64 + 32)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct CompactCommitRef<'a> {
     pub target_hash: &'a [u8; 32],
+
+    /// Number of the block targeted by the commit.
+    ///
+    /// Block numbers are encoded in **little endian** on the number of bytes passed to the
+    /// decoding function, and are zero-extended to a `u64`. Decoding fails with
+    /// [`Error::InvalidBlockNumberBytes`] if more than 8 bytes are used.
+    ///
+    /// ```
+    /// use smoldot::finality::grandpa::commit::decode::decode_grandpa_commit;
+    ///
+    /// let mut encoded = Vec::new();
+    /// encoded.extend_from_slice(&12u64.to_le_bytes()); // Round number.
+    /// encoded.extend_from_slice(&3u64.to_le_bytes()); // Authorities set id.
+    /// encoded.extend_from_slice(&[0xaa; 32]); // Target hash.
+    /// encoded.extend_from_slice(&[0x01, 0, 0, 0]); // Target number, on 4 bytes.
+    /// encoded.push(0); // Number of pre-commits.
+    /// encoded.push(0); // Number of signatures.
+    ///
+    /// let commit = decode_grandpa_commit(&encoded, 4).unwrap();
+    /// assert_eq!(commit.message.target_number, 1);
+    /// ```
     pub target_number: u64,
     // TODO: don't use Vec
     pub precommits: Vec<UnsignedPrecommitRef<'a>>,
@@ -67,14 +413,174 @@ pub struct CompactCommitRef<'a> {
     pub auth_data: Vec<(&'a [u8; 64], &'a [u8; 32])>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl<'a> CompactCommitRef<'a> {
+    /// Returns the list of pre-commits together with their signature and the public key of
+    /// their author.
+    ///
+    /// [`CompactCommitRef::precommits`] and [`CompactCommitRef::auth_data`] are expected to have
+    /// the same length. If that isn't the case, the iterator stops at the end of the shorter of
+    /// the two.
+    pub fn signed_precommits(
+        &self,
+    ) -> impl ExactSizeIterator<Item = (UnsignedPrecommitRef<'a>, &'a [u8; 64], &'a [u8; 32])> + '_
+    {
+        self.precommits.iter().zip(self.auth_data.iter()).map(
+            |(precommit, (signature, public_key))| (precommit.clone(), *signature, *public_key),
+        )
+    }
+
+    /// Returns each distinct public key found in [`CompactCommitRef::auth_data`]. The list is
+    /// ordered by public key.
+    ///
+    /// This can be used to compare the signers of this commit with a known list of authorities
+    /// before verifying the signatures. No signature is verified by this function.
+    pub fn signer_keys(&self) -> impl Iterator<Item = &'a [u8; 32]> {
+        self.auth_data
+            .iter()
+            .map(|(_, public_key)| *public_key)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+
+    /// Returns the number of distinct public keys found in [`CompactCommitRef::auth_data`].
+    ///
+    /// Equivalent to the number of items returned by [`CompactCommitRef::signer_keys`]. No
+    /// signature is verified by this function, and the public keys aren't compared with any
+    /// list of authorities.
+    pub fn num_distinct_signers(&self) -> usize {
+        self.auth_data
+            .iter()
+            .map(|(_, public_key)| *public_key)
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    /// Returns each distinct block hash targeted by the pre-commits of this commit, together
+    /// with the number of pre-commits that target it. The list is ordered by block hash.
+    ///
+    /// If it contains more than one item, the authorities have voted for different blocks
+    /// during the round.
+    pub fn target_groups(&self) -> impl Iterator<Item = (&'a [u8; 32], usize)> {
+        let mut groups = BTreeMap::<&'a [u8; 32], usize>::new();
+        for precommit in &self.precommits {
+            *groups.entry(precommit.target_hash).or_insert(0) += 1;
+        }
+        groups.into_iter()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct UnsignedPrecommitRef<'a> {
     pub target_hash: &'a [u8; 32],
+
+    /// Number of the block targeted by the pre-commit.
+    ///
+    /// Encoded the same way as [`CompactCommitRef::target_number`], in other words in
+    /// **little endian**.
+    ///
+    /// ```
+    /// use smoldot::finality::grandpa::commit::decode::decode_grandpa_commit;
+    ///
+    /// let mut encoded = Vec::new();
+    /// encoded.extend_from_slice(&12u64.to_le_bytes()); // Round number.
+    /// encoded.extend_from_slice(&3u64.to_le_bytes()); // Authorities set id.
+    /// encoded.extend_from_slice(&[0xaa; 32]); // Target hash.
+    /// encoded.extend_from_slice(&[0x01, 0, 0, 0]); // Target number, on 4 bytes.
+    /// encoded.push(1 << 2); // Number of pre-commits.
+    /// encoded.extend_from_slice(&[0xaa; 32]); // Pre-commit target hash.
+    /// encoded.extend_from_slice(&[0x01, 0, 0, 0]); // Pre-commit target number, on 4 bytes.
+    /// encoded.push(1 << 2); // Number of signatures.
+    /// encoded.extend_from_slice(&[0; 64]); // Signature.
+    /// encoded.extend_from_slice(&[0; 32]); // Public key.
+    ///
+    /// let commit = decode_grandpa_commit(&encoded, 4).unwrap();
+    /// assert_eq!(commit.message.precommits[0].target_number, 1);
+    /// ```
+    pub target_number: u64,
+}
+
+/// Owned version of [`UnsignedPrecommitRef`].
+///
+/// Can for example be used as a key in a map after the data that the [`UnsignedPrecommitRef`]
+/// borrows from has been dropped.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct UnsignedPrecommit {
+    pub target_hash: [u8; 32],
     pub target_number: u64,
 }
 
+impl<'a> From<&UnsignedPrecommitRef<'a>> for UnsignedPrecommit {
+    fn from(precommit: &UnsignedPrecommitRef<'a>) -> UnsignedPrecommit {
+        UnsignedPrecommit {
+            target_hash: *precommit.target_hash,
+            target_number: precommit.target_number,
+        }
+    }
+}
+
+impl<'a> From<&'a UnsignedPrecommit> for UnsignedPrecommitRef<'a> {
+    fn from(precommit: &'a UnsignedPrecommit) -> UnsignedPrecommitRef<'a> {
+        UnsignedPrecommitRef {
+            target_hash: &precommit.target_hash,
+            target_number: precommit.target_number,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for CompactCommitRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactCommitRef")
+            .field("target_hash", &HexDebug(self.target_hash))
+            .field("target_number", &self.target_number)
+            .field("precommits", &self.precommits)
+            .field(
+                "auth_data",
+                &self
+                    .auth_data
+                    .iter()
+                    .map(|(signature, public_key)| (HexDebug(*signature), HexDebug(*public_key)))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<'a> fmt::Debug for UnsignedPrecommitRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnsignedPrecommitRef")
+            .field("target_hash", &HexDebug(self.target_hash))
+            .field("target_number", &self.target_number)
+            .finish()
+    }
+}
+
+impl fmt::Debug for UnsignedPrecommit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&UnsignedPrecommitRef::from(self), f)
+    }
+}
+
+/// Implements `fmt::Debug` and prints the bytes as a `0x`-prefixed hexadecimal string.
+struct HexDebug<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for HexDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0))
+    }
+}
+
+/// Way the signatures and public keys of a commit are encoded.
+#[derive(Debug, Copy, Clone)]
+enum AuthDataLayout {
+    /// List of `(signature, public_key)` pairs.
+    Interleaved,
+    /// List of signatures followed with list of public keys.
+    StructOfArrays,
+}
+
 fn commit_message<'a>(
     block_number_bytes: usize,
+    layout: AuthDataLayout,
 ) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], CommitMessageRef> {
     nom::error::context(
         "commit_message",
@@ -82,7 +588,7 @@ fn commit_message<'a>(
             nom::sequence::tuple((
                 nom::number::streaming::le_u64,
                 nom::number::streaming::le_u64,
-                compact_commit(block_number_bytes),
+                compact_commit(block_number_bytes, layout),
             )),
             |(round_number, set_id, message)| CommitMessageRef {
                 round_number,
@@ -95,6 +601,7 @@ fn commit_message<'a>(
 
 fn compact_commit<'a>(
     block_number_bytes: usize,
+    layout: AuthDataLayout,
 ) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], CompactCommitRef> {
     nom::error::context(
         "compact_commit",
@@ -109,24 +616,7 @@ fn compact_commit<'a>(
                         unsigned_precommit(block_number_bytes),
                     )
                 }),
-                nom::combinator::flat_map(crate::util::nom_scale_compact_usize, |num_elems| {
-                    nom::multi::many_m_n(
-                        num_elems,
-                        num_elems,
-                        nom::combinator::map(
-                            nom::sequence::tuple((
-                                nom::bytes::streaming::take(64u32),
-                                nom::bytes::streaming::take(32u32),
-                            )),
-                            |(sig, pubkey)| {
-                                (
-                                    <&[u8; 64]>::try_from(sig).unwrap(),
-                                    <&[u8; 32]>::try_from(pubkey).unwrap(),
-                                )
-                            },
-                        ),
-                    )
-                }),
+                auth_data(layout),
             )),
             |(target_hash, target_number, precommits, auth_data)| CompactCommitRef {
                 target_hash: <&[u8; 32]>::try_from(target_hash).unwrap(),
@@ -138,6 +628,62 @@ fn compact_commit<'a>(
     )
 }
 
+/// See [`CompactCommitRef::auth_data`].
+type AuthData<'a> = Vec<(&'a [u8; 64], &'a [u8; 32])>;
+
+fn auth_data<'a>(
+    layout: AuthDataLayout,
+) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], AuthData<'a>> {
+    move |bytes| match layout {
+        AuthDataLayout::Interleaved => nom::error::context(
+            "auth_data",
+            nom::combinator::flat_map(crate::util::nom_scale_compact_usize, |num_elems| {
+                nom::multi::many_m_n(
+                    num_elems,
+                    num_elems,
+                    nom::sequence::tuple((signature, public_key)),
+                )
+            }),
+        )(bytes),
+        AuthDataLayout::StructOfArrays => nom::error::context(
+            "auth_data",
+            nom::combinator::map(
+                nom::combinator::verify(
+                    nom::sequence::tuple((
+                        nom::combinator::flat_map(
+                            crate::util::nom_scale_compact_usize,
+                            |num_elems| nom::multi::many_m_n(num_elems, num_elems, signature),
+                        ),
+                        nom::combinator::flat_map(
+                            crate::util::nom_scale_compact_usize,
+                            |num_elems| nom::multi::many_m_n(num_elems, num_elems, public_key),
+                        ),
+                    )),
+                    |(signatures, public_keys): &(Vec<_>, Vec<_>)| {
+                        signatures.len() == public_keys.len()
+                    },
+                ),
+                |(signatures, public_keys)| signatures.into_iter().zip(public_keys).collect(),
+            ),
+        )(bytes),
+    }
+}
+
+// Note that `nom::bytes::streaming::take` either returns exactly the requested number of bytes
+// or an `Incomplete` error, and as such the `unwrap()`s below can never panic.
+
+fn signature(bytes: &[u8]) -> nom::IResult<&[u8], &[u8; 64]> {
+    nom::combinator::map(nom::bytes::streaming::take(64u32), |sig| {
+        <&[u8; 64]>::try_from(sig).unwrap()
+    })(bytes)
+}
+
+fn public_key(bytes: &[u8]) -> nom::IResult<&[u8], &[u8; 32]> {
+    nom::combinator::map(nom::bytes::streaming::take(32u32), |pubkey| {
+        <&[u8; 32]>::try_from(pubkey).unwrap()
+    })(bytes)
+}
+
 fn unsigned_precommit<'a>(
     block_number_bytes: usize,
 ) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], UnsignedPrecommitRef> {
@@ -158,6 +704,583 @@ fn unsigned_precommit<'a>(
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn invalid_block_number_bytes() {
+        assert!(matches!(
+            super::decode_grandpa_commit(&[0; 128], 9),
+            Err(super::Error::InvalidBlockNumberBytes)
+        ));
+        assert!(matches!(
+            super::decode_partial_grandpa_commit(&[0; 128], 9),
+            Err(super::Error::InvalidBlockNumberBytes)
+        ));
+        assert!(matches!(
+            super::decode_grandpa_commit(&[0; 128], 0),
+            Err(super::Error::BlockNumberBytesUnknown)
+        ));
+    }
+
+    #[test]
+    fn detect_block_number_bytes() {
+        for block_number_bytes in [2, 4, 8] {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&12u64.to_le_bytes());
+            encoded.extend_from_slice(&3u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u64.to_le_bytes()[..block_number_bytes]);
+            encoded.push(2 << 2);
+            for _ in 0..2 {
+                encoded.extend_from_slice(&[0xaa; 32]);
+                encoded.extend_from_slice(&1000u64.to_le_bytes()[..block_number_bytes]);
+            }
+            encoded.push(2 << 2);
+            encoded.extend_from_slice(&[0x11; 2 * 96]);
+
+            assert_eq!(
+                super::detect_block_number_bytes(&encoded).unwrap(),
+                block_number_bytes
+            );
+            let commit = super::decode_grandpa_commit(&encoded, 0).unwrap();
+            assert_eq!(commit.message.target_number, 1000);
+
+            // The other decoding functions that decode an entire input detect the number of
+            // bytes as well.
+            let commit = super::decode_grandpa_commit_limited(&encoded, 0, 2).unwrap();
+            assert_eq!(commit.message.target_number, 1000);
+
+            let mut list = vec![2 << 2];
+            list.extend_from_slice(&encoded);
+            list.extend_from_slice(&encoded);
+            let commits = super::decode_grandpa_commits(&list, 0).unwrap();
+            assert_eq!(commits.len(), 2);
+            assert!(commits
+                .iter()
+                .all(|commit| commit.message.target_number == 1000));
+
+            let mut soa = encoded[..encoded.len() - 2 * 96 - 1].to_vec();
+            soa.push(2 << 2);
+            soa.extend_from_slice(&[0x11; 2 * 64]);
+            soa.push(2 << 2);
+            soa.extend_from_slice(&[0x22; 2 * 32]);
+            let commit = super::decode_grandpa_commit_soa(&soa, 0).unwrap();
+            assert_eq!(commit.message.target_number, 1000);
+            assert_eq!(commit.message.auth_data[1].1, &[0x22; 32]);
+
+            // The end of the commit isn't known when decoding partially, in which case the
+            // number of bytes can't be detected.
+            assert!(matches!(
+                super::decode_partial_grandpa_commit(&encoded, 0),
+                Err(super::Error::InvalidBlockNumberBytes)
+            ));
+            assert!(matches!(
+                super::decode_grandpa_commit_bounded(&encoded, 0, encoded.len()),
+                Err(super::Error::InvalidBlockNumberBytes)
+            ));
+        }
+    }
+
+    #[test]
+    fn commit_header() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&3u64.to_le_bytes());
+        assert_eq!(
+            super::decode_grandpa_commit_header(&encoded).unwrap(),
+            (3, 12)
+        );
+
+        // The rest of the commit is ignored, even if invalid.
+        encoded.extend_from_slice(&[0xff; 5]);
+        assert_eq!(
+            super::decode_grandpa_commit_header(&encoded).unwrap(),
+            (3, 12)
+        );
+
+        assert!(super::decode_grandpa_commit_header(&encoded[..15]).is_err());
+    }
+
+    #[test]
+    fn dedup() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&3u64.to_le_bytes());
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        encoded.push(3 << 2);
+        for _ in 0..3 {
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+        }
+        encoded.push(3 << 2);
+        for (signature, public_key) in [(1, 1), (2, 2), (1, 1)] {
+            encoded.extend_from_slice(&[signature; 64]);
+            encoded.extend_from_slice(&[public_key; 32]);
+        }
+
+        let (commit, num_removed) = super::decode_grandpa_commit_dedup(&encoded, 4).unwrap();
+        assert_eq!(num_removed, 1);
+        assert_eq!(commit.message.precommits.len(), 2);
+        assert_eq!(
+            commit.message.auth_data,
+            vec![(&[1; 64], &[1; 32]), (&[2; 64], &[2; 32])]
+        );
+
+        // The default decoder keeps the duplicates.
+        let commit = super::decode_grandpa_commit(&encoded, 4).unwrap();
+        assert_eq!(commit.message.precommits.len(), 3);
+    }
+
+    #[test]
+    fn truncated_auth_data() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&3u64.to_le_bytes());
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        encoded.push(2 << 2);
+        for _ in 0..2 {
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+        }
+        encoded.push(2 << 2);
+        let auth_data_start = encoded.len();
+        for n in 1..=2 {
+            encoded.extend_from_slice(&[n; 64]);
+            encoded.extend_from_slice(&[n; 32]);
+        }
+
+        assert!(super::decode_grandpa_commit(&encoded, 4).is_ok());
+
+        // Truncate the commit at every position within the signatures and public keys,
+        // including in the middle of the first signature.
+        for len in auth_data_start..encoded.len() {
+            let truncated = &encoded[..len];
+            assert!(matches!(
+                super::decode_grandpa_commit(truncated, 4),
+                Err(super::Error::Decode(_))
+            ));
+            assert!(super::decode_partial_grandpa_commit(truncated, 4).is_err());
+            assert!(super::decode_grandpa_commit_limited(truncated, 4, 2).is_err());
+            assert!(super::decode_grandpa_commit_dedup(truncated, 4).is_err());
+        }
+    }
+
+    #[test]
+    fn bounded() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&3u64.to_le_bytes());
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        encoded.push(1 << 2);
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        encoded.push(1 << 2);
+        encoded.extend_from_slice(&[1; 64]);
+        encoded.extend_from_slice(&[2; 32]);
+        let commit_len = encoded.len();
+        encoded.extend_from_slice(&[0xff; 10]);
+
+        // The bound is large enough.
+        for max_len in [commit_len, commit_len + 3, usize::MAX] {
+            let (commit, remainder) =
+                super::decode_grandpa_commit_bounded(&encoded, 4, max_len).unwrap();
+            assert_eq!(commit.message.precommits.len(), 1);
+            assert_eq!(remainder, &[0xff; 10]);
+        }
+
+        // The bound is too small.
+        for max_len in [0, 20, commit_len - 1] {
+            assert!(matches!(
+                super::decode_grandpa_commit_bounded(&encoded, 4, max_len),
+                Err(super::Error::ExceedsBound)
+            ));
+        }
+
+        // The input itself is too short, which isn't a violation of the bound.
+        assert!(matches!(
+            super::decode_grandpa_commit_bounded(&encoded[..commit_len - 1], 4, usize::MAX),
+            Err(super::Error::Decode(_))
+        ));
+
+        // The number of pre-commits is a lie, and the bytes following the commit would be read
+        // if it wasn't for the bound.
+        let mut lying = encoded[..commit_len].to_vec();
+        lying[8 + 8 + 32 + 4] = 2 << 2;
+        lying.extend_from_slice(&[0; 200]);
+        assert!(matches!(
+            super::decode_grandpa_commit_bounded(&lying, 4, commit_len),
+            Err(super::Error::ExceedsBound)
+        ));
+    }
+
+    #[test]
+    fn target_number_little_endian() {
+        for block_number_bytes in [3, 4, 8] {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&12u64.to_le_bytes());
+            encoded.extend_from_slice(&3u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&[0x03, 0x02, 0x01, 0, 0, 0, 0, 0][..block_number_bytes]);
+            encoded.push(1 << 2);
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&[0xff, 0x01, 0, 0, 0, 0, 0, 0][..block_number_bytes]);
+            encoded.push(1 << 2);
+            encoded.extend_from_slice(&[1; 64]);
+            encoded.extend_from_slice(&[2; 32]);
+
+            let commit = super::decode_grandpa_commit(&encoded, block_number_bytes).unwrap();
+            assert_eq!(commit.message.target_number, 0x01_02_03);
+            assert_eq!(commit.message.precommits[0].target_number, 0x01_ff);
+        }
+    }
+
+    #[test]
+    fn signer_keys() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&3u64.to_le_bytes());
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        encoded.push(3 << 2);
+        for _ in 0..3 {
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+        }
+        encoded.push(3 << 2);
+        for (signature, public_key) in [(1, 5), (2, 2), (3, 5)] {
+            encoded.extend_from_slice(&[signature; 64]);
+            encoded.extend_from_slice(&[public_key; 32]);
+        }
+
+        let commit = super::decode_grandpa_commit(&encoded, 4).unwrap();
+        assert_eq!(
+            commit.message.signer_keys().collect::<Vec<_>>(),
+            vec![&[2; 32], &[5; 32]]
+        );
+        assert_eq!(commit.message.num_distinct_signers(), 2);
+    }
+
+    #[test]
+    fn decode_partial_concatenated() {
+        let encode = |round: u64, num_precommits: u8| {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&round.to_le_bytes());
+            encoded.extend_from_slice(&3u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+            encoded.push(num_precommits << 2);
+            for _ in 0..num_precommits {
+                encoded.extend_from_slice(&[0xaa; 32]);
+                encoded.extend_from_slice(&1000u32.to_le_bytes());
+            }
+            encoded.push(num_precommits << 2);
+            for n in 0..num_precommits {
+                encoded.extend_from_slice(&[n; 64]);
+                encoded.extend_from_slice(&[n; 32]);
+            }
+            encoded
+        };
+
+        let first = encode(12, 2);
+        let second = encode(13, 5);
+        let stream = [&first[..], &second[..]].concat();
+
+        let mut cursor = 0;
+        let (commit, remainder) =
+            super::decode_partial_grandpa_commit(&stream[cursor..], 4).unwrap();
+        assert_eq!(commit.round_number, 12);
+        assert_eq!(commit.encoded_len(4), first.len());
+        cursor += commit.encoded_len(4);
+        assert_eq!(&stream[cursor..], remainder);
+
+        let (commit, remainder) =
+            super::decode_partial_grandpa_commit(&stream[cursor..], 4).unwrap();
+        assert_eq!(commit.round_number, 13);
+        assert_eq!(commit.encoded_len(4), second.len());
+        cursor += commit.encoded_len(4);
+        assert_eq!(cursor, stream.len());
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    fn decode_multiple_commits() {
+        let encode = |round: u64| {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&round.to_le_bytes());
+            encoded.extend_from_slice(&3u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+            encoded.push(1 << 2);
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+            encoded.push(1 << 2);
+            encoded.extend_from_slice(&[1; 64]);
+            encoded.extend_from_slice(&[2; 32]);
+            encoded
+        };
+
+        let mut encoded = vec![2 << 2];
+        encoded.extend_from_slice(&encode(12));
+        encoded.extend_from_slice(&encode(13));
+
+        let commits = super::decode_grandpa_commits(&encoded, 4).unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].round_number, 12);
+        assert_eq!(commits[1].round_number, 13);
+
+        // More commits than declared.
+        let mut too_long = encoded.clone();
+        too_long[0] = 1 << 2;
+        assert!(matches!(
+            super::decode_grandpa_commits(&too_long, 4),
+            Err(super::Error::Decode(_))
+        ));
+
+        // Fewer commits than declared.
+        let mut too_short = encoded.clone();
+        too_short[0] = 3 << 2;
+        assert!(matches!(
+            super::decode_grandpa_commits(&too_short, 4),
+            Err(super::Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn target_groups() {
+        let precommit = |target_hash| super::UnsignedPrecommitRef {
+            target_hash,
+            target_number: 2,
+        };
+
+        let commit = super::CompactCommitRef {
+            target_hash: &[0; 32],
+            target_number: 1,
+            precommits: vec![
+                precommit(&[2; 32]),
+                precommit(&[1; 32]),
+                precommit(&[2; 32]),
+                precommit(&[2; 32]),
+            ],
+            auth_data: Vec::new(),
+        };
+
+        assert_eq!(
+            commit.target_groups().collect::<Vec<_>>(),
+            vec![(&[1; 32], 1), (&[2; 32], 3)]
+        );
+    }
+
+    #[test]
+    fn decode_soa() {
+        let mut header = Vec::new();
+        header.extend_from_slice(&12u64.to_le_bytes());
+        header.extend_from_slice(&3u64.to_le_bytes());
+        header.extend_from_slice(&[0xaa; 32]);
+        header.extend_from_slice(&1000u32.to_le_bytes());
+        header.push(2 << 2);
+        for _ in 0..2 {
+            header.extend_from_slice(&[0xaa; 32]);
+            header.extend_from_slice(&1000u32.to_le_bytes());
+        }
+
+        let mut interleaved = header.clone();
+        interleaved.push(2 << 2);
+        for n in 1..=2 {
+            interleaved.extend_from_slice(&[n; 64]);
+            interleaved.extend_from_slice(&[n * 10; 32]);
+        }
+
+        let mut soa = header.clone();
+        soa.push(2 << 2);
+        soa.extend_from_slice(&[1; 64]);
+        soa.extend_from_slice(&[2; 64]);
+        soa.push(2 << 2);
+        soa.extend_from_slice(&[10; 32]);
+        soa.extend_from_slice(&[20; 32]);
+
+        assert_eq!(
+            super::decode_grandpa_commit_soa(&soa, 4).unwrap(),
+            super::decode_grandpa_commit(&interleaved, 4).unwrap()
+        );
+
+        // Different number of signatures and public keys.
+        let mut mismatch = header;
+        mismatch.push(2 << 2);
+        mismatch.extend_from_slice(&[1; 64]);
+        mismatch.extend_from_slice(&[2; 64]);
+        mismatch.push(1 << 2);
+        mismatch.extend_from_slice(&[10; 32]);
+        assert!(matches!(
+            super::decode_grandpa_commit_soa(&mismatch, 4),
+            Err(super::Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn signed_precommits() {
+        let commit = super::CompactCommitRef {
+            target_hash: &[0; 32],
+            target_number: 1,
+            precommits: vec![
+                super::UnsignedPrecommitRef {
+                    target_hash: &[1; 32],
+                    target_number: 2,
+                },
+                super::UnsignedPrecommitRef {
+                    target_hash: &[3; 32],
+                    target_number: 4,
+                },
+            ],
+            auth_data: vec![(&[5; 64], &[6; 32])],
+        };
+
+        let mut iter = commit.signed_precommits();
+        assert_eq!(iter.len(), 1);
+        let (precommit, signature, public_key) = iter.next().unwrap();
+        assert_eq!(*precommit.target_hash, [1; 32]);
+        assert_eq!(precommit.target_number, 2);
+        assert_eq!(*signature, [5; 64]);
+        assert_eq!(*public_key, [6; 32]);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn max_block_number() {
+        // Decoding a number made of only `0xff` bytes succeeds.
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&1u64.to_le_bytes());
+        encoded.extend_from_slice(&1u64.to_le_bytes());
+        encoded.extend_from_slice(&[0; 32]);
+        encoded.extend_from_slice(&u32::MAX.to_le_bytes());
+        encoded.push(0);
+        encoded.push(0);
+        let decoded = super::decode_grandpa_commit(&encoded, 4).unwrap();
+        assert_eq!(decoded.message.target_number, u64::from(u32::MAX));
+    }
+
+    #[test]
+    fn limited() {
+        let encode = |num_precommits_declared: usize, num_precommits: usize| {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&12u64.to_le_bytes());
+            encoded.extend_from_slice(&3u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u32.to_le_bytes());
+            encoded.extend_from_slice(
+                crate::util::encode_scale_compact_usize(num_precommits_declared).as_ref(),
+            );
+            for _ in 0..num_precommits {
+                encoded.extend_from_slice(&[0xaa; 32]);
+                encoded.extend_from_slice(&1000u32.to_le_bytes());
+            }
+            encoded.extend_from_slice(
+                crate::util::encode_scale_compact_usize(num_precommits).as_ref(),
+            );
+            for _ in 0..num_precommits {
+                encoded.extend_from_slice(&[0x11; 64]);
+                encoded.extend_from_slice(&[0x22; 32]);
+            }
+            encoded
+        };
+
+        let encoded = encode(3, 3);
+        let commit = super::decode_grandpa_commit_limited(&encoded, 4, 3).unwrap();
+        assert_eq!(commit.message.precommits.len(), 3);
+
+        assert!(matches!(
+            super::decode_grandpa_commit_limited(&encoded, 4, 2),
+            Err(super::Error::TooManyPrecommits)
+        ));
+
+        // Number of pre-commits that can't possibly fit in the input.
+        assert!(matches!(
+            super::decode_grandpa_commit_limited(&encode(10_000_000, 1), 4, usize::MAX),
+            Err(super::Error::TooManyPrecommits)
+        ));
+    }
+
+    #[test]
+    fn precommits_hash() {
+        let mut set = hashbrown::HashSet::with_hasher(crate::util::SipHasherBuild::new([0; 16]));
+
+        let precommit1 = super::UnsignedPrecommitRef {
+            target_hash: &[1; 32],
+            target_number: 10,
+        };
+        let precommit2 = super::UnsignedPrecommitRef {
+            target_hash: &[2; 32],
+            target_number: 10,
+        };
+
+        assert!(set.insert(super::UnsignedPrecommit::from(&precommit1)));
+        assert!(set.insert(super::UnsignedPrecommit::from(&precommit2)));
+        assert!(!set.insert(super::UnsignedPrecommit::from(
+            &super::UnsignedPrecommitRef {
+                target_hash: &[1; 32],
+                target_number: 10,
+            }
+        )));
+        assert_eq!(set.len(), 2);
+
+        let owned = super::UnsignedPrecommit::from(&precommit1);
+        assert_eq!(super::UnsignedPrecommitRef::from(&owned), precommit1);
+    }
+
+    #[test]
+    fn debug_is_hex() {
+        let commit = super::CommitMessageRef {
+            round_number: 1,
+            set_id: 2,
+            message: super::CompactCommitRef {
+                target_hash: &[0xab; 32],
+                target_number: 3,
+                precommits: vec![super::UnsignedPrecommitRef {
+                    target_hash: &[0xcd; 32],
+                    target_number: 3,
+                }],
+                auth_data: vec![(&[0x12; 64], &[0x34; 32])],
+            },
+        };
+
+        let debug = format!("{commit:?}");
+        assert!(debug.contains(&format!("target_hash: 0x{}", "ab".repeat(32))));
+        assert!(debug.contains(&format!("target_hash: 0x{}", "cd".repeat(32))));
+        assert!(debug.contains(&format!("(0x{}, 0x{})", "12".repeat(64), "34".repeat(32))));
+    }
+
+    #[test]
+    fn cmp_by_round_and_target() {
+        let commit = |round_number, set_id, target_number| super::CommitMessageRef {
+            round_number,
+            set_id,
+            message: super::CompactCommitRef {
+                target_hash: &[0; 32],
+                target_number,
+                precommits: Vec::new(),
+                auth_data: Vec::new(),
+            },
+        };
+
+        assert_eq!(commit(5, 1, 100).round_and_target_key(), (5, 1, 100));
+        assert_eq!(
+            commit(5, 1, 100).cmp_by_round_and_target(&commit(4, 2, 200)),
+            core::cmp::Ordering::Greater
+        );
+        assert_eq!(
+            commit(5, 1, 100).cmp_by_round_and_target(&commit(5, 2, 50)),
+            core::cmp::Ordering::Less
+        );
+        assert_eq!(
+            commit(5, 1, 100).cmp_by_round_and_target(&commit(5, 1, 101)),
+            core::cmp::Ordering::Less
+        );
+        assert_eq!(
+            commit(5, 1, 100).cmp_by_round_and_target(&commit(5, 1, 100)),
+            core::cmp::Ordering::Equal
+        );
+    }
+
     #[test]
     fn basic_decode_commit() {
         let actual = super::decode_grandpa_commit(
@@ -15,15 +15,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::finality::grandpa::commit::decode;
+use crate::{
+    finality::{
+        grandpa::{self, commit::decode, votes_ancestries},
+        justification,
+    },
+    header,
+};
 
-use alloc::vec::Vec;
-use core::{cmp, iter, mem};
 use rand_chacha::{
     rand_core::{RngCore as _, SeedableRng as _},
     ChaCha20Rng,
 };
 
+mod tests;
+
 /// Configuration for a commit verification process.
 #[derive(Debug)]
 pub struct Config<C> {
@@ -31,6 +37,8 @@ pub struct Config<C> {
     pub commit: C,
 
     /// Number of bytes used for encoding the block number in the SCALE-encoded commit.
+    ///
+    /// If 0, it is detected using [`decode::detect_block_number_bytes`].
     pub block_number_bytes: usize,
 
     // TODO: document
@@ -65,8 +73,17 @@ pub enum InProgress<C> {
 
 /// Verifies that a commit is valid.
 pub fn verify<C: AsRef<[u8]>>(config: Config<C>) -> InProgress<C> {
+    // The detected number of bytes is also necessary in order to build the signed messages.
+    let block_number_bytes = match config.block_number_bytes {
+        0 => match decode::detect_block_number_bytes(config.commit.as_ref()) {
+            Ok(n) => n,
+            Err(_) => return InProgress::Finished(Err(Error::InvalidFormat)),
+        },
+        n => n,
+    };
+
     let decoded_commit =
-        match decode::decode_grandpa_commit(config.commit.as_ref(), config.block_number_bytes) {
+        match decode::decode_grandpa_commit(config.commit.as_ref(), block_number_bytes) {
             Ok(c) => c,
             Err(_) => return InProgress::Finished(Err(Error::InvalidFormat)),
         };
@@ -103,7 +120,7 @@ pub fn verify<C: AsRef<[u8]>>(config: Config<C>) -> InProgress<C> {
 
     Verification {
         commit: config.commit,
-        block_number_bytes: config.block_number_bytes,
+        block_number_bytes,
         next_precommit_index: 0,
         next_precommit_author_verified: false,
         next_precommit_block_verified: false,
@@ -115,6 +132,428 @@ pub fn verify<C: AsRef<[u8]>>(config: Config<C>) -> InProgress<C> {
     .resume()
 }
 
+/// Configuration for the verification of a commit that has already been decoded.
+///
+/// Contrary to [`verify`], the verification of an already-decoded commit returns the same
+/// error type as the verification of a justification, in order for both to be handled the
+/// same way.
+#[derive(Debug)]
+pub struct DecodedConfig<'a, 'b, I> {
+    /// Commit to verify.
+    pub commit: &'b decode::CommitMessageRef<'a>,
+
+    /// Number of bytes used for encoding the block number in the SCALE-encoded commit.
+    ///
+    /// Must not be 0, as the commit has already been decoded. See
+    /// [`justification::verify::Error::InvalidBlockNumberBytes`].
+    pub block_number_bytes: usize,
+
+    /// Identifier of the authorities set that must have produced the commit.
+    pub expected_authorities_set_id: u64,
+
+    /// List of authorities that are allowed to emit pre-commits for the block referred to by
+    /// the commit. Must implement `Iterator<Item = &[u8]>`, where each item is the public key
+    /// of an authority.
+    pub authorities_list: I,
+
+    /// Seed for a PRNG used for various purposes during the verification.
+    ///
+    /// > **Note**: The verification is nonetheless deterministic.
+    pub randomness_seed: [u8; 32],
+}
+
+/// Verifies that an already-decoded commit is valid.
+///
+/// Contrary to [`verify`], the list of authorities is known ahead of time and no question is
+/// asked to the API user. Because the headers of the blocks being voted on aren't available,
+/// all the pre-commits must target the target of the commit. Use
+/// [`verify_commit_with_ancestries`] in order to accept commits whose pre-commits target
+/// descendants of the target.
+pub fn verify_commit<'a>(
+    config: DecodedConfig<'a, '_, impl Iterator<Item = &'a [u8]>>,
+) -> Result<(), justification::verify::Error> {
+    verify_commit_inner(config, None::<core::iter::Empty<_>>)
+}
+
+/// Verifies that an already-decoded commit is valid, using the given headers in order to check
+/// the ancestry of the blocks voted on.
+///
+/// Contrary to [`verify_commit`], the pre-commits can target a block other than the target of
+/// the commit, and are checked to target a descendant of it, exactly like for justifications. The
+/// `votes_ancestries` must contain the headers of all the blocks between the blocks voted on
+/// (included) and the target of the commit (excluded), and nothing more.
+pub fn verify_commit_with_ancestries<'a, 'h>(
+    config: DecodedConfig<'a, '_, impl Iterator<Item = &'a [u8]>>,
+    votes_ancestries: impl Iterator<Item = header::HeaderRef<'h>>,
+) -> Result<(), justification::verify::Error> {
+    verify_commit_inner(config, Some(votes_ancestries))
+}
+
+/// Verifies that an already-decoded commit is valid, but lets the API user verify the
+/// signatures.
+///
+/// Everything except for the signatures is checked in the same way as [`verify_commit`] before
+/// this function returns. Then, each signature is yielded through
+/// [`CommitVerify::SignatureVerification`], which makes it possible to offload the Ed25519
+/// verifications, for example to a hardware accelerator that batches them.
+pub fn verify_commit_external_signatures<'a, 'b>(
+    config: DecodedConfig<'a, 'b, impl Iterator<Item = &'a [u8]>>,
+) -> CommitVerify<'a, 'b> {
+    let commit = config.commit;
+    let block_number_bytes = config.block_number_bytes;
+
+    if let Err(err) = check_commit_except_signatures(config, None::<core::iter::Empty<_>>) {
+        return CommitVerify::Finished(Err(err));
+    }
+
+    SignatureVerification::from_remaining(
+        commit,
+        block_number_bytes,
+        &commit.message.precommits,
+        &commit.message.auth_data,
+    )
+}
+
+/// Verification of an already-decoded commit in progress.
+///
+/// See [`verify_commit_external_signatures`].
+#[must_use]
+pub enum CommitVerify<'a, 'b> {
+    /// Verifying whether a signature is correct is required in order to continue.
+    SignatureVerification(SignatureVerification<'a, 'b>),
+    /// Verification is finished. Contains an error if the commit is invalid.
+    Finished(Result<(), justification::verify::Error>),
+}
+
+/// Verifying whether a signature is correct is required in order to continue.
+#[must_use]
+pub struct SignatureVerification<'a, 'b> {
+    commit: &'b decode::CommitMessageRef<'a>,
+    block_number_bytes: usize,
+    /// Pre-commits whose signature remains to be verified, starting with the one of this
+    /// [`SignatureVerification`]. Never empty.
+    precommits: &'b [decode::UnsignedPrecommitRef<'a>],
+    /// Signatures and public keys of the pre-commits in
+    /// [`SignatureVerification::precommits`]. Has the same length as
+    /// [`SignatureVerification::precommits`].
+    auth_data: &'b [(&'a [u8; 64], &'a [u8; 32])],
+    /// Message that the signature is expected to sign.
+    message: Vec<u8>,
+}
+
+impl<'a, 'b> SignatureVerification<'a, 'b> {
+    fn from_remaining(
+        commit: &'b decode::CommitMessageRef<'a>,
+        block_number_bytes: usize,
+        precommits: &'b [decode::UnsignedPrecommitRef<'a>],
+        auth_data: &'b [(&'a [u8; 64], &'a [u8; 32])],
+    ) -> CommitVerify<'a, 'b> {
+        // The lengths are guaranteed to be equal by `check_commit_except_signatures`.
+        debug_assert_eq!(precommits.len(), auth_data.len());
+
+        let Some(precommit) = precommits.first() else {
+            return CommitVerify::Finished(Ok(()));
+        };
+
+        let message = grandpa::signed_message::grandpa_precommit_signed_message(
+            precommit.target_hash,
+            precommit.target_number,
+            block_number_bytes,
+            commit.round_number,
+            commit.set_id,
+        );
+
+        CommitVerify::SignatureVerification(SignatureVerification {
+            commit,
+            block_number_bytes,
+            precommits,
+            auth_data,
+            message,
+        })
+    }
+
+    /// Returns the message that the signature is expected to sign.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Returns the signature.
+    pub fn signature(&self) -> &'a [u8; 64] {
+        self.auth_data[0].0
+    }
+
+    /// Returns the public key the signature is against.
+    ///
+    /// This public key is guaranteed to be part of the list of authorities.
+    pub fn public_key(&self) -> &'a [u8; 32] {
+        self.auth_data[0].1
+    }
+
+    /// Returns the list of `(message, public_key, signature)` tuples that remain to be
+    /// verified, including the one of this [`SignatureVerification`].
+    ///
+    /// This can be used in order to verify all the remaining signatures at once, then call
+    /// [`SignatureVerification::resume_with_results`].
+    pub fn remaining(
+        &self,
+    ) -> impl ExactSizeIterator<Item = (Vec<u8>, &'a [u8; 32], &'a [u8; 64])> + '_ {
+        self.precommits.iter().zip(self.auth_data.iter()).map(
+            |(precommit, (signature, public_key))| {
+                let message = grandpa::signed_message::grandpa_precommit_signed_message(
+                    precommit.target_hash,
+                    precommit.target_number,
+                    self.block_number_bytes,
+                    self.commit.round_number,
+                    self.commit.set_id,
+                );
+                (message, *public_key, *signature)
+            },
+        )
+    }
+
+    /// Verify the signature. Returns `true` if it is valid.
+    pub fn is_valid(&self) -> bool {
+        let Ok(public_key) = ed25519_zebra::VerificationKey::try_from(*self.public_key()) else {
+            return false;
+        };
+
+        public_key
+            .verify(
+                &ed25519_zebra::Signature::from(*self.signature()),
+                &self.message,
+            )
+            .is_ok()
+    }
+
+    /// Verify the signature and resume the verification.
+    ///
+    /// This is the default behavior, and should be used unless the signature is verified by
+    /// other means.
+    pub fn verify_and_resume(self) -> CommitVerify<'a, 'b> {
+        if self.is_valid() {
+            self.resume_success()
+        } else {
+            self.resume_failed()
+        }
+    }
+
+    /// Resume the verification assuming that the signature is valid.
+    ///
+    /// This can be used in situations where the signature has been verified by other means,
+    /// for example by using hardware acceleration.
+    pub fn resume_success(self) -> CommitVerify<'a, 'b> {
+        SignatureVerification::from_remaining(
+            self.commit,
+            self.block_number_bytes,
+            &self.precommits[1..],
+            &self.auth_data[1..],
+        )
+    }
+
+    /// Resume the verification by passing, for each item returned by
+    /// [`SignatureVerification::remaining`] and in the same order, whether the signature is
+    /// valid.
+    ///
+    /// Returns [`CommitVerify::Finished`] containing an error if any of the values is `false`.
+    /// If `results` contains fewer items than [`SignatureVerification::remaining`], the
+    /// verification continues with the first signature whose validity hasn't been reported.
+    /// Items in excess are ignored.
+    pub fn resume_with_results(
+        self,
+        results: impl IntoIterator<Item = bool>,
+    ) -> CommitVerify<'a, 'b> {
+        let mut num_valid = 0;
+        for is_valid in results.into_iter().take(self.precommits.len()) {
+            if !is_valid {
+                return CommitVerify::Finished(Err(justification::verify::Error::BadSignature));
+            }
+            num_valid += 1;
+        }
+
+        SignatureVerification::from_remaining(
+            self.commit,
+            self.block_number_bytes,
+            &self.precommits[num_valid..],
+            &self.auth_data[num_valid..],
+        )
+    }
+
+    /// Resume the verification assuming that the signature is invalid.
+    ///
+    /// Always returns [`CommitVerify::Finished`] containing an error.
+    pub fn resume_failed(self) -> CommitVerify<'a, 'b> {
+        CommitVerify::Finished(Err(justification::verify::Error::BadSignature))
+    }
+}
+
+fn verify_commit_inner<'a, 'h>(
+    config: DecodedConfig<'a, '_, impl Iterator<Item = &'a [u8]>>,
+    votes_ancestries: Option<impl Iterator<Item = header::HeaderRef<'h>>>,
+) -> Result<(), justification::verify::Error> {
+    let commit = config.commit;
+    let block_number_bytes = config.block_number_bytes;
+
+    let mut randomness = check_commit_except_signatures(config, votes_ancestries)?;
+
+    // Verifying all the signatures together brings better performances than verifying them one
+    // by one.
+    let mut signatures_batch = ed25519_zebra::batch::Verifier::new();
+
+    for (precommit, signature, authority_public_key) in commit.message.signed_precommits() {
+        let msg = grandpa::signed_message::grandpa_precommit_signed_message(
+            precommit.target_hash,
+            precommit.target_number,
+            block_number_bytes,
+            commit.round_number,
+            commit.set_id,
+        );
+
+        signatures_batch.queue(ed25519_zebra::batch::Item::from((
+            ed25519_zebra::VerificationKeyBytes::from(*authority_public_key),
+            ed25519_zebra::Signature::from(*signature),
+            &msg,
+        )));
+    }
+
+    // Actual signatures verification performed here.
+    signatures_batch
+        .verify(&mut randomness)
+        .map_err(|_| justification::verify::Error::BadSignature)
+}
+
+/// Performs all the checks of [`verify_commit_inner`] except for the signatures verification.
+///
+/// Returns the PRNG seeded with [`DecodedConfig::randomness_seed`], in order for it to be used
+/// for verifying the signatures.
+fn check_commit_except_signatures<'a, 'h>(
+    config: DecodedConfig<'a, '_, impl Iterator<Item = &'a [u8]>>,
+    votes_ancestries: Option<impl Iterator<Item = header::HeaderRef<'h>>>,
+) -> Result<ChaCha20Rng, justification::verify::Error> {
+    let commit = config.commit;
+
+    // The number of bytes can't be detected, as the commit has already been decoded.
+    if config.block_number_bytes == 0 {
+        return Err(justification::verify::Error::InvalidBlockNumberBytes);
+    }
+
+    if commit.set_id != config.expected_authorities_set_id {
+        return Err(justification::verify::Error::SetIdOutOfRange);
+    }
+
+    // A pre-commit without a signature, or a signature without a pre-commit, can't possibly
+    // be verified.
+    if commit.message.auth_data.len() != commit.message.precommits.len() {
+        return Err(justification::verify::Error::BadSignature);
+    }
+
+    let mut randomness = ChaCha20Rng::from_seed(config.randomness_seed);
+
+    // Collect the authorities in a set in order to be able to determine with a low complexity
+    // whether a public key is an authority.
+    // For each authority, contains its index and the index of its pre-commit, if any has been
+    // seen before in the list of pre-commits.
+    let mut authorities_list = {
+        let mut list = hashbrown::HashMap::<&[u8], _, _>::with_capacity_and_hasher(
+            0,
+            crate::util::SipHasherBuild::new({
+                let mut seed = [0; 16];
+                randomness.fill_bytes(&mut seed);
+                seed
+            }),
+        );
+        for (index, authority) in config.authorities_list.enumerate() {
+            list.insert(authority, (index, None));
+        }
+        list
+    };
+
+    let block_number_bytes = config.block_number_bytes;
+    let mut votes_ancestries = votes_ancestries.map(|headers| {
+        votes_ancestries::VotesAncestries::new(
+            headers.map(|header| (header.hash(block_number_bytes), header)),
+            {
+                let mut seed = [0; 16];
+                randomness.fill_bytes(&mut seed);
+                seed
+            },
+        )
+    });
+
+    // Check that commit contains a number of signatures equal to at least 2/3rd of the
+    // number of authorities.
+    // Duplicate signatures are checked below.
+    // The logic of the check is `actual >= (expected * 2 / 3) + 1`.
+    if commit.message.precommits.len() < (authorities_list.len() * 2 / 3) + 1 {
+        return Err(justification::verify::Error::NotEnoughSignatures);
+    }
+
+    for (precommit_index, (precommit, _, authority_public_key)) in
+        commit.message.signed_precommits().enumerate()
+    {
+        match authorities_list.get_mut(&authority_public_key[..]) {
+            Some((authority_index, Some(first_precommit_index))) => {
+                return Err(justification::verify::Error::DuplicateSignature {
+                    authority_public_key: *authority_public_key,
+                    authority_index: *authority_index,
+                    first_precommit_index: *first_precommit_index,
+                    second_precommit_index: precommit_index,
+                });
+            }
+            Some((_, authority_precommit_index)) => {
+                *authority_precommit_index = Some(precommit_index);
+            }
+            None => {
+                return Err(justification::verify::Error::NotAuthority {
+                    authority_public_key: *authority_public_key,
+                    precommit_index,
+                })
+            }
+        }
+
+        // A pre-commit can only target the target of the commit or one of its descendants.
+        // Without the headers of the votes ancestries, nothing proves that a block other than
+        // the target is one of its descendants, and the pre-commit must target the target
+        // itself. The errors are the same as with an empty list of headers.
+        match &mut votes_ancestries {
+            Some(votes_ancestries) => votes_ancestries
+                .check_descendant(
+                    commit.message.target_hash,
+                    commit.message.target_number,
+                    precommit.target_hash,
+                    precommit.target_number,
+                )
+                .map_err(justification::verify::Error::BadAncestry)?,
+            None if precommit.target_number > commit.message.target_number => {
+                return Err(justification::verify::Error::BadAncestry(
+                    votes_ancestries::Error::MissingHeader {
+                        block_hash: *precommit.target_hash,
+                    },
+                ));
+            }
+            None => {
+                if precommit.target_number != commit.message.target_number
+                    || precommit.target_hash != commit.message.target_hash
+                {
+                    return Err(justification::verify::Error::BadAncestry(
+                        votes_ancestries::Error::NotDescendant,
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(votes_ancestries) = &votes_ancestries {
+        let num_unused = votes_ancestries.num_unused();
+        if num_unused != 0 {
+            return Err(justification::verify::Error::BadAncestry(
+                votes_ancestries::Error::UnusedHeaders { num_unused },
+            ));
+        }
+    }
+
+    Ok(randomness)
+}
+
 /// Must return whether a certain public key is in the list of authorities that are allowed to
 /// generate pre-commits.
 #[must_use]
@@ -205,7 +644,9 @@ impl<C: AsRef<[u8]>> IsParent<C> {
             None => {}
             Some(true) => self.inner.num_verified_signatures += 1,
             Some(false) => {
-                return InProgress::Finished(Err(Error::BadAncestry));
+                return InProgress::Finished(Err(Error::BadAncestry(
+                    votes_ancestries::Error::NotDescendant,
+                )));
             }
         }
 
@@ -286,28 +727,13 @@ impl<C: AsRef<[u8]>> Verification<C> {
                     decoded_commit.message.auth_data[self.next_precommit_index].1;
                 let signature = decoded_commit.message.auth_data[self.next_precommit_index].0;
 
-                let mut msg = Vec::with_capacity(1 + 32 + self.block_number_bytes + 8 + 8);
-                msg.push(1u8); // This `1` indicates which kind of message is being signed.
-                msg.extend_from_slice(&precommit.target_hash[..]);
-                // The message contains the little endian block number. While simple in concept,
-                // in reality it is more complicated because we don't know the number of bytes of
-                // this block number at compile time. We thus copy as many bytes as appropriate and
-                // pad with 0s if necessary.
-                msg.extend_from_slice(
-                    &precommit.target_number.to_le_bytes()[..cmp::min(
-                        mem::size_of_val(&precommit.target_number),
-                        self.block_number_bytes,
-                    )],
-                );
-                msg.extend(
-                    iter::repeat(0).take(
-                        self.block_number_bytes
-                            .saturating_sub(mem::size_of_val(&precommit.target_number)),
-                    ),
+                let msg = grandpa::signed_message::grandpa_precommit_signed_message(
+                    precommit.target_hash,
+                    precommit.target_number,
+                    self.block_number_bytes,
+                    decoded_commit.round_number,
+                    decoded_commit.set_id,
                 );
-                msg.extend_from_slice(&u64::to_le_bytes(decoded_commit.round_number)[..]);
-                msg.extend_from_slice(&u64::to_le_bytes(decoded_commit.set_id)[..]);
-                debug_assert_eq!(msg.len(), msg.capacity());
 
                 self.signatures_batch
                     .queue(ed25519_zebra::batch::Item::from((
@@ -362,8 +788,10 @@ pub enum Error {
     /// One of the public keys isn't in the list of authorities.
     #[display(fmt = "One of the public keys isn't in the list of authorities")]
     NotAuthority([u8; 32]),
-    /// Commit contains a vote for a block that isn't a descendant of the target block.
-    BadAncestry,
+    /// Commit contains a vote for a block that isn't a descendant of the target block, or the
+    /// votes ancestries are invalid.
+    #[display(fmt = "Invalid ancestry of a block voted on: {_0}")]
+    BadAncestry(votes_ancestries::Error),
+    /// Commit doesn't contain enough authorities signatures to be valid.
+    NotEnoughSignatures,
 }
-
-// TODO: tests
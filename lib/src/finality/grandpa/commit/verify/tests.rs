@@ -0,0 +1,519 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+#![cfg(test)]
+
+use crate::{
+    finality::{
+        grandpa::{commit::decode, votes_ancestries},
+        justification,
+    },
+    header,
+};
+use core::iter;
+
+const ROUND: u64 = 12;
+const SET_ID: u64 = 3;
+const TARGET_HASH: [u8; 32] = [0xaa; 32];
+const TARGET_NUMBER: u64 = 1000;
+
+/// Pre-commits, signatures, and public keys of a commit.
+struct Signed {
+    authorities: Vec<[u8; 32]>,
+    precommits: Vec<([u8; 32], u64)>,
+    signatures: Vec<[u8; 64]>,
+}
+
+/// Builds `num_authorities` signing keys, and pre-commits signed by the first `num_signers`
+/// of them.
+fn build(num_authorities: u8, num_signers: u8) -> Signed {
+    let signing_keys = (0..num_authorities)
+        .map(|n| ed25519_zebra::SigningKey::from([n; 32]))
+        .collect::<Vec<_>>();
+
+    let signatures = signing_keys
+        .iter()
+        .take(usize::from(num_signers))
+        .map(|key| {
+            let mut msg = vec![1u8];
+            msg.extend_from_slice(&TARGET_HASH);
+            msg.extend_from_slice(&u32::try_from(TARGET_NUMBER).unwrap().to_le_bytes());
+            msg.extend_from_slice(&ROUND.to_le_bytes());
+            msg.extend_from_slice(&SET_ID.to_le_bytes());
+            key.sign(&msg).into()
+        })
+        .collect();
+
+    Signed {
+        authorities: signing_keys
+            .iter()
+            .map(|key| ed25519_zebra::VerificationKey::from(key).into())
+            .collect(),
+        precommits: (0..num_signers)
+            .map(|_| (TARGET_HASH, TARGET_NUMBER))
+            .collect(),
+        signatures,
+    }
+}
+
+fn commit(signed: &Signed) -> decode::CommitMessageRef<'_> {
+    decode::CommitMessageRef {
+        round_number: ROUND,
+        set_id: SET_ID,
+        message: decode::CompactCommitRef {
+            target_hash: &TARGET_HASH,
+            target_number: TARGET_NUMBER,
+            precommits: signed
+                .precommits
+                .iter()
+                .map(
+                    |(target_hash, target_number)| decode::UnsignedPrecommitRef {
+                        target_hash,
+                        target_number: *target_number,
+                    },
+                )
+                .collect(),
+            auth_data: signed
+                .signatures
+                .iter()
+                .zip(signed.authorities.iter())
+                .collect(),
+        },
+    }
+}
+
+/// SCALE-encodes the commit made of the given pre-commits, with 4 bytes block numbers.
+fn encode(signed: &Signed) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&ROUND.to_le_bytes());
+    encoded.extend_from_slice(&SET_ID.to_le_bytes());
+    encoded.extend_from_slice(&TARGET_HASH);
+    encoded.extend_from_slice(&u32::try_from(TARGET_NUMBER).unwrap().to_le_bytes());
+    encoded.extend_from_slice(
+        crate::util::encode_scale_compact_usize(signed.precommits.len()).as_ref(),
+    );
+    for (target_hash, target_number) in &signed.precommits {
+        encoded.extend_from_slice(target_hash);
+        encoded.extend_from_slice(&u32::try_from(*target_number).unwrap().to_le_bytes());
+    }
+    encoded.extend_from_slice(
+        crate::util::encode_scale_compact_usize(signed.signatures.len()).as_ref(),
+    );
+    for (signature, authority) in signed.signatures.iter().zip(&signed.authorities) {
+        encoded.extend_from_slice(signature);
+        encoded.extend_from_slice(authority);
+    }
+    encoded
+}
+
+fn verify(
+    signed: &Signed,
+    commit: &decode::CommitMessageRef,
+) -> Result<(), justification::verify::Error> {
+    super::verify_commit(super::DecodedConfig {
+        commit,
+        block_number_bytes: 4,
+        expected_authorities_set_id: SET_ID,
+        authorities_list: signed.authorities.iter().map(|a| &a[..]),
+        randomness_seed: [0; 32],
+    })
+}
+
+#[test]
+fn valid_commit() {
+    let signed = build(10, 7);
+    verify(&signed, &commit(&signed)).unwrap();
+}
+
+#[test]
+fn not_enough_signatures() {
+    let signed = build(10, 6);
+    assert!(matches!(
+        verify(&signed, &commit(&signed)),
+        Err(justification::verify::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn bad_signature() {
+    let mut signed = build(10, 7);
+    signed.signatures[3][5] ^= 1;
+    assert!(matches!(
+        verify(&signed, &commit(&signed)),
+        Err(justification::verify::Error::BadSignature)
+    ));
+}
+
+#[test]
+fn bad_set_id() {
+    let signed = build(10, 7);
+    let mut commit = commit(&signed);
+    commit.set_id += 1;
+    assert!(matches!(
+        verify(&signed, &commit),
+        Err(justification::verify::Error::SetIdOutOfRange)
+    ));
+}
+
+#[test]
+fn duplicate_signature() {
+    let signed = build(10, 8);
+    let mut commit = commit(&signed);
+    commit.message.auth_data[6] = commit.message.auth_data[2];
+    assert!(matches!(
+        verify(&signed, &commit),
+        Err(justification::verify::Error::DuplicateSignature {
+            authority_index: 2,
+            first_precommit_index: 2,
+            second_precommit_index: 6,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn not_authority() {
+    let signed = build(10, 7);
+    let commit = commit(&signed);
+    let result = super::verify_commit(super::DecodedConfig {
+        commit: &commit,
+        block_number_bytes: 4,
+        expected_authorities_set_id: SET_ID,
+        authorities_list: signed.authorities.iter().skip(1).map(|a| &a[..]),
+        randomness_seed: [0; 32],
+    });
+    assert!(matches!(
+        result,
+        Err(justification::verify::Error::NotAuthority { authority_public_key, precommit_index: 0 })
+            if authority_public_key == signed.authorities[0]
+    ));
+}
+
+#[test]
+fn precommit_target_mismatch() {
+    let mut signed = build(10, 7);
+    signed.precommits[2].0 = [0xbb; 32];
+    assert!(matches!(
+        verify(&signed, &commit(&signed)),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::NotDescendant
+        ))
+    ));
+
+    let mut signed = build(10, 7);
+    signed.precommits[2].1 = TARGET_NUMBER - 1;
+    assert!(matches!(
+        verify(&signed, &commit(&signed)),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::NotDescendant
+        ))
+    ));
+
+    // A block with a higher number might be on a different fork.
+    let mut signed = build(10, 7);
+    signed.precommits[2] = ([0xbb; 32], TARGET_NUMBER + 1);
+    assert!(matches!(
+        verify(&signed, &commit(&signed)),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::MissingHeader { block_hash }
+        )) if block_hash == [0xbb; 32]
+    ));
+}
+
+#[test]
+fn with_ancestries() {
+    // Blocks `TARGET_NUMBER + 1` and `TARGET_NUMBER + 2`, descendants of the target.
+    let child = header::HeaderRef {
+        parent_hash: &TARGET_HASH,
+        number: TARGET_NUMBER + 1,
+        extrinsics_root: &[0; 32],
+        state_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    };
+    let child_hash = child.hash(4);
+    let grandchild = header::HeaderRef {
+        parent_hash: &child_hash,
+        number: TARGET_NUMBER + 2,
+        ..child.clone()
+    };
+    let grandchild_hash = grandchild.hash(4);
+
+    // Builds a commit where one pre-commit targets the grandchild.
+    let build_with_vote = |hash: [u8; 32], number: u64| {
+        let signing_keys = (0..10u8)
+            .map(|n| ed25519_zebra::SigningKey::from([n; 32]))
+            .collect::<Vec<_>>();
+        let precommits = (0..7)
+            .map(|n| {
+                if n == 2 {
+                    (hash, number)
+                } else {
+                    (TARGET_HASH, TARGET_NUMBER)
+                }
+            })
+            .collect::<Vec<_>>();
+        let signatures = signing_keys
+            .iter()
+            .zip(precommits.iter())
+            .map(|(key, (hash, number))| {
+                let mut msg = vec![1u8];
+                msg.extend_from_slice(hash);
+                msg.extend_from_slice(&u32::try_from(*number).unwrap().to_le_bytes());
+                msg.extend_from_slice(&ROUND.to_le_bytes());
+                msg.extend_from_slice(&SET_ID.to_le_bytes());
+                key.sign(&msg).into()
+            })
+            .collect();
+        Signed {
+            authorities: signing_keys
+                .iter()
+                .map(|key| ed25519_zebra::VerificationKey::from(key).into())
+                .collect(),
+            precommits,
+            signatures,
+        }
+    };
+
+    let verify = |signed: &Signed, ancestries: &[header::HeaderRef]| {
+        super::verify_commit_with_ancestries(
+            super::DecodedConfig {
+                commit: &commit(signed),
+                block_number_bytes: 4,
+                expected_authorities_set_id: SET_ID,
+                authorities_list: signed.authorities.iter().map(|a| &a[..]),
+                randomness_seed: [0; 32],
+            },
+            ancestries.iter().cloned(),
+        )
+    };
+
+    let signed = build_with_vote(grandchild_hash, TARGET_NUMBER + 2);
+    verify(&signed, &[child.clone(), grandchild.clone()]).unwrap();
+
+    // Without the ancestries, nothing proves that the grandchild descends from the target.
+    assert!(matches!(
+        super::verify_commit(super::DecodedConfig {
+            commit: &commit(&signed),
+            block_number_bytes: 4,
+            expected_authorities_set_id: SET_ID,
+            authorities_list: signed.authorities.iter().map(|a| &a[..]),
+            randomness_seed: [0; 32],
+        }),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::MissingHeader { block_hash }
+        )) if block_hash == grandchild_hash
+    ));
+
+    assert!(matches!(
+        verify(&signed, core::slice::from_ref(&grandchild)),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::MissingHeader { block_hash }
+        )) if block_hash == child_hash
+    ));
+
+    // The child isn't necessary when voting for the child.
+    let signed = build_with_vote(child_hash, TARGET_NUMBER + 1);
+    assert!(matches!(
+        verify(&signed, &[child.clone(), grandchild.clone()]),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::UnusedHeaders { num_unused: 1 }
+        ))
+    ));
+
+    // Vote for a block whose parent isn't the target.
+    let fork = header::HeaderRef {
+        parent_hash: &[0xcc; 32],
+        ..child.clone()
+    };
+    let signed = build_with_vote(fork.hash(4), TARGET_NUMBER + 1);
+    assert!(matches!(
+        verify(&signed, &[fork]),
+        Err(justification::verify::Error::BadAncestry(
+            votes_ancestries::Error::NotDescendant
+        ))
+    ));
+}
+
+fn verify_external(
+    signed: &Signed,
+    commit: &decode::CommitMessageRef,
+) -> Result<(), justification::verify::Error> {
+    let mut verification = super::verify_commit_external_signatures(super::DecodedConfig {
+        commit,
+        block_number_bytes: 4,
+        expected_authorities_set_id: SET_ID,
+        authorities_list: signed.authorities.iter().map(|a| &a[..]),
+        randomness_seed: [0; 32],
+    });
+
+    loop {
+        match verification {
+            super::CommitVerify::Finished(result) => return result,
+            super::CommitVerify::SignatureVerification(sig) => {
+                verification = sig.verify_and_resume();
+            }
+        }
+    }
+}
+
+#[test]
+fn external_signatures() {
+    let signed = build(10, 7);
+    verify_external(&signed, &commit(&signed)).unwrap();
+
+    let mut signed = build(10, 7);
+    signed.signatures[3][5] ^= 1;
+    assert!(matches!(
+        verify_external(&signed, &commit(&signed)),
+        Err(justification::verify::Error::BadSignature)
+    ));
+
+    let signed = build(10, 6);
+    assert!(matches!(
+        verify_external(&signed, &commit(&signed)),
+        Err(justification::verify::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn external_signatures_batch() {
+    let signed = build(10, 7);
+    let commit = commit(&signed);
+    let super::CommitVerify::SignatureVerification(sig) =
+        super::verify_commit_external_signatures(super::DecodedConfig {
+            commit: &commit,
+            block_number_bytes: 4,
+            expected_authorities_set_id: SET_ID,
+            authorities_list: signed.authorities.iter().map(|a| &a[..]),
+            randomness_seed: [0; 32],
+        })
+    else {
+        panic!()
+    };
+
+    let mut batch = ed25519_zebra::batch::Verifier::new();
+    assert_eq!(sig.remaining().len(), 7);
+    for (message, public_key, signature) in sig.remaining() {
+        batch.queue(ed25519_zebra::batch::Item::from((
+            ed25519_zebra::VerificationKeyBytes::from(*public_key),
+            ed25519_zebra::Signature::from(*signature),
+            &message,
+        )));
+    }
+    batch.verify(rand::thread_rng()).unwrap();
+
+    assert!(matches!(
+        sig.resume_with_results(iter::repeat_n(true, 7)),
+        super::CommitVerify::Finished(Ok(()))
+    ));
+}
+
+#[test]
+fn external_signatures_partial_results() {
+    let mut signed = build(10, 7);
+    signed.signatures[5][5] ^= 1;
+    let commit = commit(&signed);
+
+    let start = || {
+        let super::CommitVerify::SignatureVerification(sig) =
+            super::verify_commit_external_signatures(super::DecodedConfig {
+                commit: &commit,
+                block_number_bytes: 4,
+                expected_authorities_set_id: SET_ID,
+                authorities_list: signed.authorities.iter().map(|a| &a[..]),
+                randomness_seed: [0; 32],
+            })
+        else {
+            panic!()
+        };
+        sig
+    };
+
+    // Reporting the results of only some of the signatures resumes the verification at the
+    // first signature whose result hasn't been reported.
+    let super::CommitVerify::SignatureVerification(sig) =
+        start().resume_with_results(iter::repeat_n(true, 4))
+    else {
+        panic!()
+    };
+    assert_eq!(sig.remaining().len(), 3);
+    assert_eq!(sig.public_key(), &signed.authorities[4]);
+    assert!(sig.is_valid());
+
+    // The invalid signature is still detected.
+    let results = sig
+        .remaining()
+        .map(|(message, public_key, signature)| {
+            ed25519_zebra::VerificationKey::try_from(*public_key)
+                .unwrap()
+                .verify(&ed25519_zebra::Signature::from(*signature), &message)
+                .is_ok()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(results, [true, false, true]);
+    assert!(matches!(
+        sig.resume_with_results(results),
+        super::CommitVerify::Finished(Err(justification::verify::Error::BadSignature))
+    ));
+
+    // A reported failure ends the verification even if other results are missing.
+    assert!(matches!(
+        start().resume_with_results([true, false]),
+        super::CommitVerify::Finished(Err(justification::verify::Error::BadSignature))
+    ));
+}
+
+#[test]
+fn detected_block_number_bytes() {
+    let signed = build(10, 7);
+
+    let mut verification = super::verify(super::Config {
+        commit: encode(&signed),
+        block_number_bytes: 0,
+        expected_authorities_set_id: SET_ID,
+        num_authorities: 10,
+        randomness_seed: [0; 32],
+    });
+    loop {
+        match verification {
+            super::InProgress::IsAuthority(is_authority) => {
+                let is_authority_result = signed
+                    .authorities
+                    .contains(is_authority.authority_public_key());
+                verification = is_authority.resume(is_authority_result);
+            }
+            super::InProgress::IsParent(_) => panic!(),
+            super::InProgress::Finished(result) => {
+                assert!(result.is_ok());
+                break;
+            }
+            super::InProgress::FinishedUnknown => panic!(),
+        }
+    }
+
+    // The number of bytes can't be detected once the commit has been decoded.
+    assert!(matches!(
+        super::verify_commit(super::DecodedConfig {
+            commit: &commit(&signed),
+            block_number_bytes: 0,
+            expected_authorities_set_id: SET_ID,
+            authorities_list: signed.authorities.iter().map(|a| &a[..]),
+            randomness_seed: [0; 32],
+        }),
+        Err(justification::verify::Error::InvalidBlockNumberBytes)
+    ));
+}
@@ -0,0 +1,213 @@
+// Smoldot
+// Copyright (C) 2019-2022  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Neighbor packets are gossiped between the GrandPa participants.
+//!
+//! A neighbor packet indicates the round and authorities set id that the sender is currently
+//! at, and the height of the latest block that it has finalized. It is used in order to
+//! determine which votes and commits are worth sending to a peer.
+//!
+//! The packets found in GrandPa notifications are prefixed with the notification type. See
+//! also [`crate::network::protocol::decode_grandpa_notification`].
+
+use alloc::vec::Vec;
+use core::{cmp, mem};
+
+/// Attempt to decode the given SCALE-encoded neighbor packet.
+///
+/// Returns an error if `block_number_bytes` is larger than 8.
+///
+/// If `block_number_bytes` is 0, it is deduced from the length of `scale_encoded`, as the block
+/// number is the only field of a neighbor packet whose length isn't fixed.
+pub fn decode_neighbor_packet(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+) -> Result<NeighborPacket, Error<'_>> {
+    let block_number_bytes = match block_number_bytes {
+        0 => match scale_encoded.len().checked_sub(1 + 8 + 8) {
+            Some(n @ 1..=8) => n,
+            _ => return Err(Error::BlockNumberBytesUnknown),
+        },
+        n => n,
+    };
+
+    if block_number_bytes > 8 {
+        return Err(Error::InvalidBlockNumberBytes);
+    }
+
+    match nom::combinator::all_consuming(nom::combinator::complete(neighbor_packet(
+        block_number_bytes,
+    )))(scale_encoded)
+    {
+        Ok((_, packet)) => Ok(packet),
+        Err(err) => Err(Error::Decode(err)),
+    }
+}
+
+/// Error potentially returned by [`decode_neighbor_packet`].
+#[derive(Debug, derive_more::Display)]
+pub enum Error<'a> {
+    /// Failed to decode the neighbor packet.
+    #[display(fmt = "{_0}")]
+    Decode(nom::Err<nom::error::Error<&'a [u8]>>),
+    /// The number of bytes used to encode block numbers is larger than 8.
+    InvalidBlockNumberBytes,
+    /// The number of bytes used to encode block numbers is 0, and the length of the neighbor
+    /// packet doesn't correspond to any valid number of bytes.
+    BlockNumberBytesUnknown,
+}
+
+/// Decoded neighbor packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighborPacket {
+    /// Round the sender is currently at.
+    pub round_number: u64,
+    /// Authorities set id the sender is currently at.
+    pub set_id: u64,
+    /// Height of the latest block that the sender has finalized.
+    pub commit_finalized_height: u64,
+}
+
+impl NeighborPacket {
+    /// Returns an iterator to list of buffers which, when concatenated, produces the SCALE
+    /// encoding of that object.
+    pub fn scale_encoding(
+        &self,
+        block_number_bytes: usize,
+    ) -> impl Iterator<Item = impl AsRef<[u8]> + Clone> + Clone {
+        let mut commit_finalized_height = Vec::with_capacity(cmp::max(
+            block_number_bytes,
+            mem::size_of_val(&self.commit_finalized_height),
+        ));
+        commit_finalized_height.extend(self.commit_finalized_height.to_le_bytes());
+        // TODO: unclear what to do if the block number doesn't fit in `block_number_bytes`
+        debug_assert!(!commit_finalized_height
+            .iter()
+            .skip(block_number_bytes)
+            .any(|b| *b != 0));
+        commit_finalized_height.resize(block_number_bytes, 0);
+
+        [
+            either::Right(either::Left([1u8])),
+            either::Left(self.round_number.to_le_bytes()),
+            either::Left(self.set_id.to_le_bytes()),
+            either::Right(either::Right(commit_finalized_height)),
+        ]
+        .into_iter()
+    }
+}
+
+/// Nom combinator that parses a neighbor packet, starting with its version byte.
+pub(crate) fn neighbor_packet<'a, E: nom::error::ParseError<&'a [u8]>>(
+    block_number_bytes: usize,
+) -> impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], NeighborPacket, E> {
+    nom::combinator::map(
+        nom::sequence::preceded(
+            nom::bytes::streaming::tag(&[1]),
+            nom::sequence::tuple((
+                nom::number::streaming::le_u64,
+                nom::number::streaming::le_u64,
+                crate::util::nom_varsize_number_decode_u64(block_number_bytes),
+            )),
+        ),
+        |(round_number, set_id, commit_finalized_height)| NeighborPacket {
+            round_number,
+            set_id,
+            commit_finalized_height,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NeighborPacket;
+
+    const ENCODED: &[u8] = &[
+        1, 87, 14, 0, 0, 0, 0, 0, 0, 162, 13, 0, 0, 0, 0, 0, 0, 49, 231, 77, 0,
+    ];
+
+    const DECODED: NeighborPacket = NeighborPacket {
+        round_number: 3671,
+        set_id: 3490,
+        commit_finalized_height: 5_105_457,
+    };
+
+    #[test]
+    fn basic_decode() {
+        assert_eq!(super::decode_neighbor_packet(ENCODED, 4).unwrap(), DECODED);
+
+        // Trailing data isn't allowed.
+        let mut with_trailing = ENCODED.to_vec();
+        with_trailing.push(0);
+        assert!(matches!(
+            super::decode_neighbor_packet(&with_trailing, 4),
+            Err(super::Error::Decode(_))
+        ));
+
+        // Unknown version.
+        let mut unknown_version = ENCODED.to_vec();
+        unknown_version[0] = 2;
+        assert!(matches!(
+            super::decode_neighbor_packet(&unknown_version, 4),
+            Err(super::Error::Decode(_))
+        ));
+
+        assert!(matches!(
+            super::decode_neighbor_packet(&ENCODED[..20], 4),
+            Err(super::Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn block_number_bytes() {
+        assert_eq!(super::decode_neighbor_packet(ENCODED, 0).unwrap(), DECODED);
+        assert_eq!(
+            super::decode_neighbor_packet(&ENCODED[..20], 0).unwrap(),
+            DECODED
+        );
+
+        assert!(matches!(
+            super::decode_neighbor_packet(ENCODED, 9),
+            Err(super::Error::InvalidBlockNumberBytes)
+        ));
+        assert!(matches!(
+            super::decode_neighbor_packet(&ENCODED[..17], 0),
+            Err(super::Error::BlockNumberBytesUnknown)
+        ));
+        assert!(matches!(
+            super::decode_neighbor_packet(&[0; 26], 0),
+            Err(super::Error::BlockNumberBytesUnknown)
+        ));
+    }
+
+    #[test]
+    fn encode_decode() {
+        for block_number_bytes in [3, 4, 8] {
+            let encoded =
+                DECODED
+                    .scale_encoding(block_number_bytes)
+                    .fold(Vec::new(), |mut a, b| {
+                        a.extend_from_slice(b.as_ref());
+                        a
+                    });
+            assert_eq!(
+                super::decode_neighbor_packet(&encoded, block_number_bytes).unwrap(),
+                DECODED
+            );
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Smoldot
+// Copyright (C) 2019-2022  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Messages signed by the GrandPa authorities.
+//!
+//! Each pre-commit found in a commit or in a justification is accompanied with a signature of
+//! the authority that has emitted it. The message being signed is built from the target of the
+//! pre-commit, the round number, and the authorities set id.
+//!
+//! Pre-votes are signed in the same way, except for the first byte of the message. See
+//! [`grandpa_vote_message`].
+
+use alloc::{vec, vec::Vec};
+use core::{cmp, mem};
+
+/// Maximum length of the message signed by a pre-commit, when the block number is encoded with
+/// at most 8 bytes. See [`write_grandpa_precommit_signed_message`].
+pub const MAX_PRECOMMIT_SIGNED_MESSAGE_LEN: usize = 1 + 32 + 8 + 8 + 8;
+
+/// Kind of vote emitted by an authority during a GrandPa round.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VoteStage {
+    /// First stage of a round.
+    Prevote,
+    /// Second stage of a round. Pre-commits are the votes found in commits and justifications.
+    Precommit,
+}
+
+impl VoteStage {
+    /// Returns the byte that the signed message starts with.
+    pub fn prefix_byte(&self) -> u8 {
+        match self {
+            VoteStage::Prevote => 0,
+            VoteStage::Precommit => 1,
+        }
+    }
+}
+
+/// Builds the message that an authority signs when emitting a vote of the given stage.
+///
+/// This is the exact data that must be passed to an external signer, such as a hardware wallet.
+/// Calling this function with [`VoteStage::Precommit`] is equivalent to calling
+/// [`grandpa_precommit_signed_message`].
+pub fn grandpa_vote_message(
+    stage: VoteStage,
+    target_hash: &[u8; 32],
+    target_number: u64,
+    round: u64,
+    set_id: u64,
+    block_number_bytes: usize,
+) -> Vec<u8> {
+    let mut msg = vec![0; 1 + 32 + block_number_bytes + 8 + 8];
+    write_vote_signed_message(
+        stage,
+        target_hash,
+        target_number,
+        block_number_bytes,
+        round,
+        set_id,
+        &mut msg,
+    );
+    msg
+}
+
+/// Builds the message that an authority signs when emitting a pre-commit.
+///
+/// The length of the returned message only depends on `block_number_bytes`.
+pub fn grandpa_precommit_signed_message(
+    target_hash: &[u8; 32],
+    target_number: u64,
+    block_number_bytes: usize,
+    round: u64,
+    set_id: u64,
+) -> Vec<u8> {
+    grandpa_vote_message(
+        VoteStage::Precommit,
+        target_hash,
+        target_number,
+        round,
+        set_id,
+        block_number_bytes,
+    )
+}
+
+/// Writes the message that an authority signs when emitting a pre-commit into the given buffer,
+/// and returns the part of the buffer that contains the message.
+///
+/// Contrary to [`grandpa_precommit_signed_message`], doesn't perform any heap allocation.
+///
+/// Returns `None` if `block_number_bytes` is larger than 8, in which case the message doesn't
+/// fit in the buffer.
+pub fn write_grandpa_precommit_signed_message<'a>(
+    target_hash: &[u8; 32],
+    target_number: u64,
+    block_number_bytes: usize,
+    round: u64,
+    set_id: u64,
+    out: &'a mut [u8; MAX_PRECOMMIT_SIGNED_MESSAGE_LEN],
+) -> Option<&'a [u8]> {
+    if block_number_bytes > 8 {
+        return None;
+    }
+
+    let out = &mut out[..1 + 32 + block_number_bytes + 8 + 8];
+    write_vote_signed_message(
+        VoteStage::Precommit,
+        target_hash,
+        target_number,
+        block_number_bytes,
+        round,
+        set_id,
+        out,
+    );
+    Some(out)
+}
+
+/// Writes the message that an authority signs when emitting a vote. The length of `out` must be
+/// exactly the length of the message.
+fn write_vote_signed_message(
+    stage: VoteStage,
+    target_hash: &[u8; 32],
+    target_number: u64,
+    block_number_bytes: usize,
+    round: u64,
+    set_id: u64,
+    out: &mut [u8],
+) {
+    debug_assert_eq!(out.len(), 1 + 32 + block_number_bytes + 8 + 8);
+
+    out[0] = stage.prefix_byte(); // Indicates which kind of message is being signed.
+    out[1..33].copy_from_slice(&target_hash[..]);
+
+    // The message contains the little endian block number. While simple in concept,
+    // in reality it is more complicated because we don't know the number of bytes of
+    // this block number at compile time. We thus copy as many bytes as appropriate and
+    // pad with 0s if necessary.
+    let (block_number, rest) = out[33..].split_at_mut(block_number_bytes);
+    let num_copied = cmp::min(mem::size_of_val(&target_number), block_number_bytes);
+    block_number[..num_copied].copy_from_slice(&target_number.to_le_bytes()[..num_copied]);
+    block_number[num_copied..].fill(0);
+
+    rest[..8].copy_from_slice(&u64::to_le_bytes(round)[..]);
+    rest[8..].copy_from_slice(&u64::to_le_bytes(set_id)[..]);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn precommit_signed_message() {
+        let msg = super::grandpa_precommit_signed_message(&[0xaa; 32], 0x0102, 4, 12, 3);
+        let mut expected = vec![1u8];
+        expected.extend_from_slice(&[0xaa; 32]);
+        expected.extend_from_slice(&[0x02, 0x01, 0, 0]);
+        expected.extend_from_slice(&[12, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(msg, expected);
+    }
+
+    #[test]
+    fn vote_message() {
+        let prevote =
+            super::grandpa_vote_message(super::VoteStage::Prevote, &[0xaa; 32], 0x0102, 12, 3, 4);
+        let precommit =
+            super::grandpa_vote_message(super::VoteStage::Precommit, &[0xaa; 32], 0x0102, 12, 3, 4);
+
+        assert_eq!(prevote[0], 0);
+        assert_eq!(precommit[0], 1);
+        assert_eq!(prevote[1..], precommit[1..]);
+        assert_eq!(
+            precommit,
+            super::grandpa_precommit_signed_message(&[0xaa; 32], 0x0102, 4, 12, 3)
+        );
+    }
+
+    #[test]
+    fn precommit_signed_message_large_block_number() {
+        let msg = super::grandpa_precommit_signed_message(&[0xaa; 32], u64::MAX, 10, 12, 3);
+        assert_eq!(msg.len(), 1 + 32 + 10 + 8 + 8);
+        assert_eq!(&msg[33..41], &[0xff; 8]);
+        assert_eq!(&msg[41..43], &[0, 0]);
+    }
+
+    #[test]
+    fn write_precommit_signed_message() {
+        for block_number_bytes in 0..=8 {
+            let mut buffer = [0xff; super::MAX_PRECOMMIT_SIGNED_MESSAGE_LEN];
+            let msg = super::write_grandpa_precommit_signed_message(
+                &[0xaa; 32],
+                0x0102,
+                block_number_bytes,
+                12,
+                3,
+                &mut buffer,
+            )
+            .unwrap();
+            assert_eq!(
+                msg,
+                super::grandpa_precommit_signed_message(
+                    &[0xaa; 32],
+                    0x0102,
+                    block_number_bytes,
+                    12,
+                    3
+                )
+            );
+        }
+
+        let mut buffer = [0; super::MAX_PRECOMMIT_SIGNED_MESSAGE_LEN];
+        assert!(super::write_grandpa_precommit_signed_message(
+            &[0xaa; 32],
+            0x0102,
+            9,
+            12,
+            3,
+            &mut buffer
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn precommit_signed_message_reference_4_bytes() {
+        // Pre-commit of a commit gossiped on Polkadot, on round 3669 of the authorities set 3490.
+        let target_hash = [
+            182, 68, 115, 35, 15, 201, 152, 195, 12, 181, 59, 244, 231, 124, 34, 248, 98, 253, 4,
+            180, 158, 70, 161, 84, 76, 118, 151, 68, 101, 104, 187, 82,
+        ];
+        let public_key = [
+            45, 154, 173, 12, 30, 12, 25, 95, 195, 198, 235, 98, 29, 248, 44, 121, 73, 203, 132,
+            51, 196, 138, 65, 42, 3, 49, 169, 182, 129, 146, 242, 193,
+        ];
+        let signature = [
+            189, 185, 216, 33, 163, 12, 201, 104, 162, 255, 11, 241, 156, 90, 244, 205, 251, 44,
+            45, 139, 129, 117, 178, 85, 129, 78, 58, 255, 76, 232, 199, 85, 236, 30, 227, 87, 50,
+            34, 22, 27, 241, 6, 33, 137, 55, 5, 190, 36, 122, 61, 112, 51, 99, 34, 119, 46, 185,
+            156, 188, 133, 140, 103, 33, 10,
+        ];
+
+        let msg = super::grandpa_precommit_signed_message(&target_hash, 5_105_457, 4, 3669, 3490);
+        assert_eq!(
+            hex::encode(&msg),
+            "01b64473230fc998c30cb53bf4e77c22f862fd04b49e46a1544c7697446568bb52\
+             31e74d00550e000000000000a20d000000000000"
+        );
+
+        // The message is the one that has actually been signed by the authority.
+        ed25519_zebra::VerificationKey::try_from(public_key)
+            .unwrap()
+            .verify(&ed25519_zebra::Signature::from(signature), &msg)
+            .unwrap();
+    }
+
+    #[test]
+    fn precommit_signed_message_8_bytes() {
+        // Expected bytes written by hand following the SCALE encoding of
+        // `(Message::Precommit(Precommit { target_hash, target_number }), round, set_id)`, with a
+        // `u64` block number. Contrary to the 4 bytes test above, this doesn't come from a real
+        // chain.
+        let msg = super::grandpa_precommit_signed_message(
+            &[0x11; 32],
+            0x0102_0304_0506_0708,
+            8,
+            0x1_0000_0000,
+            7,
+        );
+        assert_eq!(
+            hex::encode(&msg),
+            "011111111111111111111111111111111111111111111111111111111111111111\
+             0807060504030201\
+             0000000001000000\
+             0700000000000000"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+// Smoldot
+// Copyright (C) 2019-2022  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verification of the ancestry of the blocks voted on by pre-commits.
+//!
+//! The pre-commits found in a commit or in a justification can target either the target of the
+//! commit or justification, or one of its descendants. Justifications, and optionally commits,
+//! are accompanied with the headers of the blocks between the target and the blocks voted on,
+//! called the votes ancestries, which prove that the voted blocks are indeed descendants of the
+//! target.
+//!
+//! Every header of the votes ancestries is expected to be necessary in order to prove the
+//! ancestry of at least one pre-commit. See [`VotesAncestries::num_unused`].
+
+use crate::header;
+
+/// Headers of the votes ancestries, indexed by hash.
+pub struct VotesAncestries<'a> {
+    /// Headers, indexed by their hash.
+    headers: hashbrown::HashMap<[u8; 32], Entry<'a>, crate::util::SipHasherBuild>,
+}
+
+struct Entry<'a> {
+    /// Number of the block.
+    number: u64,
+    /// Hash of the parent of the block.
+    parent_hash: &'a [u8; 32],
+    /// `true` if the header has been used to prove the ancestry of a block.
+    used: bool,
+    /// Sum of the weights of the votes for this block or one of its descendants. See
+    /// [`VotesAncestries::add_vote`].
+    weight: u128,
+}
+
+impl<'a> VotesAncestries<'a> {
+    /// Builds a new [`VotesAncestries`] from a list of headers and their hash.
+    ///
+    /// The `hasher_seed` is used to randomize the container that stores the headers, and should
+    /// be generated randomly.
+    pub fn new(
+        headers: impl Iterator<Item = ([u8; 32], header::HeaderRef<'a>)>,
+        hasher_seed: [u8; 16],
+    ) -> Self {
+        let mut map = hashbrown::HashMap::with_capacity_and_hasher(
+            headers.size_hint().0,
+            crate::util::SipHasherBuild::new(hasher_seed),
+        );
+
+        for (hash, header) in headers {
+            map.insert(
+                hash,
+                Entry {
+                    number: header.number,
+                    parent_hash: header.parent_hash,
+                    used: false,
+                    weight: 0,
+                },
+            );
+        }
+
+        VotesAncestries { headers: map }
+    }
+
+    /// Checks that the given block is the given ancestor or one of its descendants, by walking
+    /// through the headers of the votes ancestries.
+    ///
+    /// The headers that are walked through are marked as used.
+    pub fn check_descendant(
+        &mut self,
+        ancestor_hash: &[u8; 32],
+        ancestor_number: u64,
+        block_hash: &[u8; 32],
+        block_number: u64,
+    ) -> Result<(), Error> {
+        let mut hash = *block_hash;
+        let mut number = block_number;
+
+        loop {
+            if number <= ancestor_number {
+                return if number == ancestor_number && hash == *ancestor_hash {
+                    Ok(())
+                } else {
+                    Err(Error::NotDescendant)
+                };
+            }
+
+            let entry = self
+                .headers
+                .get_mut(&hash)
+                .ok_or(Error::MissingHeader { block_hash: hash })?;
+
+            // The number in the header must be consistent with the one of its child.
+            if entry.number != number {
+                return Err(Error::NotDescendant);
+            }
+
+            entry.used = true;
+            hash = *entry.parent_hash;
+            number -= 1;
+        }
+    }
+
+    /// Checks, like [`VotesAncestries::check_descendant`], that the given block is the given
+    /// ancestor or one of its descendants, then adds `weight` to the weight of the given block
+    /// and of all the blocks between it and the ancestor.
+    ///
+    /// The weights are later used by [`VotesAncestries::ghost`].
+    pub fn add_vote(
+        &mut self,
+        ancestor_hash: &[u8; 32],
+        ancestor_number: u64,
+        block_hash: &[u8; 32],
+        block_number: u64,
+        weight: u64,
+    ) -> Result<(), Error> {
+        self.check_descendant(ancestor_hash, ancestor_number, block_hash, block_number)?;
+
+        // All the headers have been found by `check_descendant`.
+        let mut hash = *block_hash;
+        for _ in ancestor_number..block_number {
+            let entry = self.headers.get_mut(&hash).unwrap();
+            entry.weight += u128::from(weight);
+            hash = *entry.parent_hash;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the hash and number of the GHOST of the votes added with
+    /// [`VotesAncestries::add_vote`], in other words the highest block that is `base` or one of
+    /// its descendants and whose weight is strictly superior to `threshold`.
+    ///
+    /// The weight of `base` itself isn't known, and it is assumed to be strictly superior to
+    /// `threshold`.
+    ///
+    /// `threshold` is expected to be at least half of the total weight of the votes, in which
+    /// case at most one child of each block can possibly be above the threshold.
+    pub fn ghost(
+        &self,
+        base_hash: &[u8; 32],
+        base_number: u64,
+        threshold: u128,
+    ) -> ([u8; 32], u64) {
+        let mut ghost = (*base_hash, base_number);
+
+        while let Some((hash, _)) = self.headers.iter().find(|(_, entry)| {
+            *entry.parent_hash == ghost.0 && entry.number == ghost.1 + 1 && entry.weight > threshold
+        }) {
+            ghost = (*hash, ghost.1 + 1);
+        }
+
+        ghost
+    }
+
+    /// Returns the number of headers that haven't been used by
+    /// [`VotesAncestries::check_descendant`] so far.
+    pub fn num_unused(&self) -> usize {
+        self.headers.values().filter(|entry| !entry.used).count()
+    }
+}
+
+/// Error that can happen while checking the ancestry of a block voted on.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum Error {
+    /// Block voted on isn't a descendant of the target block.
+    #[display(fmt = "Block voted on isn't a descendant of the target block")]
+    NotDescendant,
+    /// The votes ancestries don't contain the header of a block that is necessary in order to
+    /// prove the ancestry of a block voted on.
+    #[display(
+        fmt = "Missing header of block 0x{} in votes ancestries",
+        "hex::encode(block_hash)"
+    )]
+    MissingHeader {
+        /// Hash of the block whose header is missing.
+        block_hash: [u8; 32],
+    },
+    /// Some headers of the votes ancestries aren't necessary in order to prove the ancestry of
+    /// any block voted on.
+    #[display(fmt = "{num_unused} unnecessary headers in votes ancestries")]
+    UnusedHeaders {
+        /// Number of unnecessary headers.
+        num_unused: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::header;
+
+    fn header(parent_hash: &[u8; 32], number: u64) -> header::HeaderRef<'_> {
+        header::HeaderRef {
+            parent_hash,
+            number,
+            extrinsics_root: &[0; 32],
+            state_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+    }
+
+    #[test]
+    fn check_descendant() {
+        // Chain of blocks 10 (target) <- 11 <- 12, plus an unrelated block 13.
+        let target = [10; 32];
+        let headers = [
+            ([11; 32], header(&target, 11)),
+            ([12; 32], header(&[11; 32], 12)),
+            ([13; 32], header(&[0xff; 32], 13)),
+        ];
+        let mut ancestries = super::VotesAncestries::new(headers.into_iter(), [0; 16]);
+        assert_eq!(ancestries.num_unused(), 3);
+
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &target, 10),
+            Ok(())
+        );
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[12; 32], 12),
+            Ok(())
+        );
+        assert_eq!(ancestries.num_unused(), 1);
+
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[13; 32], 13),
+            Err(super::Error::MissingHeader {
+                block_hash: [0xff; 32]
+            })
+        );
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[14; 32], 14),
+            Err(super::Error::MissingHeader {
+                block_hash: [14; 32]
+            })
+        );
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[9; 32], 9),
+            Err(super::Error::NotDescendant)
+        );
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[11; 32], 10),
+            Err(super::Error::NotDescendant)
+        );
+        // Header found, but with a number inconsistent with the one of the vote.
+        assert_eq!(
+            ancestries.check_descendant(&target, 10, &[12; 32], 13),
+            Err(super::Error::NotDescendant)
+        );
+    }
+
+    #[test]
+    fn ghost() {
+        // Blocks 10 (target) <- 11 <- 12a and 11 <- 12b.
+        let target = [10; 32];
+        let headers = [
+            ([11; 32], header(&target, 11)),
+            ([0x1a; 32], header(&[11; 32], 12)),
+            ([0x1b; 32], header(&[11; 32], 12)),
+        ];
+        let mut ancestries = super::VotesAncestries::new(headers.into_iter(), [0; 16]);
+        assert_eq!(ancestries.ghost(&target, 10, 6), (target, 10));
+
+        // The votes are split between the two forks, and no block above 11 has a supermajority.
+        assert_eq!(ancestries.add_vote(&target, 10, &[0x1a; 32], 12, 4), Ok(()));
+        assert_eq!(ancestries.add_vote(&target, 10, &[0x1b; 32], 12, 3), Ok(()));
+        assert_eq!(ancestries.ghost(&target, 10, 6), ([11; 32], 11));
+        assert_eq!(ancestries.num_unused(), 0);
+
+        assert_eq!(ancestries.add_vote(&target, 10, &[0x1a; 32], 12, 3), Ok(()));
+        assert_eq!(ancestries.ghost(&target, 10, 6), ([0x1a; 32], 12));
+
+        // Failing votes don't change the weights.
+        assert_eq!(
+            ancestries.add_vote(&target, 10, &[0x1c; 32], 12, 10),
+            Err(super::Error::MissingHeader {
+                block_hash: [0x1c; 32]
+            })
+        );
+        assert_eq!(ancestries.ghost(&target, 10, 6), ([0x1a; 32], 12));
+    }
+}
@@ -15,22 +15,34 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::header;
+use crate::{
+    finality::grandpa::{self, commit::decode::CommitMessageRef},
+    header,
+};
 
 use alloc::vec::Vec;
 use core::fmt;
 
 /// Attempt to decode the given SCALE-encoded justification.
+///
+/// If `block_number_bytes` is 0, it is detected using [`detect_block_number_bytes`]. This
+/// detection is best-effort, and passing the actual value should be preferred whenever it is
+/// known.
 pub fn decode_grandpa(
     scale_encoded: &[u8],
     block_number_bytes: usize,
 ) -> Result<GrandpaJustificationRef, Error> {
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes(scale_encoded)?,
+        n => n,
+    };
+
     match nom::combinator::complete(nom::combinator::all_consuming(grandpa_justification(
         block_number_bytes,
     )))(scale_encoded)
     {
         Ok((_, justification)) => Ok(justification),
-        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error(err.code)),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error::Parse(err.code)),
         Err(_) => unreachable!(),
     }
 }
@@ -38,18 +50,74 @@ pub fn decode_grandpa(
 /// Attempt to decode the given SCALE-encoded justification.
 ///
 /// Contrary to [`decode_grandpa`], doesn't return an error if the slice is too long but returns
-/// the remainder.
+/// the remainder. Because the end of the justification isn't known, `block_number_bytes` can't
+/// be detected, and [`Error::BlockNumberBytesUnknown`] is returned if it is 0.
 pub fn decode_partial_grandpa(
     scale_encoded: &[u8],
     block_number_bytes: usize,
 ) -> Result<(GrandpaJustificationRef, &[u8]), Error> {
+    if block_number_bytes == 0 {
+        return Err(Error::BlockNumberBytesUnknown);
+    }
+
     match nom::combinator::complete(grandpa_justification(block_number_bytes))(scale_encoded) {
         Ok((remainder, justification)) => Ok((justification, remainder)),
-        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error(err.code)),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error::Parse(err.code)),
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Attempt to decode the given SCALE-encoded justification.
+///
+/// Contrary to [`decode_grandpa`], the headers of the votes ancestries aren't decoded ahead of
+/// time, but only when iterating over [`GrandpaJustificationLazyRef::votes_ancestries`]. This
+/// makes it possible to stop decoding the headers as soon as the desired one has been found.
+///
+/// > **Note**: Because the votes ancestries headers are the last field of a justification, no
+/// >           error is returned if the justification is followed with unexpected data. Such
+/// >           data will instead lead to an error when iterating over the headers.
+///
+/// If `block_number_bytes` is 0, it is detected using [`detect_block_number_bytes`], which
+/// requires decoding the headers of the votes ancestries.
+pub fn decode_grandpa_lazy(
+    scale_encoded: &[u8],
+    block_number_bytes: usize,
+) -> Result<GrandpaJustificationLazyRef, Error> {
+    let block_number_bytes = match block_number_bytes {
+        0 => detect_block_number_bytes(scale_encoded)?,
+        n => n,
+    };
+
+    match nom::combinator::complete(nom::combinator::all_consuming(grandpa_justification_lazy(
+        block_number_bytes,
+    )))(scale_encoded)
+    {
+        Ok((_, justification)) => Ok(justification),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error::Parse(err.code)),
         Err(_) => unreachable!(),
     }
 }
 
+/// Finds the number of bytes used to encode block numbers in the given SCALE-encoded
+/// justification.
+///
+/// Returns [`Error::BlockNumberBytesUnknown`] if the justification can't be decoded with any
+/// number of bytes, or if it can be decoded with more than one. The latter is unlikely but
+/// possible, as the justification doesn't contain the number of bytes.
+pub fn detect_block_number_bytes(scale_encoded: &[u8]) -> Result<usize, Error> {
+    let mut candidates = (1..=8).filter(|n| {
+        nom::combinator::complete(nom::combinator::all_consuming(grandpa_justification(*n)))(
+            scale_encoded,
+        )
+        .is_ok()
+    });
+
+    match (candidates.next(), candidates.next()) {
+        (Some(n), None) => Ok(n),
+        _ => Err(Error::BlockNumberBytesUnknown),
+    }
+}
+
 const PRECOMMIT_ENCODED_LEN: usize = 32 + 4 + 64 + 32;
 
 /// Decoded justification.
@@ -63,6 +131,18 @@ pub struct GrandpaJustificationRef<'a> {
     pub votes_ancestries: VotesAncestriesIter<'a>,
 }
 
+/// Decoded justification whose votes ancestries haven't been decoded yet.
+///
+/// See [`decode_grandpa_lazy`].
+#[derive(Debug)]
+pub struct GrandpaJustificationLazyRef<'a> {
+    pub round: u64,
+    pub target_hash: &'a [u8; 32],
+    pub target_number: u64,
+    pub precommits: PrecommitsRef<'a>,
+    pub votes_ancestries: VotesAncestriesLazyIter<'a>,
+}
+
 /// Decoded justification.
 // TODO: document and explain
 #[derive(Debug)]
@@ -71,7 +151,128 @@ pub struct GrandpaJustification {
     pub target_hash: [u8; 32],
     pub target_number: u64,
     pub precommits: Vec<Precommit>,
-    // TODO: pub votes_ancestries: Vec<Header>,
+    pub votes_ancestries: Vec<header::Header>,
+}
+
+impl GrandpaJustification {
+    /// Builds a justification from a commit message and the headers of the ancestries of the
+    /// blocks voted for.
+    ///
+    /// This makes it possible to verify a commit message received through the gossiping
+    /// protocol using the justification verification code.
+    ///
+    /// > **Note**: The authorities set id found in the commit message isn't part of a
+    /// >           justification, and must be passed separately to the verification.
+    pub fn from_commit(
+        commit: &CommitMessageRef,
+        votes_ancestries: Vec<header::Header>,
+    ) -> GrandpaJustification {
+        GrandpaJustification {
+            round: commit.round_number,
+            target_hash: *commit.message.target_hash,
+            target_number: commit.message.target_number,
+            precommits: commit
+                .message
+                .signed_precommits()
+                .map(|(precommit, signature, public_key)| Precommit {
+                    target_hash: *precommit.target_hash,
+                    target_number: precommit.target_number,
+                    signature: *signature,
+                    authority_public_key: *public_key,
+                })
+                .collect(),
+            votes_ancestries,
+        }
+    }
+
+    /// Builds a valid justification signed by `num_authorities` synthetic authorities, and
+    /// returns it alongside with the public keys of these authorities.
+    ///
+    /// All the authorities vote for the block number 1 whose hash is `[0xaa; 32]`. The keys are
+    /// derived from the index of each authority, and the output is thus deterministic.
+    ///
+    /// The justification is valid when verified against the returned list of authorities,
+    /// `set_id` as authorities set id, and `block_number_bytes` as number of bytes of block
+    /// numbers. This is meant to be used to write tests and benchmarks of the verification.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn synthetic(
+        num_authorities: usize,
+        round: u64,
+        set_id: u64,
+        block_number_bytes: usize,
+    ) -> (GrandpaJustification, Vec<[u8; 32]>) {
+        const TARGET_HASH: [u8; 32] = [0xaa; 32];
+        const TARGET_NUMBER: u64 = 1;
+
+        let message = crate::finality::grandpa::signed_message::grandpa_precommit_signed_message(
+            &TARGET_HASH,
+            TARGET_NUMBER,
+            block_number_bytes,
+            round,
+            set_id,
+        );
+
+        let precommits = (0..num_authorities)
+            .map(|authority_index| {
+                let mut seed = [0; 32];
+                seed[..8].copy_from_slice(&u64::try_from(authority_index).unwrap().to_le_bytes());
+                let signing_key = ed25519_zebra::SigningKey::from(seed);
+
+                Precommit {
+                    target_hash: TARGET_HASH,
+                    target_number: TARGET_NUMBER,
+                    signature: signing_key.sign(&message).into(),
+                    authority_public_key: ed25519_zebra::VerificationKey::from(&signing_key).into(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let authorities = precommits
+            .iter()
+            .map(|precommit| precommit.authority_public_key)
+            .collect();
+
+        let justification = GrandpaJustification {
+            round,
+            target_hash: TARGET_HASH,
+            target_number: TARGET_NUMBER,
+            precommits,
+            votes_ancestries: Vec::new(),
+        };
+
+        (justification, authorities)
+    }
+}
+
+impl<'a> GrandpaJustificationRef<'a> {
+    /// Returns, for each pre-commit of the justification, the public key of its author, its
+    /// signature, and the message that has been signed.
+    ///
+    /// These are the signatures whose validity is checked by
+    /// [`verify`](crate::finality::justification::verify::verify), making it possible to verify
+    /// them individually, for example on multiple threads.
+    ///
+    /// > **Note**: The signatures are the only thing that can be verified this way. Whether the
+    /// >           authors are authorities, whether they have signed more than once, whether
+    /// >           enough authorities have signed, and the ancestry of the blocks voted for must
+    /// >           still be checked by the caller.
+    pub fn verification_items(
+        &self,
+        authorities_set_id: u64,
+        block_number_bytes: usize,
+    ) -> impl Iterator<Item = (&'a [u8; 32], &'a [u8; 64], Vec<u8>)> {
+        let round = self.round;
+        self.precommits.iter().map(move |precommit| {
+            let message = grandpa::signed_message::grandpa_precommit_signed_message(
+                precommit.target_hash,
+                precommit.target_number,
+                block_number_bytes,
+                round,
+                authorities_set_id,
+            );
+            (precommit.authority_public_key, precommit.signature, message)
+        })
+    }
 }
 
 impl<'a> From<&'a GrandpaJustification> for GrandpaJustificationRef<'a> {
@@ -83,11 +284,8 @@ impl<'a> From<&'a GrandpaJustification> for GrandpaJustificationRef<'a> {
             precommits: PrecommitsRef {
                 inner: PrecommitsRefInner::Decoded(&j.precommits),
             },
-            // TODO:
             votes_ancestries: VotesAncestriesIter {
-                slice: &[],
-                num: 0,
-                block_number_bytes: 4,
+                inner: VotesAncestriesIterInner::Decoded(j.votes_ancestries.iter()),
             },
         }
     }
@@ -100,6 +298,7 @@ impl<'a> From<GrandpaJustificationRef<'a>> for GrandpaJustification {
             target_hash: *j.target_hash,
             target_number: j.target_number,
             precommits: j.precommits.iter().map(Into::into).collect(),
+            votes_ancestries: j.votes_ancestries.map(Into::into).collect(),
         }
     }
 }
@@ -125,7 +324,7 @@ enum PrecommitsRefInner<'a> {
 }
 
 impl<'a> PrecommitsRef<'a> {
-    pub fn iter(&self) -> impl ExactSizeIterator<Item = PrecommitRef<'a>> + 'a {
+    pub fn iter(&self) -> PrecommitsRefIter<'a> {
         match self.inner {
             PrecommitsRefInner::Undecoded {
                 data,
@@ -147,10 +346,13 @@ impl<'a> PrecommitsRef<'a> {
     }
 }
 
+/// Iterator towards the precommits of a justification. See [`PrecommitsRef::iter`].
+#[derive(Clone)]
 pub struct PrecommitsRefIter<'a> {
     inner: PrecommitsRefIterInner<'a>,
 }
 
+#[derive(Clone)]
 enum PrecommitsRefIterInner<'a> {
     Decoded(core::slice::Iter<'a, Precommit>),
     Undecoded {
@@ -222,7 +424,7 @@ impl<'a> PrecommitRef<'a> {
     ) -> Result<(PrecommitRef, &[u8]), Error> {
         match precommit(block_number_bytes)(scale_encoded) {
             Ok((remainder, precommit)) => Ok((precommit, remainder)),
-            Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error(err.code)),
+            Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(Error::Parse(err.code)),
             Err(_) => unreachable!(),
         }
     }
@@ -269,7 +471,64 @@ impl<'a> From<PrecommitRef<'a>> for Precommit {
 /// Iterator towards the headers of the vote ancestries.
 #[derive(Debug, Clone)]
 pub struct VotesAncestriesIter<'a> {
-    /// Encoded headers.
+    inner: VotesAncestriesIterInner<'a>,
+}
+
+#[derive(Debug, Clone)]
+enum VotesAncestriesIterInner<'a> {
+    Decoded(core::slice::Iter<'a, header::Header>),
+    Undecoded {
+        /// Encoded headers.
+        slice: &'a [u8],
+        /// Number of headers items remaining.
+        num: usize,
+        /// Number of bytes when encoding the block number.
+        block_number_bytes: usize,
+    },
+}
+
+impl<'a> Iterator for VotesAncestriesIter<'a> {
+    type Item = header::HeaderRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            VotesAncestriesIterInner::Decoded(iter) => iter.next().map(Into::into),
+            VotesAncestriesIterInner::Undecoded {
+                slice,
+                num,
+                block_number_bytes,
+            } => {
+                if *num == 0 {
+                    return None;
+                }
+
+                // Validity is guaranteed when the `VotesAncestriesIter` is constructed.
+                let (item, new_slice) = header::decode_partial(slice, *block_number_bytes).unwrap();
+                *slice = new_slice;
+                *num -= 1;
+
+                Some(item)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            VotesAncestriesIterInner::Decoded(iter) => iter.size_hint(),
+            VotesAncestriesIterInner::Undecoded { num, .. } => (*num, Some(*num)),
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for VotesAncestriesIter<'a> {}
+
+/// Iterator towards the headers of the vote ancestries. Each header is decoded only when
+/// the iterator reaches it.
+///
+/// Once an error has been returned, the iterator doesn't yield any more item.
+#[derive(Debug, Clone)]
+pub struct VotesAncestriesLazyIter<'a> {
+    /// Encoded headers, potentially invalid.
     slice: &'a [u8],
     /// Number of headers items remaining.
     num: usize,
@@ -277,34 +536,52 @@ pub struct VotesAncestriesIter<'a> {
     block_number_bytes: usize,
 }
 
-impl<'a> Iterator for VotesAncestriesIter<'a> {
-    type Item = header::HeaderRef<'a>;
+impl<'a> Iterator for VotesAncestriesLazyIter<'a> {
+    type Item = Result<header::HeaderRef<'a>, header::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.num == 0 {
+            // Data found after the last header is considered as part of the last header.
+            if !self.slice.is_empty() {
+                self.slice = &[];
+                return Some(Err(header::Error::TooLong));
+            }
+
             return None;
         }
 
-        // Validity is guaranteed when the `VotesAncestriesIter` is constructed.
-        let (item, new_slice) =
-            header::decode_partial(self.slice, self.block_number_bytes).unwrap();
-        self.slice = new_slice;
-        self.num -= 1;
-
-        Some(item)
+        match header::decode_partial(self.slice, self.block_number_bytes) {
+            Ok((item, new_slice)) => {
+                self.slice = new_slice;
+                self.num -= 1;
+                Some(Ok(item))
+            }
+            Err(err) => {
+                self.slice = &[];
+                self.num = 0;
+                Some(Err(err))
+            }
+        }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.num, Some(self.num))
+        (0, Some(self.num + 1))
     }
 }
 
-impl<'a> ExactSizeIterator for VotesAncestriesIter<'a> {}
+impl<'a> core::iter::FusedIterator for VotesAncestriesLazyIter<'a> {}
 
 /// Potential error when decoding a justification.
 #[derive(Debug, derive_more::Display)]
-#[display(fmt = "Justification parsing error: {_0:?}")]
-pub struct Error(nom::error::ErrorKind);
+pub enum Error {
+    /// Failed to parse the justification.
+    #[display(fmt = "Justification parsing error: {_0:?}")]
+    Parse(nom::error::ErrorKind),
+    /// The number of bytes used to encode block numbers is 0 and couldn't be detected. See
+    /// [`detect_block_number_bytes`].
+    #[display(fmt = "Couldn't detect the number of bytes used to encode block numbers")]
+    BlockNumberBytesUnknown,
+}
 
 /// `Nom` combinator that parses a justification.
 fn grandpa_justification<'a>(
@@ -333,6 +610,33 @@ fn grandpa_justification<'a>(
     )
 }
 
+/// `Nom` combinator that parses a justification, without decoding the votes ancestries.
+fn grandpa_justification_lazy<'a>(
+    block_number_bytes: usize,
+) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], GrandpaJustificationLazyRef> {
+    nom::error::context(
+        "grandpa_justification",
+        nom::combinator::map(
+            nom::sequence::tuple((
+                nom::number::streaming::le_u64,
+                nom::bytes::streaming::take(32u32),
+                crate::util::nom_varsize_number_decode_u64(block_number_bytes),
+                precommits(block_number_bytes),
+                votes_ancestries_lazy(block_number_bytes),
+            )),
+            |(round, target_hash, target_number, precommits, votes_ancestries)| {
+                GrandpaJustificationLazyRef {
+                    round,
+                    target_hash: TryFrom::try_from(target_hash).unwrap(),
+                    target_number,
+                    precommits,
+                    votes_ancestries,
+                }
+            },
+        ),
+    )
+}
+
 /// `Nom` combinator that parses a list of precommits.
 fn precommits<'a>(
     block_number_bytes: usize,
@@ -404,17 +708,149 @@ fn votes_ancestries<'a>(
                     |(), _| (),
                 )),
                 move |slice| VotesAncestriesIter {
-                    slice,
-                    num: num_elems,
-                    block_number_bytes,
+                    inner: VotesAncestriesIterInner::Undecoded {
+                        slice,
+                        num: num_elems,
+                        block_number_bytes,
+                    },
                 },
             )
         }),
     )
 }
 
+/// `Nom` combinator that parses the number of headers in the votes ancestries, then consumes
+/// the rest of the data without decoding it.
+fn votes_ancestries_lazy<'a>(
+    block_number_bytes: usize,
+) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], VotesAncestriesLazyIter> {
+    nom::error::context(
+        "votes ancestries",
+        nom::combinator::map(
+            nom::sequence::pair(crate::util::nom_scale_compact_usize, nom::combinator::rest),
+            move |(num, slice)| VotesAncestriesLazyIter {
+                slice,
+                num,
+                block_number_bytes,
+            },
+        ),
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn decode_lazy() {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&12u64.to_le_bytes());
+        encoded.extend_from_slice(&[0xaa; 32]);
+        encoded.extend_from_slice(&1000u32.to_le_bytes());
+        // No pre-commit.
+        encoded.push(0);
+
+        let header = crate::header::HeaderRef {
+            parent_hash: &[0; 32],
+            number: 1000,
+            state_root: &[0; 32],
+            extrinsics_root: &[0; 32],
+            digest: crate::header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+
+        encoded.push(2 << 2);
+        encoded.extend_from_slice(&header);
+        encoded.extend_from_slice(&header);
+
+        let justification = super::decode_grandpa_lazy(&encoded, 4).unwrap();
+        assert_eq!(justification.target_number, 1000);
+        let ancestries = justification.votes_ancestries.collect::<Vec<_>>();
+        assert_eq!(ancestries.len(), 2);
+        assert!(ancestries
+            .iter()
+            .all(|h| h.as_ref().unwrap().number == 1000));
+
+        // Unexpected trailing data.
+        encoded.push(0);
+        let justification = super::decode_grandpa_lazy(&encoded, 4).unwrap();
+        let ancestries = justification.votes_ancestries.collect::<Vec<_>>();
+        assert_eq!(ancestries.len(), 3);
+        assert!(matches!(ancestries[2], Err(crate::header::Error::TooLong)));
+    }
+
+    #[test]
+    fn detect_block_number_bytes() {
+        for block_number_bytes in [4, 8] {
+            let mut encoded = Vec::new();
+            encoded.extend_from_slice(&12u64.to_le_bytes());
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u64.to_le_bytes()[..block_number_bytes]);
+            encoded.push(1 << 2);
+            encoded.extend_from_slice(&[0xaa; 32]);
+            encoded.extend_from_slice(&1000u64.to_le_bytes()[..block_number_bytes]);
+            encoded.extend_from_slice(&[0x11; 64 + 32]);
+            // No votes ancestry.
+            encoded.push(0);
+
+            assert_eq!(
+                super::detect_block_number_bytes(&encoded).unwrap(),
+                block_number_bytes
+            );
+            let justification = super::decode_grandpa(&encoded, 0).unwrap();
+            assert_eq!(justification.target_number, 1000);
+        }
+
+        assert!(matches!(
+            super::decode_grandpa(&[0; 20], 0),
+            Err(super::Error::BlockNumberBytesUnknown)
+        ));
+        assert!(matches!(
+            super::decode_partial_grandpa(&[0; 20], 0),
+            Err(super::Error::BlockNumberBytesUnknown)
+        ));
+    }
+
+    #[test]
+    fn from_commit() {
+        let header = crate::header::Header {
+            parent_hash: [0; 32],
+            number: 999,
+            state_root: [0; 32],
+            extrinsics_root: [0; 32],
+            digest: crate::header::DigestRef::empty().into(),
+        };
+
+        let commit = crate::finality::grandpa::commit::decode::CommitMessageRef {
+            round_number: 12,
+            set_id: 3,
+            message: crate::finality::grandpa::commit::decode::CompactCommitRef {
+                target_hash: &[0xaa; 32],
+                target_number: 1000,
+                precommits: vec![
+                    crate::finality::grandpa::commit::decode::UnsignedPrecommitRef {
+                        target_hash: &[0xbb; 32],
+                        target_number: 1001,
+                    },
+                ],
+                auth_data: vec![(&[0xcc; 64], &[0xdd; 32])],
+            },
+        };
+
+        let justification = super::GrandpaJustification::from_commit(&commit, vec![header]);
+        assert_eq!(justification.round, 12);
+        assert_eq!(justification.target_hash, [0xaa; 32]);
+        assert_eq!(justification.target_number, 1000);
+        assert_eq!(justification.precommits.len(), 1);
+        assert_eq!(justification.precommits[0].target_hash, [0xbb; 32]);
+        assert_eq!(justification.precommits[0].target_number, 1001);
+        assert_eq!(justification.precommits[0].signature, [0xcc; 64]);
+        assert_eq!(justification.precommits[0].authority_public_key, [0xdd; 32]);
+
+        let justification_ref = super::GrandpaJustificationRef::from(&justification);
+        let ancestries = justification_ref.votes_ancestries.collect::<Vec<_>>();
+        assert_eq!(ancestries.len(), 1);
+        assert_eq!(ancestries[0].number, 999);
+    }
+
     #[test]
     fn decode() {
         super::decode_grandpa(
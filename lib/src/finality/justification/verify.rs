@@ -15,147 +15,1595 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::finality::justification::decode;
+use crate::{
+    finality::{grandpa, justification::decode},
+    header,
+};
 
-use alloc::vec::Vec;
-use core::{cmp, iter, mem};
+use alloc::{boxed::Box, vec::Vec};
+use core::{cmp, fmt, num::NonZeroUsize};
 use rand_chacha::{
-    rand_core::{RngCore as _, SeedableRng as _},
+    rand_core::{self, CryptoRng, RngCore, SeedableRng as _},
     ChaCha20Rng,
 };
 
+mod tests;
+
 /// Configuration for a justification verification process.
 #[derive(Debug)]
 pub struct Config<'a, I> {
     /// Justification to verify.
     pub justification: decode::GrandpaJustificationRef<'a>,
 
+    /// Number of bytes used for encoding the block number in the justification.
+    ///
+    /// Must not be 0, as the justification has already been decoded. See
+    /// [`Error::InvalidBlockNumberBytes`].
     pub block_number_bytes: usize,
 
     // TODO: document
     pub authorities_set_id: u64,
 
     /// List of authorities that are allowed to emit pre-commits for the block referred to by
-    /// the justification. Must implement `Iterator<Item = &[u8]>`, where each item is
-    /// the public key of an authority.
+    /// the justification. Must implement `Iterator<Item = impl AuthoritiesListEntry>`.
+    ///
+    /// Each item is either the public key of an authority (`&[u8]`), in which case all the
+    /// authorities have the same voting weight, or a tuple of a public key and the voting weight
+    /// of this authority (`(&[u8], u64)`).
+    ///
+    /// Each of these two forms can also be prefixed with the index of the authority within the
+    /// authorities set (`(usize, &[u8])` or `(usize, &[u8], u64)`). If no index is provided, the
+    /// index of an authority is its position within this list. This index is reported in
+    /// [`Error::DuplicateSignature`].
+    ///
+    /// If a public key is found multiple times in the list, only its last entry is taken into
+    /// account, including for the total weight against which the threshold is checked.
     pub authorities_list: I,
 
+    /// Number of items in [`Config::authorities_list`], if known. Used in order to allocate
+    /// the memory necessary to store the authorities upfront. If `None`, the lower bound of
+    /// [`Iterator::size_hint`] is used instead.
+    ///
+    /// Passing a wrong value doesn't lead to an incorrect verification.
+    pub authorities_list_len_hint: Option<usize>,
+
+    /// If `Some`, the list of authorities is this prebuilt set, and [`Config::authorities_list`]
+    /// and [`Config::authorities_list_len_hint`] are ignored.
+    ///
+    /// Building the list of authorities is necessary at the start of each verification. When
+    /// many justifications are verified against the same authorities set, building an
+    /// [`AuthoritiesSet`] once and passing it here avoids repeating this work.
+    pub authorities_set: Option<&'a AuthoritiesSet<'a>>,
+
+    /// Public keys that aren't part of [`Config::authorities_list`] but whose pre-commits must
+    /// be tolerated. Pre-commits made by these keys are skipped: their signature isn't verified,
+    /// they don't count towards the two thirds threshold, and they don't lead to
+    /// [`Error::NotAuthority`].
+    ///
+    /// This is only meant to handle justifications produced around a change of authorities
+    /// set, where pre-commits can come from the previous set. Should normally be empty.
+    /// A key that is also in [`Config::authorities_list`] is treated as an authority.
+    pub allowed_foreign_keys: &'a [[u8; 32]],
+
     /// Seed for a PRNG used for various purposes during the verification.
     ///
+    /// If `None`, a seed is derived from the entire content of the justification, including its
+    /// signatures, and from [`Config::authorities_set_id`]. This is convenient when no source
+    /// of randomness is available, but the author of the justification can compute this seed
+    /// as well. Passing a secret seed, generated randomly, should be preferred when the
+    /// justification comes from an untrusted source.
+    ///
     /// > **Note**: The verification is nonetheless deterministic.
-    pub randomness_seed: [u8; 32],
+    pub randomness_seed: Option<[u8; 32]>,
+
+    /// Seed of the hash maps built during the verification.
+    ///
+    /// If `None`, the seed is drawn from the PRNG seeded with [`Config::randomness_seed`]. If
+    /// `Some`, the same number of bytes is still drawn from the PRNG and discarded, meaning that
+    /// the randomness used to verify the signatures doesn't depend on this field.
+    ///
+    /// This is mostly useful in tests in order to make the iteration order of these hash maps
+    /// reproducible independently of [`Config::randomness_seed`]. Should normally be `None`.
+    pub map_hash_seed: Option<[u8; 16]>,
+
+    /// If `true`, the justification isn't required to contain signatures from at least two
+    /// thirds of the authorities. The signatures that it contains are still verified.
+    ///
+    /// > **Note**: A justification verified with this option set to `true` is not a proof of
+    /// >           finality. This is only useful for inspection purposes, and should normally
+    /// >           be `false`.
+    pub skip_threshold_check: bool,
+
+    /// If `Some`, the verification fails with [`Error::SetIdOutOfRange`] if
+    /// [`Config::authorities_set_id`] isn't within the given inclusive range.
+    ///
+    /// Passing the wrong authorities set id otherwise leads to [`Error::BadSignature`], as the
+    /// set id is part of the signed messages. This check makes it possible to more easily detect
+    /// a justification being paired with the wrong authorities set.
+    pub expected_set_id_range: Option<(u64, u64)>,
+
+    /// If `Some`, must contain the SCALE-encoded header of the block that the justification is
+    /// expected to finalize. The verification fails with [`Error::TargetHashMismatch`] if the
+    /// target of the justification isn't this block.
+    pub target_header: Option<&'a [u8]>,
+
+    /// If `Some`, contains the hash and number of a block that is already trusted, and the
+    /// verification fails with [`Error::NotDescendantOfTrusted`] if the target of the
+    /// justification isn't this block or one of its descendants.
+    ///
+    /// The path between the target and the trusted block is searched for in the votes
+    /// ancestries of the justification, starting from the parent of [`Config::target_header`]
+    /// if it is provided. The headers of this path can be added to the votes ancestries by the
+    /// caller, and aren't considered as unnecessary.
+    pub trusted_ancestor: Option<([u8; 32], u64)>,
+
+    /// If `true`, the verification doesn't stop at the first pre-commit that is found to be
+    /// invalid, and instead fails with [`Error::Multiple`] containing all the problems found in
+    /// the justification.
+    ///
+    /// > **Note**: This is only useful for inspection purposes, and should normally be `false`.
+    /// >           Signatures are verified one by one whenever a group of signatures contains an
+    /// >           invalid one, which makes the verification of invalid justifications slower.
+    pub collect_all_errors: bool,
+
+    /// Function used to calculate the hash of a SCALE-encoded block header, for chains that
+    /// don't use the default hashing scheme. If `None`, the header is hashed using BLAKE2-256,
+    /// like everywhere else in this crate.
+    ///
+    /// Used when checking [`Config::target_header`] and the votes ancestries.
+    /// The signed messages themselves are never hashed.
+    pub header_hash_fn: Option<HeaderHashFn>,
+
+    /// If `true`, the signatures are verified one by one, and the verification succeeds as soon
+    /// as the authorities whose signature has been verified represent at least two thirds of the
+    /// authorities. The remaining pre-commits are then ignored.
+    ///
+    /// This is a tradeoff: verifying signatures one by one forfeits the speedup of batch
+    /// verification, and is thus slower than the default behaviour when all the signatures are
+    /// necessary. This option is only beneficial when justifications are often
+    /// over-provisioned, in other words contain many more signatures than necessary.
+    ///
+    /// Note that an invalid signature found before the threshold is reached still leads to
+    /// [`Error::BadSignature`], while invalid signatures found after the threshold is reached
+    /// are never detected. The ancestry of the blocks voted on by the remaining pre-commits is
+    /// still checked, and so is the absence of unnecessary headers in the votes ancestries.
+    ///
+    /// Ignored if [`Config::skip_threshold_check`] or [`Config::collect_all_errors`] is `true`,
+    /// or if [`Config::verify_signatures`] is `false`.
+    pub stop_at_threshold: bool,
+
+    /// If `true`, all the random bytes used during the verification are recorded and returned
+    /// in [`VerifySuccess::transcript`]. This makes it possible to prove after the fact which
+    /// randomness the verification has used.
+    pub record_transcript: bool,
+
+    /// If `false`, the signatures of the pre-commits aren't verified, and only the structure of
+    /// the justification is checked: the authorities, the absence of duplicates, the number of
+    /// pre-commits, and the ancestry of the blocks voted on. Should normally be `true`.
+    ///
+    /// > **Note**: Verifying the signatures is by far the most expensive part of the
+    /// >           verification. Setting this to `false` makes it possible to cheaply reject
+    /// >           malformed justifications before verifying them again with `true`. A
+    /// >           justification verified with `false` must never be trusted.
+    pub verify_signatures: bool,
 }
 
-/// Verifies that a justification is valid.
-pub fn verify<'a>(config: Config<impl Iterator<Item = &'a [u8]>>) -> Result<(), Error> {
-    let num_precommits = config.justification.precommits.iter().count();
-
-    let mut randomness = ChaCha20Rng::from_seed(config.randomness_seed);
-
-    // Collect the authorities in a set in order to be able to determine with a low complexity
-    // whether a public key is an authority.
-    // For each authority, contains a boolean indicating whether the authority has been seen
-    // before in the list of pre-commits.
-    let mut authorities_list = {
-        let mut list = hashbrown::HashMap::<&[u8], _, _>::with_capacity_and_hasher(
-            0,
-            crate::util::SipHasherBuild::new({
-                let mut seed = [0; 16];
-                randomness.fill_bytes(&mut seed);
-                seed
-            }),
+/// Function that calculates the hash of a SCALE-encoded block header. See
+/// [`Config::header_hash_fn`].
+pub type HeaderHashFn = fn(&[u8]) -> [u8; 32];
+
+/// Entry in [`Config::authorities_list`].
+pub trait AuthoritiesListEntry<'a> {
+    /// Returns the public key of the authority.
+    fn public_key(&self) -> &'a [u8];
+    /// Returns the voting weight of the authority.
+    fn weight(&self) -> u64;
+    /// Returns the index of the authority within the authorities set, or `None` if it is equal
+    /// to the position of the entry within [`Config::authorities_list`].
+    fn index(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<'a> AuthoritiesListEntry<'a> for &'a [u8] {
+    fn public_key(&self) -> &'a [u8] {
+        self
+    }
+
+    fn weight(&self) -> u64 {
+        1
+    }
+}
+
+impl<'a> AuthoritiesListEntry<'a> for (&'a [u8], u64) {
+    fn public_key(&self) -> &'a [u8] {
+        self.0
+    }
+
+    fn weight(&self) -> u64 {
+        self.1
+    }
+}
+
+impl<'a> AuthoritiesListEntry<'a> for (usize, &'a [u8]) {
+    fn public_key(&self) -> &'a [u8] {
+        self.1
+    }
+
+    fn weight(&self) -> u64 {
+        1
+    }
+
+    fn index(&self) -> Option<usize> {
+        Some(self.0)
+    }
+}
+
+impl<'a> AuthoritiesListEntry<'a> for (usize, &'a [u8], u64) {
+    fn public_key(&self) -> &'a [u8] {
+        self.1
+    }
+
+    fn weight(&self) -> u64 {
+        self.2
+    }
+
+    fn index(&self) -> Option<usize> {
+        Some(self.0)
+    }
+}
+
+/// List of authorities built ahead of the verification. See [`Config::authorities_set`].
+///
+/// Must be rebuilt whenever the authorities set changes.
+pub struct AuthoritiesSet<'a> {
+    /// List of authorities. Each entry contains the public key, the index, and the voting weight
+    /// of an authority.
+    authorities: Vec<(&'a [u8], usize, u64)>,
+    /// Position within [`AuthoritiesSet::authorities`] of each public key.
+    positions: hashbrown::HashMap<&'a [u8], usize, crate::util::SipHasherBuild>,
+    /// Sum of the weights of all the authorities.
+    total_weight: u128,
+    /// `true` if all the authorities have a weight of 1.
+    unit_weights: bool,
+}
+
+impl<'a> AuthoritiesSet<'a> {
+    /// Builds a new set from a list of authorities in the same format as
+    /// [`Config::authorities_list`].
+    ///
+    /// The `randomness_seed` is used in order to build a hash map, and doesn't influence the
+    /// outcome of the verifications.
+    pub fn new(
+        authorities_list: impl Iterator<Item = impl AuthoritiesListEntry<'a>>,
+        randomness_seed: [u8; 16],
+    ) -> Self {
+        let mut authorities = Vec::with_capacity(authorities_list.size_hint().0);
+        let mut positions = hashbrown::HashMap::with_capacity_and_hasher(
+            authorities_list.size_hint().0,
+            crate::util::SipHasherBuild::new(randomness_seed),
         );
-        for authority in config.authorities_list {
-            list.insert(authority, false);
+        for (position, authority) in authorities_list.enumerate() {
+            let entry = (
+                authority.public_key(),
+                authority.index().unwrap_or(position),
+                authority.weight(),
+            );
+
+            // If a public key is found multiple times, only its last entry is kept, like when
+            // the list is passed through `Config::authorities_list`.
+            match positions.entry(authority.public_key()) {
+                hashbrown::hash_map::Entry::Occupied(existing) => {
+                    authorities[*existing.get()] = entry;
+                }
+                hashbrown::hash_map::Entry::Vacant(vacant) => {
+                    vacant.insert(authorities.len());
+                    authorities.push(entry);
+                }
+            }
+        }
+
+        // The weights are summed only after the duplicates have been removed, as otherwise the
+        // threshold could be impossible to reach.
+        let unit_weights = authorities.iter().all(|(_, _, weight)| *weight == 1);
+        let total_weight = authorities
+            .iter()
+            .map(|(_, _, weight)| u128::from(*weight))
+            .sum();
+
+        AuthoritiesSet {
+            authorities,
+            positions,
+            total_weight,
+            unit_weights,
+        }
+    }
+
+    /// Returns the number of authorities in the set.
+    pub fn len(&self) -> usize {
+        self.authorities.len()
+    }
+
+    /// Returns `true` if the set doesn't contain any authority.
+    pub fn is_empty(&self) -> bool {
+        self.authorities.is_empty()
+    }
+
+    /// Returns the position within [`AuthoritiesSet::authorities`] of the given public key.
+    fn position(&self, public_key: &[u8]) -> Option<usize> {
+        self.positions.get(public_key).copied()
+    }
+}
+
+impl<'a> fmt::Debug for AuthoritiesSet<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.authorities
+                    .iter()
+                    .map(|(public_key, index, weight)| (index, hex::encode(public_key), weight)),
+            )
+            .finish()
+    }
+}
+
+/// Information about a justification that has been successfully verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifySuccess {
+    /// Number of valid signatures found in the justification.
+    pub num_signatures: usize,
+    /// Number of authorities in [`Config::authorities_list`].
+    ///
+    /// The ratio between [`VerifySuccess::num_signatures`] and this value indicates how much
+    /// of the authorities set has participated in the finalization.
+    pub num_authorities: usize,
+    /// Block that the justification has been verified to finalize.
+    pub target: VerifiedTarget,
+    /// Supermajority GHOST of the pre-commits, in other words the highest block that is voted on,
+    /// directly or through one of its descendants, by more than two thirds of the weight of the
+    /// authorities. Always equal to [`VerifySuccess::target`] or one of its descendants.
+    ///
+    /// If [`Config::stop_at_threshold`] is `true`, only the pre-commits verified before the
+    /// threshold was reached are taken into account. If [`Config::skip_threshold_check`] is
+    /// `true` and no block has such a supermajority, this is equal to [`VerifySuccess::target`].
+    pub ghost: VerifiedTarget,
+    /// Public keys of the authorities of [`Config::authorities_list`] that haven't produced any
+    /// pre-commit, ordered by index within the authorities set.
+    ///
+    /// If [`Config::stop_at_threshold`] is `true`, this also contains the authorities whose
+    /// pre-commit has been ignored because the threshold had already been reached.
+    pub non_signers: Vec<[u8; 32]>,
+    /// Random bytes used during the verification. `None` if [`Config::record_transcript`] was
+    /// `false`.
+    pub transcript: Option<VerificationTranscript>,
+}
+
+/// Randomness used during the verification of a justification. See
+/// [`Config::record_transcript`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationTranscript {
+    /// Seed of the PRNG. Either [`Config::randomness_seed`] or the seed derived from the
+    /// justification.
+    pub seed: [u8; 32],
+    /// All the bytes produced by the PRNG seeded with [`VerificationTranscript::seed`], in the
+    /// order in which they have been used.
+    ///
+    /// When signatures are verified in parallel, each group of signatures uses a PRNG seeded
+    /// with bytes found in this list. The bytes produced by these other PRNGs aren't included.
+    pub random_bytes: Vec<u8>,
+}
+
+/// Block finalized by a justification that has been successfully verified.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VerifiedTarget {
+    /// Hash of the finalized block.
+    pub hash: [u8; 32],
+    /// Height of the finalized block.
+    pub number: u64,
+}
+
+/// Verifies that a justification is valid.
+///
+/// See [`verify_with_stats`] in order to obtain more information about the justification.
+pub fn verify<'a>(
+    config: Config<'a, impl Iterator<Item = impl AuthoritiesListEntry<'a>>>,
+) -> Result<(), Error> {
+    verify_with_stats(config).map(|_| ())
+}
+
+/// Verifies that a justification is valid, and returns information about it.
+pub fn verify_with_stats<'a>(
+    config: Config<'a, impl Iterator<Item = impl AuthoritiesListEntry<'a>>>,
+) -> Result<VerifySuccess, Error> {
+    let mut verification = verify_incremental(config, NonZeroUsize::new(usize::MAX).unwrap());
+
+    loop {
+        match verification {
+            Verify::Finished(result) => return result,
+            Verify::Yield(yielded) => verification = yielded.resume(),
+        }
+    }
+}
+
+/// Decodes the given SCALE-encoded justification, then verifies that it is valid.
+///
+/// This is a convenience function equivalent to calling [`decode::decode_grandpa`] followed
+/// with [`verify_with_stats`].
+///
+/// If `block_number_bytes` is 0, it is detected using [`decode::detect_block_number_bytes`].
+pub fn verify_scale_encoded<'a>(
+    scale_encoded_justification: &'a [u8],
+    block_number_bytes: usize,
+    authorities_set_id: u64,
+    authorities_list: impl Iterator<Item = impl AuthoritiesListEntry<'a>>,
+    randomness_seed: Option<[u8; 32]>,
+) -> Result<VerifySuccess, Error> {
+    // The detected number of bytes is also necessary in order to build the signed messages.
+    let block_number_bytes = match block_number_bytes {
+        0 => {
+            decode::detect_block_number_bytes(scale_encoded_justification).map_err(Error::Decode)?
+        }
+        n => n,
+    };
+
+    let justification = decode::decode_grandpa(scale_encoded_justification, block_number_bytes)
+        .map_err(Error::Decode)?;
+
+    verify_with_stats(Config {
+        justification,
+        block_number_bytes,
+        authorities_set_id,
+        authorities_list,
+        authorities_list_len_hint: None,
+        authorities_set: None,
+        allowed_foreign_keys: &[],
+        randomness_seed,
+        map_hash_seed: None,
+        skip_threshold_check: false,
+        expected_set_id_range: None,
+        target_header: None,
+        trusted_ancestor: None,
+        collect_all_errors: false,
+        header_hash_fn: None,
+        stop_at_threshold: false,
+        record_transcript: false,
+        verify_signatures: true,
+    })
+}
+
+/// Verifies the signature of a single pre-commit.
+///
+/// This performs the same verification as the one applied to each pre-commit of a justification
+/// by [`verify`], except that the signature is verified on its own rather than as part of a batch.
+/// No check is performed concerning whether `public_key` belongs to an authority.
+pub fn verify_single_precommit(
+    public_key: &[u8; 32],
+    signature: &[u8; 64],
+    target_hash: &[u8; 32],
+    target_number: u64,
+    round: u64,
+    set_id: u64,
+    block_number_bytes: usize,
+) -> Result<(), Error> {
+    let public_key =
+        ed25519_zebra::VerificationKey::try_from(*public_key).map_err(|_| Error::BadPublicKey)?;
+
+    let msg = grandpa::signed_message::grandpa_precommit_signed_message(
+        target_hash,
+        target_number,
+        block_number_bytes,
+        round,
+        set_id,
+    );
+
+    public_key
+        .verify(&ed25519_zebra::Signature::from(*signature), &msg)
+        .map_err(|_| Error::BadSignature)
+}
+
+/// Verifies a list of pre-commits that don't necessarily come from a decoded justification,
+/// for example because they have been assembled from a database.
+///
+/// The pre-commits are checked in the same way as the pre-commits of a justification passed to
+/// [`verify`]: each pre-commit must be signed by a different authority, all signatures must be
+/// valid, and the pre-commits must come from more than two thirds of the authorities (or of
+/// their total weight).
+///
+/// Contrary to [`verify`], nothing is known about the target of the pre-commits, and the blocks
+/// that they vote for are not checked in any way.
+pub fn verify_precommits<'a>(
+    precommits: impl Iterator<Item = decode::PrecommitRef<'a>>,
+    round: u64,
+    authorities_set_id: u64,
+    authorities_list: impl Iterator<Item = impl AuthoritiesListEntry<'a>>,
+    block_number_bytes: usize,
+    randomness_seed: [u8; 32],
+) -> Result<(), Error> {
+    let mut verification = start_verification(
+        PrecommitsConfig {
+            precommits: either::Right(precommits.collect::<Vec<_>>().into_iter()),
+            round,
+            // The target is irrelevant, as `votes_ancestries` is `None`.
+            target: VerifiedTarget {
+                hash: [0; 32],
+                number: 0,
+            },
+            target_parent: None,
+            votes_ancestries: None,
+            header_hash_fn: None,
+            trusted_ancestor: None,
+            block_number_bytes,
+            authorities_set_id,
+            authorities_list,
+            authorities_list_len_hint: None,
+            authorities_set: None,
+            allowed_foreign_keys: &[],
+            randomness_seed,
+            map_hash_seed: None,
+            record_transcript: false,
+            skip_threshold_check: false,
+            collect_all_errors: false,
+            stop_at_threshold: false,
+            verify_signatures: true,
+        },
+        NonZeroUsize::new(usize::MAX).unwrap(),
+    );
+
+    loop {
+        match verification {
+            Verify::Finished(result) => return result.map(|_| ()),
+            Verify::Yield(yielded) => verification = yielded.resume(),
+        }
+    }
+}
+
+/// Starts verifying that a justification is valid.
+///
+/// Contrary to [`verify`], the signatures are verified by groups of at most
+/// `signatures_per_batch`, and control is given back to the caller between each group. This
+/// makes it possible to avoid blocking the current thread for a long time when the justification
+/// contains a lot of pre-commits.
+pub fn verify_incremental<'a>(
+    config: Config<'a, impl Iterator<Item = impl AuthoritiesListEntry<'a>>>,
+    signatures_per_batch: NonZeroUsize,
+) -> Verify<'a> {
+    if let Some((min, max)) = config.expected_set_id_range {
+        if config.authorities_set_id < min || config.authorities_set_id > max {
+            return Verify::Finished(Err(Error::SetIdOutOfRange));
+        }
+    }
+
+    // Hash and number of the parent of the target, if known.
+    let mut target_parent = None;
+
+    if let Some(target_header) = config.target_header {
+        match header::decode(target_header, config.block_number_bytes) {
+            Ok(decoded) => {
+                target_parent = Some((*decoded.parent_hash, decoded.number.checked_sub(1)))
+            }
+            Err(err) => return Verify::Finished(Err(Error::InvalidTargetHeader(err))),
+        }
+
+        let target_header_hash = match config.header_hash_fn {
+            Some(hash_fn) => hash_fn(target_header),
+            None => header::hash_from_scale_encoded_header(target_header),
+        };
+
+        if target_header_hash != *config.justification.target_hash {
+            return Verify::Finished(Err(Error::TargetHashMismatch));
         }
-        list
+    }
+
+    let randomness_seed = config.randomness_seed.unwrap_or_else(|| {
+        default_randomness_seed(
+            &config.justification,
+            config.block_number_bytes,
+            config.authorities_set_id,
+        )
+    });
+
+    start_verification(
+        PrecommitsConfig {
+            precommits: either::Left(config.justification.precommits.iter()),
+            round: config.justification.round,
+            target: VerifiedTarget {
+                hash: *config.justification.target_hash,
+                number: config.justification.target_number,
+            },
+            target_parent,
+            votes_ancestries: Some(config.justification.votes_ancestries),
+            header_hash_fn: config.header_hash_fn,
+            trusted_ancestor: config.trusted_ancestor,
+            block_number_bytes: config.block_number_bytes,
+            authorities_set_id: config.authorities_set_id,
+            authorities_list: config.authorities_list,
+            authorities_list_len_hint: config.authorities_list_len_hint,
+            authorities_set: config.authorities_set,
+            allowed_foreign_keys: config.allowed_foreign_keys,
+            randomness_seed,
+            map_hash_seed: config.map_hash_seed,
+            record_transcript: config.record_transcript,
+            skip_threshold_check: config.skip_threshold_check,
+            collect_all_errors: config.collect_all_errors,
+            stop_at_threshold: config.stop_at_threshold,
+            verify_signatures: config.verify_signatures,
+        },
+        signatures_per_batch,
+    )
+}
+
+/// Pre-commits to verify, and how to verify them. Shared by [`verify_incremental`] and
+/// [`verify_precommits`].
+///
+/// See [`Config`] for the documentation of most fields.
+struct PrecommitsConfig<'a, I> {
+    precommits: either::Either<
+        decode::PrecommitsRefIter<'a>,
+        alloc::vec::IntoIter<decode::PrecommitRef<'a>>,
+    >,
+    round: u64,
+    target: VerifiedTarget,
+    /// Hash and number of the parent of the target, if known.
+    target_parent: Option<([u8; 32], Option<u64>)>,
+    /// If `None`, the blocks that the pre-commits vote for aren't checked.
+    votes_ancestries: Option<decode::VotesAncestriesIter<'a>>,
+    header_hash_fn: Option<HeaderHashFn>,
+    trusted_ancestor: Option<([u8; 32], u64)>,
+    block_number_bytes: usize,
+    authorities_set_id: u64,
+    authorities_list: I,
+    authorities_list_len_hint: Option<usize>,
+    authorities_set: Option<&'a AuthoritiesSet<'a>>,
+    allowed_foreign_keys: &'a [[u8; 32]],
+    randomness_seed: [u8; 32],
+    map_hash_seed: Option<[u8; 16]>,
+    record_transcript: bool,
+    skip_threshold_check: bool,
+    collect_all_errors: bool,
+    stop_at_threshold: bool,
+    verify_signatures: bool,
+}
+
+/// Builds the list of authorities, performs the checks that don't require verifying any
+/// signature, then starts verifying the pre-commits.
+fn start_verification<'a>(
+    config: PrecommitsConfig<'a, impl Iterator<Item = impl AuthoritiesListEntry<'a>>>,
+    signatures_per_batch: NonZeroUsize,
+) -> Verify<'a> {
+    // Grandpa rounds start at 1. A justification for round 0 can't have been produced by honest
+    // authorities.
+    if config.round == 0 {
+        return Verify::Finished(Err(Error::InvalidRound));
+    }
+
+    // The number of bytes can't be detected, as the pre-commits have already been decoded.
+    if config.block_number_bytes == 0 {
+        return Verify::Finished(Err(Error::InvalidBlockNumberBytes));
+    }
+
+    let num_precommits = config.precommits.len();
+
+    let mut randomness = TranscriptRng {
+        inner: ChaCha20Rng::from_seed(config.randomness_seed),
+        seed: config.randomness_seed,
+        recorded: if config.record_transcript {
+            Some(Vec::new())
+        } else {
+            None
+        },
+    };
+
+    let (authorities_list, total_weight, unit_weights) = build_authorities_list(
+        config.authorities_set,
+        config.authorities_list,
+        config.authorities_list_len_hint,
+        config.map_hash_seed,
+        &mut randomness,
+    );
+
+    // Public keys whose pre-commits must be skipped. Randomness is only drawn if necessary, in
+    // order to not modify the verification of justifications that don't use this feature.
+    let allowed_foreign_keys = if config.allowed_foreign_keys.is_empty() {
+        None
+    } else {
+        let mut set = hashbrown::HashSet::<&[u8], _>::with_capacity_and_hasher(
+            config.allowed_foreign_keys.len(),
+            crate::util::SipHasherBuild::new(map_hash_seed(&mut randomness, config.map_hash_seed)),
+        );
+        set.extend(
+            config
+                .allowed_foreign_keys
+                .iter()
+                .map(|key| &key[..])
+                .filter(|key| !authorities_list.contains_key(key)),
+        );
+        Some(set)
     };
 
+    // Number of pre-commits that are skipped because of `allowed_foreign_keys`.
+    let num_foreign_precommits = allowed_foreign_keys.as_ref().map_or(0, |foreign_keys| {
+        config
+            .precommits
+            .clone()
+            .filter(|precommit| foreign_keys.contains(&precommit.authority_public_key[..]))
+            .count()
+    });
+
+    let mut votes_ancestries = config.votes_ancestries.map(|votes_ancestries| {
+        grandpa::votes_ancestries::VotesAncestries::new(
+            votes_ancestries.map(|header| {
+                let hash = match config.header_hash_fn {
+                    Some(hash_fn) => hash_fn(&header.scale_encoding_vec(config.block_number_bytes)),
+                    None => header.hash(config.block_number_bytes),
+                };
+                (hash, header)
+            }),
+            map_hash_seed(&mut randomness, config.map_hash_seed),
+        )
+    });
+
+    // Check that the target is the trusted block or one of its descendants. Since the headers
+    // walked through are marked as used, this must be done before the votes are verified.
+    if let (Some((trusted_hash, trusted_number)), Some(votes_ancestries)) =
+        (config.trusted_ancestor, votes_ancestries.as_mut())
+    {
+        let is_trusted =
+            config.target.hash == trusted_hash && config.target.number == trusted_number;
+        let is_descendant = is_trusted
+            || match config.target_parent {
+                Some((parent_hash, Some(parent_number))) => votes_ancestries
+                    .check_descendant(&trusted_hash, trusted_number, &parent_hash, parent_number)
+                    .is_ok(),
+                Some((_, None)) => false,
+                None => votes_ancestries
+                    .check_descendant(
+                        &trusted_hash,
+                        trusted_number,
+                        &config.target.hash,
+                        config.target.number,
+                    )
+                    .is_ok(),
+            };
+
+        if !is_descendant {
+            return Verify::Finished(Err(Error::NotDescendantOfTrusted));
+        }
+    }
+
+    // No justification can possibly be valid if the list of authorities is empty. This is most
+    // likely a misconfiguration.
+    if authorities_list.is_empty() {
+        return Verify::Finished(Err(Error::EmptyAuthoritySet));
+    }
+
     // Check that justification contains a number of signatures equal to at least 2/3rd of the
     // number of authorities.
     // Duplicate signatures are checked below.
     // The logic of the check is `actual >= (expected * 2 / 3) + 1`.
-    if num_precommits < (authorities_list.len() * 2 / 3) + 1 {
-        return Err(Error::NotEnoughSignatures);
+    // If the authorities have different weights, or if all errors must be collected, the check
+    // is only performed once the signatures have been verified.
+    if !config.skip_threshold_check
+        && !config.collect_all_errors
+        && unit_weights
+        && num_precommits - num_foreign_precommits < (authorities_list.len() * 2 / 3) + 1
+    {
+        return Verify::Finished(Err(Error::NotEnoughSignatures));
     }
 
-    // Verifying all the signatures together brings better performances than verifying them one
-    // by one.
-    // Note that batched ed25519 verification has some issues. The code below uses a special
-    // flavour of ed25519 where ambiguities are removed.
-    // See https://docs.rs/ed25519-zebra/2.2.0/ed25519_zebra/batch/index.html and
-    // https://github.com/zcash/zips/blob/master/zip-0215.rst
-    let mut batch = ed25519_zebra::batch::Verifier::new();
+    Verification {
+        precommits: config.precommits,
+        round: config.round,
+        target: config.target,
+        block_number_bytes: config.block_number_bytes,
+        authorities_set_id: config.authorities_set_id,
+        authorities_list,
+        allowed_foreign_keys,
+        num_foreign_precommits,
+        votes_ancestries,
+        votes_weight: 0,
+        num_precommits,
+        skip_threshold_check: config.skip_threshold_check,
+        collect_all_errors: config.collect_all_errors,
+        errors: Vec::new(),
+        stop_at_threshold: config.stop_at_threshold
+            && !config.skip_threshold_check
+            && !config.collect_all_errors
+            && config.verify_signatures,
+        verify_signatures: config.verify_signatures,
+        total_weight,
+        signed_weight: 0,
+        signatures_per_batch,
+        randomness,
+    }
+    .resume()
+}
+
+/// Draws the seed of a hash map from `randomness`. If `overridden` is `Some`, the bytes are
+/// drawn anyway, but the content of `overridden` is returned instead.
+///
+/// See [`Config::map_hash_seed`].
+fn map_hash_seed(randomness: &mut TranscriptRng, overridden: Option<[u8; 16]>) -> [u8; 16] {
+    let mut seed = [0; 16];
+    randomness.fill_bytes(&mut seed);
+    overridden.unwrap_or(seed)
+}
+
+/// Collects the authorities in a set in order to be able to determine with a low complexity
+/// whether a public key is an authority.
+///
+/// Returns the list of authorities, the sum of their weights, and whether all the weights are
+/// equal to 1.
+fn build_authorities_list<'a>(
+    authorities_set: Option<&'a AuthoritiesSet<'a>>,
+    authorities_list: impl Iterator<Item = impl AuthoritiesListEntry<'a>>,
+    authorities_list_len_hint: Option<usize>,
+    map_hash_seed_override: Option<[u8; 16]>,
+    randomness: &mut TranscriptRng,
+) -> (AuthoritiesList<'a>, u128, bool) {
+    // For each authority, contains its index, its weight, and the index of the pre-commit of
+    // this authority, if any has been seen before in the list of pre-commits.
+    // The seed of the hasher is drawn even if an `AuthoritiesSet` is provided, in order for the
+    // rest of the verification to use the same random bytes in both cases.
+    let hasher_seed = map_hash_seed(randomness, map_hash_seed_override);
+
+    match authorities_set {
+        Some(set) => (
+            AuthoritiesList::Shared(set, alloc::vec![None; set.len()]),
+            set.total_weight,
+            set.unit_weights,
+        ),
+        None => {
+            let mut list = hashbrown::HashMap::<&[u8], Authority, _>::with_capacity_and_hasher(
+                authorities_list_len_hint.unwrap_or_else(|| authorities_list.size_hint().0),
+                crate::util::SipHasherBuild::new(hasher_seed),
+            );
+            for (position, authority) in authorities_list.enumerate() {
+                // If a public key is found multiple times, only its last entry is kept.
+                list.insert(
+                    authority.public_key(),
+                    Authority {
+                        index: authority.index().unwrap_or(position),
+                        weight: authority.weight(),
+                        precommit_index: None,
+                    },
+                );
+            }
+
+            // The weights are summed only after the duplicates have been removed, as otherwise
+            // the threshold could be impossible to reach.
+            let unit_weights = list.values().all(|authority| authority.weight == 1);
+            let total_weight = list
+                .values()
+                .map(|authority| u128::from(authority.weight))
+                .sum();
+            (AuthoritiesList::Owned(list), total_weight, unit_weights)
+        }
+    }
+}
+
+/// Derives a seed for the PRNG used during the verification from the content of the
+/// justification. Used when [`Config::randomness_seed`] is `None`.
+///
+/// The SCALE encoding of the entire justification, including the signatures and public keys,
+/// is hashed, followed with the authorities set id. Any modification to the justification thus
+/// leads to a different seed, which prevents its author from choosing the signatures after
+/// having chosen the seed.
+fn default_randomness_seed(
+    justification: &decode::GrandpaJustificationRef,
+    block_number_bytes: usize,
+    authorities_set_id: u64,
+) -> [u8; 32] {
+    let mut hasher = blake2_rfc::blake2b::Blake2b::with_key(32, &[]);
+
+    // Block numbers are encoded over `block_number_bytes` bytes, padded with 0s if necessary.
+    let hash_block_number = |hasher: &mut blake2_rfc::blake2b::Blake2b, number: u64| {
+        let number = number.to_le_bytes();
+        hasher.update(&number[..cmp::min(number.len(), block_number_bytes)]);
+        for _ in number.len()..block_number_bytes {
+            hasher.update(&[0]);
+        }
+    };
+
+    hasher.update(&justification.round.to_le_bytes());
+    hasher.update(justification.target_hash);
+    hash_block_number(&mut hasher, justification.target_number);
+    hasher.update(
+        crate::util::encode_scale_compact_usize(justification.precommits.iter().len()).as_ref(),
+    );
+    for precommit in justification.precommits.iter() {
+        hasher.update(precommit.target_hash);
+        hash_block_number(&mut hasher, precommit.target_number);
+        hasher.update(precommit.signature);
+        hasher.update(precommit.authority_public_key);
+    }
+    hasher.update(
+        crate::util::encode_scale_compact_usize(justification.votes_ancestries.len()).as_ref(),
+    );
+    for header in justification.votes_ancestries.clone() {
+        for chunk in header.scale_encoding(block_number_bytes) {
+            hasher.update(chunk.as_ref());
+        }
+    }
+    hasher.update(&authorities_set_id.to_le_bytes());
+
+    let result = hasher.finalize();
+    debug_assert_eq!(result.as_bytes().len(), 32);
+
+    let mut out = [0; 32];
+    out.copy_from_slice(result.as_bytes());
+    out
+}
+
+/// Justification verification in progress.
+#[must_use]
+pub enum Verify<'a> {
+    /// Verification is finished. Contains an error if the justification is invalid.
+    Finished(Result<VerifySuccess, Error>),
+    /// A group of signatures has been verified successfully. The verification can be continued
+    /// by calling [`Yield::resume`].
+    Yield(Yield<'a>),
+}
+
+/// Verification has been paused in order to give back control to the caller.
+#[must_use]
+pub struct Yield<'a> {
+    inner: Box<Verification<'a>>,
+}
+
+impl<'a> Yield<'a> {
+    /// Number of pre-commits whose signature remains to be verified.
+    pub fn num_remaining_precommits(&self) -> usize {
+        self.inner.precommits.len()
+    }
+
+    /// Resumes the verification process.
+    pub fn resume(self) -> Verify<'a> {
+        self.inner.resume()
+    }
+}
+
+struct Verification<'a> {
+    /// Pre-commits that remain to be verified. Either the pre-commits of a justification, or
+    /// the ones passed to [`verify_precommits`].
+    precommits: either::Either<
+        decode::PrecommitsRefIter<'a>,
+        alloc::vec::IntoIter<decode::PrecommitRef<'a>>,
+    >,
+
+    /// Round of the justification. Part of the signed messages.
+    round: u64,
+
+    /// Target of the justification.
+    target: VerifiedTarget,
+
+    /// See [`Config::block_number_bytes`].
+    block_number_bytes: usize,
+
+    /// See [`Config::authorities_set_id`].
+    authorities_set_id: u64,
+
+    /// List of authorities, indexed by public key.
+    authorities_list: AuthoritiesList<'a>,
+
+    /// See [`Config::allowed_foreign_keys`]. Doesn't contain any authority. `None` if empty.
+    allowed_foreign_keys: Option<hashbrown::HashSet<&'a [u8], crate::util::SipHasherBuild>>,
+
+    /// Total number of pre-commits in the justification made by a key of
+    /// [`Verification::allowed_foreign_keys`].
+    num_foreign_precommits: usize,
+
+    /// Headers of the votes ancestries of the justification. If `None`, the ancestry of the
+    /// blocks voted on isn't checked.
+    votes_ancestries: Option<grandpa::votes_ancestries::VotesAncestries<'a>>,
+
+    /// Sum of the weights of the authorities whose pre-commit has been verified to target
+    /// [`Verification::target`] or one of its descendants. Only updated if
+    /// [`Verification::votes_ancestries`] is `Some`.
+    votes_weight: u128,
+
+    /// Total number of pre-commits in the justification.
+    num_precommits: usize,
+
+    /// See [`Config::skip_threshold_check`].
+    skip_threshold_check: bool,
+
+    /// See [`Config::collect_all_errors`].
+    collect_all_errors: bool,
+
+    /// Errors found so far. Always empty if [`Verification::collect_all_errors`] is `false`.
+    errors: Vec<Error>,
+
+    /// See [`Config::stop_at_threshold`]. Always `false` if the option must be ignored.
+    stop_at_threshold: bool,
+
+    /// See [`Config::verify_signatures`].
+    verify_signatures: bool,
+
+    /// Sum of the weights of all the authorities.
+    total_weight: u128,
+
+    /// Sum of the weights of the authorities whose signature has been verified. Only updated if
+    /// [`Verification::stop_at_threshold`] is `true`.
+    signed_weight: u128,
+
+    /// Maximum number of signatures to verify before yielding.
+    signatures_per_batch: NonZeroUsize,
+
+    /// Randomness generator used during the batch verification.
+    randomness: TranscriptRng,
+}
+
+/// Random number generator used during the verification, that optionally records the bytes
+/// that it produces. See [`Config::record_transcript`].
+struct TranscriptRng {
+    /// Actual generator.
+    inner: ChaCha20Rng,
+    /// Seed that [`TranscriptRng::inner`] has been created with.
+    seed: [u8; 32],
+    /// If `Some`, all the bytes produced so far.
+    recorded: Option<Vec<u8>>,
+}
+
+impl TranscriptRng {
+    /// Turns the recorded bytes, if any, into a [`VerificationTranscript`].
+    fn into_transcript(self) -> Option<VerificationTranscript> {
+        Some(VerificationTranscript {
+            seed: self.seed,
+            random_bytes: self.recorded?,
+        })
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        if let Some(recorded) = &mut self.recorded {
+            recorded.extend_from_slice(bytes);
+        }
+    }
+}
+
+impl RngCore for TranscriptRng {
+    fn next_u32(&mut self) -> u32 {
+        let value = self.inner.next_u32();
+        self.record(&value.to_le_bytes());
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = self.inner.next_u64();
+        self.record(&value.to_le_bytes());
+        value
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest);
+        self.record(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.inner.try_fill_bytes(dest)?;
+        self.record(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for TranscriptRng {}
+
+/// See [`Verification::authorities_list`].
+enum AuthoritiesList<'a> {
+    /// Built from [`Config::authorities_list`].
+    Owned(hashbrown::HashMap<&'a [u8], Authority, crate::util::SipHasherBuild>),
+    /// See [`Config::authorities_set`]. Also contains, for each entry of the set, the index
+    /// within the justification of the pre-commit of this authority, if any has been seen
+    /// before in the list of pre-commits.
+    Shared(&'a AuthoritiesSet<'a>, Vec<Option<usize>>),
+}
+
+impl<'a> AuthoritiesList<'a> {
+    fn len(&self) -> usize {
+        match self {
+            AuthoritiesList::Owned(list) => list.len(),
+            AuthoritiesList::Shared(set, _) => set.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, public_key: &[u8]) -> bool {
+        match self {
+            AuthoritiesList::Owned(list) => list.contains_key(public_key),
+            AuthoritiesList::Shared(set, _) => set.position(public_key).is_some(),
+        }
+    }
+
+    /// Returns the index and the weight of the given authority, and the index of its pre-commit.
+    fn get_mut(&mut self, public_key: &[u8]) -> Option<(usize, u64, &mut Option<usize>)> {
+        match self {
+            AuthoritiesList::Owned(list) => {
+                let authority = list.get_mut(public_key)?;
+                Some((
+                    authority.index,
+                    authority.weight,
+                    &mut authority.precommit_index,
+                ))
+            }
+            AuthoritiesList::Shared(set, precommit_indices) => {
+                let position = set.position(public_key)?;
+                let (_, index, weight) = set.authorities[position];
+                Some((index, weight, &mut precommit_indices[position]))
+            }
+        }
+    }
+
+    /// Returns the public key, index, weight, and index of the pre-commit of each authority.
+    fn iter(&self) -> impl Iterator<Item = (&'a [u8], usize, u64, Option<usize>)> + '_ {
+        match self {
+            AuthoritiesList::Owned(list) => {
+                either::Left(list.iter().map(|(public_key, authority)| {
+                    (
+                        *public_key,
+                        authority.index,
+                        authority.weight,
+                        authority.precommit_index,
+                    )
+                }))
+            }
+            AuthoritiesList::Shared(set, precommit_indices) => {
+                either::Right(set.authorities.iter().zip(precommit_indices).map(
+                    |(&(public_key, index, weight), precommit_index)| {
+                        (public_key, index, weight, *precommit_index)
+                    },
+                ))
+            }
+        }
+    }
+}
+
+/// Entry in [`AuthoritiesList::Owned`].
+struct Authority {
+    /// Index of the authority within the authorities set.
+    index: usize,
+    /// Voting weight of the authority.
+    weight: u64,
+    /// Index within the justification of the pre-commit of this authority, if any has been seen
+    /// before in the list of pre-commits.
+    precommit_index: Option<usize>,
+}
+
+impl<'a> Verification<'a> {
+    /// Returns the public keys of the authorities that haven't produced any pre-commit so far,
+    /// ordered by index. See [`VerifySuccess::non_signers`].
+    fn non_signers(&self) -> Vec<[u8; 32]> {
+        let mut non_signers = self
+            .authorities_list
+            .iter()
+            .filter(|(.., precommit_index)| precommit_index.is_none())
+            // Public keys of an invalid length can't have produced any pre-commit anyway.
+            .filter_map(|(public_key, index, ..)| {
+                Some((index, <[u8; 32]>::try_from(public_key).ok()?))
+            })
+            .collect::<Vec<_>>();
+        non_signers.sort_unstable_by_key(|(index, _)| *index);
+        non_signers
+            .into_iter()
+            .map(|(_, public_key)| public_key)
+            .collect()
+    }
+
+    /// Returns the supermajority GHOST of the pre-commits verified so far. See
+    /// [`VerifySuccess::ghost`].
+    fn ghost(&self) -> VerifiedTarget {
+        match &self.votes_ancestries {
+            Some(ancestries) => {
+                let (hash, number) = ancestries.ghost(
+                    &self.target.hash,
+                    self.target.number,
+                    self.total_weight * 2 / 3,
+                );
+                VerifiedTarget { hash, number }
+            }
+            None => self.target.clone(),
+        }
+    }
 
-    for precommit in config.justification.precommits.iter() {
-        match authorities_list.entry(precommit.authority_public_key) {
-            hashbrown::hash_map::Entry::Occupied(mut entry) => {
-                if entry.insert(true) {
-                    return Err(Error::DuplicateSignature(*precommit.authority_public_key));
+    /// Finishes the verification once the threshold has been reached. See
+    /// [`Config::stop_at_threshold`].
+    fn finish_at_threshold(mut self) -> Verify<'a> {
+        // The signatures of the remaining pre-commits aren't verified, but their ancestry is, in
+        // order for the votes ancestries to be checked in the same way as when all the
+        // signatures are verified. Pre-commits made by foreign keys are skipped, as they would
+        // be otherwise.
+        if let Some(ancestries) = self.votes_ancestries.as_mut() {
+            for precommit in self.precommits.by_ref() {
+                if self
+                    .allowed_foreign_keys
+                    .as_ref()
+                    .is_some_and(|foreign_keys| {
+                        foreign_keys.contains(&precommit.authority_public_key[..])
+                    })
+                {
+                    continue;
+                }
+
+                if let Err(error) = ancestries.check_descendant(
+                    &self.target.hash,
+                    self.target.number,
+                    precommit.target_hash,
+                    precommit.target_number,
+                ) {
+                    return Verify::Finished(Err(Error::BadAncestry(error)));
                 }
             }
-            hashbrown::hash_map::Entry::Vacant(_) => {
-                return Err(Error::NotAuthority(*precommit.authority_public_key))
+
+            let num_unused = ancestries.num_unused();
+            if num_unused != 0 {
+                return Verify::Finished(Err(Error::BadAncestry(
+                    grandpa::votes_ancestries::Error::UnusedHeaders { num_unused },
+                )));
             }
         }
 
-        // TODO: must check signed block ancestry using `votes_ancestries`
+        // Pre-commits made by foreign keys are skipped, and the number of signatures is thus
+        // the number of authorities that have signed before the threshold was reached.
+        let num_signatures = self
+            .authorities_list
+            .iter()
+            .filter(|(.., precommit_index)| precommit_index.is_some())
+            .count();
+        Verify::Finished(Ok(VerifySuccess {
+            num_signatures,
+            num_authorities: self.authorities_list.len(),
+            non_signers: self.non_signers(),
+            ghost: self.ghost(),
+            target: self.target,
+            transcript: self.randomness.into_transcript(),
+        }))
+    }
 
-        let mut msg = Vec::with_capacity(1 + 32 + 4 + 8 + 8);
-        msg.push(1u8); // This `1` indicates which kind of message is being signed.
-        msg.extend_from_slice(&precommit.target_hash[..]);
-        // The message contains the little endian block number. While simple in concept,
-        // in reality it is more complicated because we don't know the number of bytes of
-        // this block number at compile time. We thus copy as many bytes as appropriate and
-        // pad with 0s if necessary.
-        msg.extend_from_slice(
-            &precommit.target_number.to_le_bytes()[..cmp::min(
-                mem::size_of_val(&precommit.target_number),
-                config.block_number_bytes,
-            )],
-        );
-        msg.extend(
-            iter::repeat(0).take(
-                config
-                    .block_number_bytes
-                    .saturating_sub(mem::size_of_val(&precommit.target_number)),
-            ),
-        );
-        msg.extend_from_slice(&u64::to_le_bytes(config.justification.round)[..]);
-        msg.extend_from_slice(&u64::to_le_bytes(config.authorities_set_id)[..]);
-        debug_assert_eq!(msg.len(), msg.capacity());
+    fn resume(mut self) -> Verify<'a> {
+        let mut signatures = Vec::with_capacity(cmp::min(
+            self.precommits.len(),
+            self.signatures_per_batch.get(),
+        ));
+
+        // Index within the justification of the first pre-commit of this batch.
+        let first_precommit_index = self.num_precommits - self.precommits.len();
+
+        // Set to `true` if [`Verification::stop_at_threshold`] is `true` and the threshold has
+        // been reached.
+        let mut threshold_reached = false;
+
+        for (precommit_index, precommit) in (first_precommit_index..).zip(
+            self.precommits
+                .by_ref()
+                .take(self.signatures_per_batch.get()),
+        ) {
+            let authority_weight = match self
+                .authorities_list
+                .get_mut(&precommit.authority_public_key[..])
+            {
+                Some((index, _, Some(first_precommit_index))) => Err(Error::DuplicateSignature {
+                    authority_public_key: *precommit.authority_public_key,
+                    authority_index: index,
+                    first_precommit_index: *first_precommit_index,
+                    second_precommit_index: precommit_index,
+                }),
+                Some((_, weight, authority_precommit_index)) => {
+                    *authority_precommit_index = Some(precommit_index);
+                    Ok(weight)
+                }
+                None if self
+                    .allowed_foreign_keys
+                    .as_ref()
+                    .is_some_and(|foreign_keys| {
+                        foreign_keys.contains(&precommit.authority_public_key[..])
+                    }) =>
+                {
+                    continue;
+                }
+                None => Err(Error::NotAuthority {
+                    authority_public_key: *precommit.authority_public_key,
+                    precommit_index,
+                }),
+            };
+
+            let authority_weight = match authority_weight {
+                Ok(weight) => weight,
+                Err(error) if !self.collect_all_errors => return Verify::Finished(Err(error)),
+                Err(error) => {
+                    // The signature of this pre-commit isn't verified, as it is irrelevant.
+                    self.errors.push(error);
+                    continue;
+                }
+            };
+
+            // A pre-commit can only target the target of the justification or one of its
+            // descendants. Its weight is also added to the blocks it votes for, in order to
+            // later find the supermajority GHOST.
+            if let Some(ancestries) = self.votes_ancestries.as_mut() {
+                if let Err(error) = ancestries.add_vote(
+                    &self.target.hash,
+                    self.target.number,
+                    precommit.target_hash,
+                    precommit.target_number,
+                    authority_weight,
+                ) {
+                    if !self.collect_all_errors {
+                        return Verify::Finished(Err(Error::BadAncestry(error)));
+                    }
+                    self.errors.push(Error::BadAncestry(error));
+                    continue;
+                }
+                self.votes_weight += u128::from(authority_weight);
+            }
+
+            if !self.verify_signatures {
+                continue;
+            }
+
+            // The message is written on the stack in order to avoid a heap allocation, except
+            // in the unlikely situation where it doesn't fit.
+            let mut msg_buffer = [0; grandpa::signed_message::MAX_PRECOMMIT_SIGNED_MESSAGE_LEN];
+            let msg = match grandpa::signed_message::write_grandpa_precommit_signed_message(
+                precommit.target_hash,
+                precommit.target_number,
+                self.block_number_bytes,
+                self.round,
+                self.authorities_set_id,
+                &mut msg_buffer,
+            ) {
+                Some(msg) => either::Left(msg),
+                None => either::Right(grandpa::signed_message::grandpa_precommit_signed_message(
+                    precommit.target_hash,
+                    precommit.target_number,
+                    self.block_number_bytes,
+                    self.round,
+                    self.authorities_set_id,
+                )),
+            };
+
+            let signature = ed25519_zebra::batch::Item::from((
+                ed25519_zebra::VerificationKeyBytes::from(*precommit.authority_public_key),
+                ed25519_zebra::Signature::from(*precommit.signature),
+                &msg,
+            ));
 
-        batch.queue(ed25519_zebra::batch::Item::from((
-            ed25519_zebra::VerificationKeyBytes::from(*precommit.authority_public_key),
-            ed25519_zebra::Signature::from(*precommit.signature),
-            &msg,
-        )));
+            if !self.stop_at_threshold {
+                signatures.push(signature);
+                continue;
+            }
+
+            if signature.verify_single().is_err() {
+                return Verify::Finished(Err(Error::BadSignature));
+            }
+
+            // Equivalent to the threshold check below.
+            self.signed_weight += u128::from(authority_weight);
+            if self.signed_weight > self.total_weight * 2 / 3 {
+                threshold_reached = true;
+                break;
+            }
+        }
+
+        if threshold_reached {
+            return self.finish_at_threshold();
+        }
+
+        // Actual signatures verification performed here.
+        // When collecting all the errors, the signatures are verified again one by one if the
+        // group contains an invalid signature, in order to find out how many are invalid.
+        let signatures_backup = if self.collect_all_errors {
+            Some(signatures.clone())
+        } else {
+            None
+        };
+        if !verify_signatures(signatures, &mut self.randomness) {
+            match signatures_backup {
+                None => return Verify::Finished(Err(Error::BadSignature)),
+                Some(signatures) => {
+                    for signature in signatures {
+                        if signature.verify_single().is_err() {
+                            self.errors.push(Error::BadSignature);
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.precommits.len() != 0 {
+            return Verify::Yield(Yield {
+                inner: Box::new(self),
+            });
+        }
+
+        // All the headers of the votes ancestries must be necessary. Pre-commits that have been
+        // skipped because of another problem might not have used their ancestry, in which case
+        // the check is pointless.
+        let num_unused = self
+            .votes_ancestries
+            .as_ref()
+            .map_or(0, |ancestries| ancestries.num_unused());
+        if num_unused != 0 && self.errors.is_empty() {
+            let error =
+                Error::BadAncestry(grandpa::votes_ancestries::Error::UnusedHeaders { num_unused });
+            if !self.collect_all_errors {
+                return Verify::Finished(Err(error));
+            }
+            self.errors.push(error);
+        }
+
+        // Check that the total weight of the authorities that have signed is at least 2/3rd of
+        // the total weight of the authorities. When all the authorities have the same weight,
+        // this is equivalent to the check performed before the signatures verification.
+        // The sums are performed using `u128` in order to avoid overflows.
+        if !self.skip_threshold_check {
+            let signed_weight = self
+                .authorities_list
+                .iter()
+                .filter(|(.., precommit_index)| precommit_index.is_some())
+                .map(|(_, _, weight, _)| u128::from(weight))
+                .sum::<u128>();
+
+            if signed_weight < (self.total_weight * 2 / 3) + 1 {
+                if !self.collect_all_errors {
+                    return Verify::Finished(Err(Error::NotEnoughSignatures));
+                }
+                self.errors.push(Error::NotEnoughSignatures);
+            } else if self.votes_ancestries.is_some()
+                && self.votes_weight < (self.total_weight * 2 / 3) + 1
+            {
+                // The GrandPa protocol additionally requires the target of the justification to
+                // be the supermajority GHOST or one of its ancestors, in other words more than
+                // two thirds of the weight must vote for the target or one of its descendants.
+                // Contrary to the check above, pre-commits whose ancestry is invalid aren't
+                // counted. Since an invalid ancestry immediately fails the verification unless
+                // all errors are collected, this can only happen in the latter situation.
+                // Similar to Substrate, the target isn't required to be the GHOST itself.
+                self.errors.push(Error::NoSupermajorityGhost);
+            }
+        }
+
+        if !self.errors.is_empty() {
+            return Verify::Finished(Err(Error::Multiple(self.errors)));
+        }
+
+        Verify::Finished(Ok(VerifySuccess {
+            num_signatures: self.num_precommits - self.num_foreign_precommits,
+            num_authorities: self.authorities_list.len(),
+            non_signers: self.non_signers(),
+            ghost: self.ghost(),
+            target: self.target,
+            transcript: self.randomness.into_transcript(),
+        }))
     }
+}
+
+/// Number of signatures verified by each thread when the verification is split between
+/// multiple threads. No multithreading happens if there are fewer signatures than this.
+#[cfg(feature = "std")]
+const PARALLEL_VERIFICATION_CHUNK_SIZE: usize = 64;
+
+/// Verifies the given list of signatures. Returns `false` if at least one signature is invalid.
+fn verify_signatures(
+    signatures: Vec<ed25519_zebra::batch::Item>,
+    randomness: &mut TranscriptRng,
+) -> bool {
+    // Verifying all the signatures together brings better performances than verifying them
+    // one by one.
+    // Note that batched ed25519 verification has some issues. The code below uses a special
+    // flavour of ed25519 where ambiguities are removed.
+    // See https://docs.rs/ed25519-zebra/2.2.0/ed25519_zebra/batch/index.html and
+    // https://github.com/zcash/zips/blob/master/zip-0215.rst
+
+    // If many signatures need to be verified, they are split in chunks that are verified in
+    // parallel.
+    // The chunks have a fixed size, rather than depending on the number of threads, and each
+    // chunk uses a seed derived from `randomness`, in order for the verification to remain
+    // deterministic.
+    #[cfg(feature = "std")]
+    if signatures.len() > PARALLEL_VERIFICATION_CHUNK_SIZE {
+        use rayon::prelude::*;
 
-    // Actual signatures verification performed here.
-    batch
-        .verify(&mut randomness)
-        .map_err(|_| Error::BadSignature)?;
+        let seeds = signatures
+            .chunks(PARALLEL_VERIFICATION_CHUNK_SIZE)
+            .map(|_| {
+                let mut seed = [0; 32];
+                randomness.fill_bytes(&mut seed);
+                seed
+            })
+            .collect::<Vec<_>>();
 
-    // TODO: must check that votes_ancestries doesn't contain any unused entry
-    // TODO: there's also a "ghost" thing?
+        return signatures
+            .par_chunks(PARALLEL_VERIFICATION_CHUNK_SIZE)
+            .zip(seeds.par_iter())
+            .all(|(chunk, seed)| {
+                let mut batch = ed25519_zebra::batch::Verifier::new();
+                for signature in chunk {
+                    batch.queue(signature.clone());
+                }
+                batch.verify(ChaCha20Rng::from_seed(*seed)).is_ok()
+            });
+    }
 
-    Ok(())
+    let mut batch = ed25519_zebra::batch::Verifier::new();
+    for signature in signatures {
+        batch.queue(signature);
+    }
+    batch.verify(randomness).is_ok()
 }
 
 /// Error that can happen while verifying a justification.
 #[derive(Debug, derive_more::Display)]
 pub enum Error {
+    /// Failed to decode the justification.
+    #[display(fmt = "{_0}")]
+    Decode(decode::Error),
     /// One of the public keys is invalid.
     BadPublicKey,
     /// One of the signatures can't be verified.
     BadSignature,
     /// One authority has produced two signatures.
+    ///
+    /// The two pre-commits can be retrieved from the justification using the indices, for
+    /// example in order to report the equivocation.
     #[display(fmt = "One authority has produced two signatures")]
-    DuplicateSignature([u8; 32]),
+    DuplicateSignature {
+        /// Public key of the authority that has produced the two signatures.
+        authority_public_key: [u8; 32],
+        /// Index of the authority within the authorities set. See [`Config::authorities_list`].
+        authority_index: usize,
+        /// Index within the justification of the first pre-commit made by this authority.
+        first_precommit_index: usize,
+        /// Index within the justification of the second pre-commit made by this authority.
+        second_precommit_index: usize,
+    },
     /// One of the public keys isn't in the list of authorities.
     #[display(fmt = "One of the public keys isn't in the list of authorities")]
-    NotAuthority([u8; 32]),
+    NotAuthority {
+        /// Public key that isn't in the list of authorities.
+        authority_public_key: [u8; 32],
+        /// Index within the justification of the pre-commit made by this public key.
+        precommit_index: usize,
+    },
+    /// Justification contains a vote for a block that isn't a descendant of the target block,
+    /// or the votes ancestries are invalid.
+    #[display(fmt = "Invalid ancestry of a block voted on: {_0}")]
+    BadAncestry(grandpa::votes_ancestries::Error),
     /// Justification doesn't contain enough authorities signatures to be valid.
     NotEnoughSignatures,
+    /// [`Config::authorities_list`] is empty.
+    #[display(fmt = "Empty list of authorities")]
+    EmptyAuthoritySet,
+    /// The authorities set id isn't within [`Config::expected_set_id_range`], or, when
+    /// verifying a commit, isn't
+    /// [`DecodedConfig::expected_authorities_set_id`](grandpa::commit::verify::DecodedConfig::expected_authorities_set_id).
+    SetIdOutOfRange,
+    /// [`Config::block_number_bytes`] is 0. Only [`verify_scale_encoded`] can detect the number
+    /// of bytes used to encode block numbers.
+    #[display(fmt = "Number of bytes used to encode block numbers is 0")]
+    InvalidBlockNumberBytes,
+    /// The round number of the justification is 0, while Grandpa rounds start at 1.
+    #[display(fmt = "Invalid round number 0")]
+    InvalidRound,
+    /// Failed to decode [`Config::target_header`].
+    #[display(fmt = "Failed to decode target header: {_0}")]
+    InvalidTargetHeader(header::Error),
+    /// The target of the justification isn't the block whose header is
+    /// [`Config::target_header`].
+    TargetHashMismatch,
+    /// The target of the justification couldn't be proven to be [`Config::trusted_ancestor`] or
+    /// one of its descendants.
+    #[display(fmt = "Target isn't a descendant of the trusted block")]
+    NotDescendantOfTrusted,
+    /// Less than two thirds of the weight of the authorities have voted for the target or one
+    /// of its descendants, in other words the target isn't an ancestor of the supermajority
+    /// GHOST. Only ever returned as part of [`Error::Multiple`], as a vote that isn't for the
+    /// target or one of its descendants otherwise fails the verification with
+    /// [`Error::BadAncestry`].
+    #[display(fmt = "Target isn't the supermajority GHOST or one of its ancestors")]
+    NoSupermajorityGhost,
+    /// Multiple problems have been found in the justification. Only ever returned if
+    /// [`Config::collect_all_errors`] is `true`.
+    #[display(fmt = "{} problems found in the justification", "_0.len()")]
+    Multiple(Vec<Error>),
 }
@@ -0,0 +1,1328 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+#![cfg(test)]
+
+use crate::{
+    finality::{grandpa, justification::decode},
+    header,
+};
+use core::{iter, num::NonZeroUsize};
+
+const ROUND: u64 = 12;
+const SET_ID: u64 = 3;
+const TARGET_HASH: [u8; 32] = [0xaa; 32];
+const TARGET_NUMBER: u64 = 1000;
+
+/// Builds `num_authorities` signing keys, and a justification signed by the first `num_signers`
+/// of them.
+fn build(num_authorities: u8, num_signers: u8) -> (Vec<[u8; 32]>, decode::GrandpaJustification) {
+    build_with_target(num_authorities, num_signers, TARGET_HASH)
+}
+
+/// Same as [`build`], but with a custom target block hash.
+fn build_with_target(
+    num_authorities: u8,
+    num_signers: u8,
+    target_hash: [u8; 32],
+) -> (Vec<[u8; 32]>, decode::GrandpaJustification) {
+    build_custom(num_authorities, num_signers, target_hash, ROUND)
+}
+
+/// Same as [`build`], but with a custom target block hash and round number.
+fn build_custom(
+    num_authorities: u8,
+    num_signers: u8,
+    target_hash: [u8; 32],
+    round: u64,
+) -> (Vec<[u8; 32]>, decode::GrandpaJustification) {
+    let signing_keys = (0..num_authorities)
+        .map(|n| ed25519_zebra::SigningKey::from([n; 32]))
+        .collect::<Vec<_>>();
+
+    let precommits = signing_keys
+        .iter()
+        .take(usize::from(num_signers))
+        .map(|key| {
+            let mut msg = vec![1u8];
+            msg.extend_from_slice(&target_hash);
+            msg.extend_from_slice(&u32::try_from(TARGET_NUMBER).unwrap().to_le_bytes());
+            msg.extend_from_slice(&round.to_le_bytes());
+            msg.extend_from_slice(&SET_ID.to_le_bytes());
+
+            decode::Precommit {
+                target_hash,
+                target_number: TARGET_NUMBER,
+                signature: key.sign(&msg).into(),
+                authority_public_key: ed25519_zebra::VerificationKey::from(key).into(),
+            }
+        })
+        .collect();
+
+    let authorities = signing_keys
+        .iter()
+        .map(|key| ed25519_zebra::VerificationKey::from(key).into())
+        .collect();
+
+    (
+        authorities,
+        decode::GrandpaJustification {
+            round,
+            target_hash,
+            target_number: TARGET_NUMBER,
+            precommits,
+            votes_ancestries: Vec::new(),
+        },
+    )
+}
+
+/// SCALE-encodes the given justification, with 4 bytes block numbers.
+fn encode(justification: &decode::GrandpaJustification) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&justification.round.to_le_bytes());
+    out.extend_from_slice(&justification.target_hash);
+    out.extend_from_slice(
+        &u32::try_from(justification.target_number)
+            .unwrap()
+            .to_le_bytes(),
+    );
+    out.extend_from_slice(
+        crate::util::encode_scale_compact_usize(justification.precommits.len()).as_ref(),
+    );
+    for precommit in &justification.precommits {
+        out.extend_from_slice(&precommit.target_hash);
+        out.extend_from_slice(
+            &u32::try_from(precommit.target_number)
+                .unwrap()
+                .to_le_bytes(),
+        );
+        out.extend_from_slice(&precommit.signature);
+        out.extend_from_slice(&precommit.authority_public_key);
+    }
+    out.extend_from_slice(
+        crate::util::encode_scale_compact_usize(justification.votes_ancestries.len()).as_ref(),
+    );
+    for header in &justification.votes_ancestries {
+        out.extend_from_slice(&header.scale_encoding_vec(4));
+    }
+    out
+}
+
+fn config<'a>(
+    authorities: &'a [[u8; 32]],
+    justification: &'a decode::GrandpaJustification,
+) -> super::Config<'a, impl Iterator<Item = &'a [u8]>> {
+    super::Config {
+        justification: justification.into(),
+        block_number_bytes: 4,
+        authorities_set_id: SET_ID,
+        authorities_list: authorities.iter().map(|a| &a[..]),
+        authorities_list_len_hint: None,
+        authorities_set: None,
+        allowed_foreign_keys: &[],
+        randomness_seed: Some([0; 32]),
+        map_hash_seed: None,
+        skip_threshold_check: false,
+        expected_set_id_range: None,
+        target_header: None,
+        trusted_ancestor: None,
+        collect_all_errors: false,
+        header_hash_fn: None,
+        stop_at_threshold: false,
+        record_transcript: false,
+        verify_signatures: true,
+    }
+}
+
+#[test]
+fn valid_justification() {
+    let (authorities, justification) = build(10, 7);
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.num_signatures, 7);
+    assert_eq!(success.num_authorities, 10);
+    assert_eq!(
+        success.target,
+        super::VerifiedTarget {
+            hash: TARGET_HASH,
+            number: TARGET_NUMBER,
+        }
+    );
+}
+
+#[test]
+fn not_enough_signatures() {
+    let (authorities, justification) = build(10, 6);
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn bad_signature() {
+    let (authorities, mut justification) = build(10, 7);
+    justification.precommits[3].signature[5] ^= 1;
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadSignature)
+    ));
+}
+
+#[test]
+fn incremental_yields() {
+    let (authorities, justification) = build(10, 7);
+
+    let mut verification = super::verify_incremental(
+        config(&authorities, &justification),
+        NonZeroUsize::new(3).unwrap(),
+    );
+
+    let mut num_yields = 0;
+    let success = loop {
+        match verification {
+            super::Verify::Finished(result) => break result.unwrap(),
+            super::Verify::Yield(yielded) => {
+                num_yields += 1;
+                verification = yielded.resume();
+            }
+        }
+    };
+
+    assert_eq!(num_yields, 2);
+    assert_eq!(success.num_signatures, 7);
+}
+
+#[test]
+fn many_signatures() {
+    // Large enough for the verification to be split between multiple threads.
+    let (authorities, justification) = build(200, 150);
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.num_signatures, 150);
+}
+
+#[test]
+fn many_signatures_bad_signature() {
+    let (authorities, mut justification) = build(200, 150);
+    justification.precommits[120].signature[5] ^= 1;
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadSignature)
+    ));
+}
+
+#[test]
+fn duplicate_signature() {
+    let (authorities, mut justification) = build(10, 8);
+    let duplicate = justification.precommits[2].clone();
+    justification.precommits[6] = duplicate;
+    match super::verify(config(&authorities, &justification)) {
+        Err(super::Error::DuplicateSignature {
+            authority_public_key,
+            authority_index: 2,
+            first_precommit_index: 2,
+            second_precommit_index: 6,
+        }) => assert_eq!(authority_public_key, authorities[2]),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn skip_threshold_check() {
+    let (authorities, justification) = build(10, 3);
+    let success = super::verify_with_stats(super::Config {
+        skip_threshold_check: true,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 3);
+    assert_eq!(success.num_authorities, 10);
+}
+
+#[test]
+fn skip_threshold_check_bad_signature() {
+    let (authorities, mut justification) = build(10, 3);
+    justification.precommits[1].signature[5] ^= 1;
+    assert!(matches!(
+        super::verify(super::Config {
+            skip_threshold_check: true,
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::BadSignature)
+    ));
+}
+
+#[test]
+fn scale_encoded() {
+    let (authorities, justification) = build(10, 7);
+    let success = super::verify_scale_encoded(
+        &encode(&justification),
+        4,
+        SET_ID,
+        authorities.iter().map(|a| &a[..]),
+        Some([0; 32]),
+    )
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+}
+
+#[test]
+fn scale_encoded_decode_error() {
+    let (authorities, justification) = build(10, 7);
+    let mut encoded = encode(&justification);
+    encoded.pop();
+    assert!(matches!(
+        super::verify_scale_encoded(
+            &encoded,
+            4,
+            SET_ID,
+            authorities.iter().map(|a| &a[..]),
+            Some([0; 32]),
+        ),
+        Err(super::Error::Decode(_))
+    ));
+}
+
+#[test]
+fn detected_block_number_bytes() {
+    let (authorities, mut justification) = build(10, 7);
+
+    // The pre-commits vote for a child of the target, in order for the votes ancestries to be
+    // hashed as well.
+    let child = header::Header {
+        parent_hash: TARGET_HASH,
+        number: TARGET_NUMBER + 1,
+        extrinsics_root: [0; 32],
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+    let child_hash = child.hash(4);
+    let mut msg = vec![1u8];
+    msg.extend_from_slice(&child_hash);
+    msg.extend_from_slice(&u32::try_from(TARGET_NUMBER + 1).unwrap().to_le_bytes());
+    msg.extend_from_slice(&ROUND.to_le_bytes());
+    msg.extend_from_slice(&SET_ID.to_le_bytes());
+    for (n, precommit) in justification.precommits.iter_mut().enumerate() {
+        *precommit = decode::Precommit {
+            target_hash: child_hash,
+            target_number: TARGET_NUMBER + 1,
+            signature: ed25519_zebra::SigningKey::from([u8::try_from(n).unwrap(); 32])
+                .sign(&msg)
+                .into(),
+            authority_public_key: authorities[n],
+        };
+    }
+    justification.votes_ancestries.push(child);
+
+    let success = super::verify_scale_encoded(
+        &encode(&justification),
+        0,
+        SET_ID,
+        authorities.iter().map(|a| &a[..]),
+        Some([0; 32]),
+    )
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+    assert_eq!(success.ghost.hash, child_hash);
+
+    // The number of bytes can't be detected once the justification has been decoded.
+    assert!(matches!(
+        super::verify(super::Config {
+            block_number_bytes: 0,
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::InvalidBlockNumberBytes)
+    ));
+}
+
+#[test]
+fn synthetic() {
+    for block_number_bytes in [4, 8] {
+        let (justification, authorities) =
+            decode::GrandpaJustification::synthetic(40, ROUND, SET_ID, block_number_bytes);
+        assert_eq!(authorities.len(), 40);
+
+        let success = super::verify_with_stats(super::Config {
+            block_number_bytes,
+            ..config(&authorities, &justification)
+        })
+        .unwrap();
+        assert_eq!(success.num_signatures, 40);
+
+        // The justification is only valid for the given set id.
+        assert!(matches!(
+            super::verify(super::Config {
+                block_number_bytes,
+                authorities_set_id: SET_ID + 1,
+                ..config(&authorities, &justification)
+            }),
+            Err(super::Error::BadSignature)
+        ));
+    }
+}
+
+#[test]
+fn allowed_foreign_keys() {
+    // Pre-commits made by 3 keys that aren't authorities, for example because they belong to
+    // the previous authorities set.
+    let foreign_precommits = |num: u8| {
+        let msg = grandpa::signed_message::grandpa_precommit_signed_message(
+            &TARGET_HASH,
+            TARGET_NUMBER,
+            4,
+            ROUND,
+            SET_ID,
+        );
+        (0..num)
+            .map(|n| {
+                let key = ed25519_zebra::SigningKey::from([200 + n; 32]);
+                decode::Precommit {
+                    target_hash: TARGET_HASH,
+                    target_number: TARGET_NUMBER,
+                    signature: key.sign(&msg).into(),
+                    authority_public_key: ed25519_zebra::VerificationKey::from(&key).into(),
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let foreign_keys = foreign_precommits(3)
+        .iter()
+        .map(|precommit| precommit.authority_public_key)
+        .collect::<Vec<_>>();
+
+    // Mixes the pre-commits of the authorities and of the foreign keys.
+    let mixed = |num_signers: u8| {
+        let (authorities, mut justification) = build(10, num_signers);
+        for (n, precommit) in foreign_precommits(3).into_iter().enumerate() {
+            justification.precommits.insert(n * 2, precommit);
+        }
+        (authorities, justification)
+    };
+
+    let (authorities, justification) = mixed(7);
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::NotAuthority { .. })
+    ));
+
+    let success = super::verify_with_stats(super::Config {
+        allowed_foreign_keys: &foreign_keys,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+
+    let success = super::verify_with_stats(super::Config {
+        allowed_foreign_keys: &foreign_keys,
+        stop_at_threshold: true,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+
+    // The signatures of the foreign keys aren't verified.
+    let (authorities, mut justification) = mixed(7);
+    justification.precommits[0].signature[5] ^= 1;
+    super::verify(super::Config {
+        allowed_foreign_keys: &foreign_keys,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+
+    // The foreign keys don't count towards the threshold.
+    let (authorities, justification) = mixed(6);
+    assert!(matches!(
+        super::verify(super::Config {
+            allowed_foreign_keys: &foreign_keys,
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn round_zero() {
+    // All the signatures are valid, but over round 0.
+    let (authorities, justification) = build_custom(10, 7, TARGET_HASH, 0);
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::InvalidRound)
+    ));
+
+    let (authorities, justification) = build_custom(10, 7, TARGET_HASH, 1);
+    super::verify(config(&authorities, &justification)).unwrap();
+}
+
+#[test]
+fn set_id_out_of_range() {
+    let (authorities, justification) = build(10, 7);
+    assert!(matches!(
+        super::verify(super::Config {
+            expected_set_id_range: Some((SET_ID + 1, SET_ID + 5)),
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::SetIdOutOfRange)
+    ));
+
+    super::verify(super::Config {
+        expected_set_id_range: Some((SET_ID, SET_ID)),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+}
+
+#[test]
+fn default_randomness_seed() {
+    let (authorities, mut justification) = build(10, 7);
+    let success = super::verify_with_stats(super::Config {
+        randomness_seed: None,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+
+    // The seed depends on the entire justification, including the signatures and public keys.
+    let seed = |justification: &decode::GrandpaJustification, set_id| {
+        super::default_randomness_seed(&justification.into(), 4, set_id)
+    };
+    let original = seed(&justification, SET_ID);
+    assert_ne!(seed(&justification, SET_ID + 1), original);
+    justification.precommits[3].signature[10] ^= 1;
+    assert_ne!(seed(&justification, SET_ID), original);
+    justification.precommits[3].signature[10] ^= 1;
+    justification.precommits[3].authority_public_key[10] ^= 1;
+    assert_ne!(seed(&justification, SET_ID), original);
+    justification.precommits[3].authority_public_key[10] ^= 1;
+    assert_eq!(seed(&justification, SET_ID), original);
+}
+
+#[test]
+fn target_header() {
+    let target_header = header::HeaderRef {
+        parent_hash: &[0; 32],
+        number: TARGET_NUMBER,
+        state_root: &[0; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+
+    let (authorities, justification) = build_with_target(
+        10,
+        7,
+        header::hash_from_scale_encoded_header(&target_header),
+    );
+    super::verify(super::Config {
+        target_header: Some(&target_header),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+
+    let (authorities, justification) = build(10, 7);
+    assert!(matches!(
+        super::verify(super::Config {
+            target_header: Some(&target_header),
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::TargetHashMismatch)
+    ));
+}
+
+#[test]
+fn invalid_target_header() {
+    let (authorities, justification) = build(10, 7);
+    assert!(matches!(
+        super::verify(super::Config {
+            target_header: Some(&[1, 2, 3]),
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::InvalidTargetHeader(_))
+    ));
+}
+
+#[test]
+fn single_precommit() {
+    let (_, justification) = build(1, 1);
+    let precommit = &justification.precommits[0];
+
+    assert!(super::verify_single_precommit(
+        &precommit.authority_public_key,
+        &precommit.signature,
+        &precommit.target_hash,
+        precommit.target_number,
+        ROUND,
+        SET_ID,
+        4,
+    )
+    .is_ok());
+
+    assert!(matches!(
+        super::verify_single_precommit(
+            &precommit.authority_public_key,
+            &precommit.signature,
+            &precommit.target_hash,
+            precommit.target_number,
+            ROUND + 1,
+            SET_ID,
+            4,
+        ),
+        Err(super::Error::BadSignature)
+    ));
+}
+
+#[test]
+fn weighted_authorities() {
+    let (authorities, justification) = build(4, 1);
+    let weighted_config = |weights: [u64; 4]| super::Config {
+        justification: (&justification).into(),
+        block_number_bytes: 4,
+        authorities_set_id: SET_ID,
+        authorities_list: authorities.iter().zip(weights).map(|(a, w)| (&a[..], w)),
+        authorities_list_len_hint: None,
+        authorities_set: None,
+        allowed_foreign_keys: &[],
+        randomness_seed: Some([0; 32]),
+        map_hash_seed: None,
+        skip_threshold_check: false,
+        expected_set_id_range: None,
+        target_header: None,
+        trusted_ancestor: None,
+        collect_all_errors: false,
+        header_hash_fn: None,
+        stop_at_threshold: false,
+        record_transcript: false,
+        verify_signatures: true,
+    };
+
+    // The only signer holds more than 2/3rd of the total weight.
+    let success = super::verify_with_stats(weighted_config([u64::MAX, 1, 1, 1])).unwrap();
+    assert_eq!(success.num_signatures, 1);
+
+    // The only signer holds less than 2/3rd of the total weight.
+    assert!(matches!(
+        super::verify(weighted_config([2, 1, 1, 1])),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn collect_all_errors() {
+    let (authorities, mut justification) = build(10, 8);
+    let (other_authorities, other_justification) = build(11, 11);
+    justification.precommits[1].signature[5] ^= 1;
+    justification.precommits[3].signature[5] ^= 1;
+    justification.precommits[5] = justification.precommits[0].clone();
+    justification.precommits[7] = other_justification.precommits[10].clone();
+
+    let mut errors = match super::verify(super::Config {
+        collect_all_errors: true,
+        ..config(&authorities, &justification)
+    }) {
+        Err(super::Error::Multiple(errors)) => errors.into_iter(),
+        _ => panic!(),
+    };
+
+    assert!(matches!(
+        errors.next(),
+        Some(super::Error::DuplicateSignature {
+            first_precommit_index: 0,
+            second_precommit_index: 5,
+            ..
+        })
+    ));
+    assert!(matches!(
+        errors.next(),
+        Some(super::Error::NotAuthority { authority_public_key, precommit_index: 7 })
+            if authority_public_key == other_authorities[10]
+    ));
+    assert!(matches!(errors.next(), Some(super::Error::BadSignature)));
+    assert!(matches!(errors.next(), Some(super::Error::BadSignature)));
+    assert!(matches!(
+        errors.next(),
+        Some(super::Error::NotEnoughSignatures)
+    ));
+    assert!(errors.next().is_none());
+
+    // Without the flag, only the first error is reported.
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::DuplicateSignature { .. })
+    ));
+}
+
+#[test]
+fn indexed_authorities() {
+    let (authorities, mut justification) = build(10, 8);
+    justification.precommits[6] = justification.precommits[2].clone();
+
+    let result = super::verify(super::Config {
+        justification: (&justification).into(),
+        block_number_bytes: 4,
+        authorities_set_id: SET_ID,
+        authorities_list: authorities
+            .iter()
+            .enumerate()
+            .map(|(n, a)| (n + 100, &a[..])),
+        authorities_list_len_hint: None,
+        authorities_set: None,
+        allowed_foreign_keys: &[],
+        randomness_seed: Some([0; 32]),
+        map_hash_seed: None,
+        skip_threshold_check: false,
+        expected_set_id_range: None,
+        target_header: None,
+        trusted_ancestor: None,
+        collect_all_errors: false,
+        header_hash_fn: None,
+        stop_at_threshold: false,
+        record_transcript: false,
+        verify_signatures: true,
+    });
+
+    assert!(matches!(
+        result,
+        Err(super::Error::DuplicateSignature {
+            authority_index: 102,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn empty_authority_set() {
+    let (_, justification) = build(1, 1);
+    assert!(matches!(
+        super::verify(config(&[], &justification)),
+        Err(super::Error::EmptyAuthoritySet)
+    ));
+}
+
+#[test]
+fn custom_header_hash_fn() {
+    let target_header = header::HeaderRef {
+        parent_hash: &[0; 32],
+        number: TARGET_NUMBER,
+        state_root: &[0; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+
+    // The justification targets `TARGET_HASH`, which isn't the BLAKE2 hash of the header.
+    let (authorities, justification) = build(10, 7);
+    super::verify(super::Config {
+        target_header: Some(&target_header),
+        header_hash_fn: Some(|_| TARGET_HASH),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+}
+
+#[test]
+fn stop_at_threshold() {
+    // The signatures after the 7th one are invalid, but aren't verified.
+    let (authorities, mut justification) = build(10, 10);
+    for precommit in &mut justification.precommits[7..] {
+        precommit.signature[5] ^= 1;
+    }
+
+    let success = super::verify_with_stats(super::Config {
+        stop_at_threshold: true,
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 7);
+
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadSignature)
+    ));
+
+    // Invalid signatures found before the threshold is reached are still detected.
+    let (authorities, mut justification) = build(10, 10);
+    justification.precommits[2].signature[5] ^= 1;
+    assert!(matches!(
+        super::verify(super::Config {
+            stop_at_threshold: true,
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::BadSignature)
+    ));
+
+    // Unnecessary headers in the votes ancestries are detected as well.
+    let (authorities, mut justification) = build(10, 10);
+    justification.votes_ancestries.push(header::Header {
+        parent_hash: [0xbb; 32],
+        number: TARGET_NUMBER + 1,
+        extrinsics_root: [0; 32],
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    });
+    for stop_at_threshold in [false, true] {
+        assert!(matches!(
+            super::verify(super::Config {
+                stop_at_threshold,
+                ..config(&authorities, &justification)
+            }),
+            Err(super::Error::BadAncestry(
+                grandpa::votes_ancestries::Error::UnusedHeaders { num_unused: 1 }
+            ))
+        ));
+    }
+
+    // The ancestry of the pre-commits after the threshold is still checked.
+    let (authorities, mut justification) = build(10, 10);
+    justification.precommits[9].target_hash = [0xbb; 32];
+    justification.precommits[9].target_number = TARGET_NUMBER + 1;
+    assert!(matches!(
+        super::verify(super::Config {
+            stop_at_threshold: true,
+            ..config(&authorities, &justification)
+        }),
+        Err(super::Error::BadAncestry(
+            grandpa::votes_ancestries::Error::MissingHeader { .. }
+        ))
+    ));
+}
+
+#[test]
+fn record_transcript() {
+    let (authorities, justification) = build(10, 7);
+
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert!(success.transcript.is_none());
+
+    let verify = || {
+        super::verify_with_stats(super::Config {
+            record_transcript: true,
+            ..config(&authorities, &justification)
+        })
+        .unwrap()
+        .transcript
+        .unwrap()
+    };
+
+    let transcript = verify();
+    assert_eq!(transcript.seed, [0; 32]);
+    // At least the seed of the hasher has been recorded.
+    assert!(transcript.random_bytes.len() > 16);
+    // The verification is reproducible.
+    assert_eq!(transcript, verify());
+}
+
+#[test]
+fn map_hash_seed() {
+    let (authorities, justification) = build(10, 7);
+
+    let verify = |map_hash_seed| {
+        super::verify_with_stats(super::Config {
+            map_hash_seed,
+            record_transcript: true,
+            ..config(&authorities, &justification)
+        })
+        .unwrap()
+    };
+
+    // The seed of the hash maps doesn't influence the randomness used for the rest of the
+    // verification.
+    let reference = verify(None);
+    for map_hash_seed in [[0; 16], [1; 16], [0xff; 16]] {
+        assert_eq!(verify(Some(map_hash_seed)), reference);
+    }
+}
+
+#[test]
+fn wrong_authorities_list_len_hint() {
+    let (authorities, justification) = build(10, 7);
+    for hint in [0, 3, 10, 1000] {
+        super::verify(super::Config {
+            authorities_list_len_hint: Some(hint),
+            ..config(&authorities, &justification)
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+fn votes_ancestries() {
+    let (authorities, mut justification) = build(10, 7);
+
+    // Make one authority vote for a child of the target instead.
+    let child = header::Header {
+        parent_hash: TARGET_HASH,
+        number: TARGET_NUMBER + 1,
+        extrinsics_root: [0; 32],
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+    let child_hash = child.hash(4);
+    let mut msg = vec![1u8];
+    msg.extend_from_slice(&child_hash);
+    msg.extend_from_slice(&u32::try_from(TARGET_NUMBER + 1).unwrap().to_le_bytes());
+    msg.extend_from_slice(&ROUND.to_le_bytes());
+    msg.extend_from_slice(&SET_ID.to_le_bytes());
+    justification.precommits[3] = decode::Precommit {
+        target_hash: child_hash,
+        target_number: TARGET_NUMBER + 1,
+        signature: ed25519_zebra::SigningKey::from([3; 32]).sign(&msg).into(),
+        authority_public_key: authorities[3],
+    };
+
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadAncestry(
+            grandpa::votes_ancestries::Error::MissingHeader { block_hash }
+        )) if block_hash == child_hash
+    ));
+
+    justification.votes_ancestries.push(child.clone());
+    super::verify(config(&authorities, &justification)).unwrap();
+
+    // Headers that aren't necessary aren't accepted.
+    justification.votes_ancestries.push(header::Header {
+        number: TARGET_NUMBER + 2,
+        ..child
+    });
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadAncestry(
+            grandpa::votes_ancestries::Error::UnusedHeaders { num_unused: 1 }
+        ))
+    ));
+}
+
+#[test]
+fn non_signers() {
+    let (authorities, justification) = build(10, 7);
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.non_signers, authorities[7..]);
+
+    let (authorities, justification) = build(7, 7);
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert!(success.non_signers.is_empty());
+}
+
+#[test]
+fn without_signatures_verification() {
+    let no_signatures = |authorities: &[[u8; 32]], justification: &decode::GrandpaJustification| {
+        super::verify_with_stats(super::Config {
+            verify_signatures: false,
+            stop_at_threshold: true,
+            ..config(authorities, justification)
+        })
+    };
+
+    // Invalid signatures go unnoticed.
+    let (authorities, mut justification) = build(10, 7);
+    justification.precommits[3].signature[5] ^= 1;
+    let success = no_signatures(&authorities, &justification).unwrap();
+    assert_eq!(success.num_signatures, 7);
+
+    // The structure of the justification is still checked.
+    let (authorities, justification) = build(10, 6);
+    assert!(matches!(
+        no_signatures(&authorities, &justification),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+
+    let (authorities, mut justification) = build(10, 8);
+    justification.precommits[6] = justification.precommits[2].clone();
+    assert!(matches!(
+        no_signatures(&authorities, &justification),
+        Err(super::Error::DuplicateSignature { .. })
+    ));
+}
+
+#[test]
+fn trusted_ancestor() {
+    // Chain made of the trusted block, an intermediary block, and the target.
+    let trusted = ([0x77; 32], TARGET_NUMBER - 2);
+    let intermediary = header::Header {
+        parent_hash: trusted.0,
+        number: TARGET_NUMBER - 1,
+        extrinsics_root: [0; 32],
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+    let target_header = header::HeaderRef {
+        parent_hash: &intermediary.hash(4),
+        number: TARGET_NUMBER,
+        state_root: &[0; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let target_hash = header::hash_from_scale_encoded_header(&target_header);
+
+    let (authorities, mut justification) = build_with_target(10, 7, target_hash);
+    justification.votes_ancestries.push(intermediary);
+
+    let verify = |justification: &decode::GrandpaJustification, trusted_ancestor| {
+        super::verify(super::Config {
+            target_header: Some(&target_header),
+            trusted_ancestor,
+            ..config(&authorities, justification)
+        })
+    };
+
+    verify(&justification, Some(trusted)).unwrap();
+    assert!(matches!(
+        verify(&justification, Some(([0x78; 32], TARGET_NUMBER - 2))),
+        Err(super::Error::NotDescendantOfTrusted)
+    ));
+
+    // The intermediary header is only necessary in order to reach the trusted block.
+    for trusted_ancestor in [None, Some((target_hash, TARGET_NUMBER))] {
+        assert!(matches!(
+            verify(&justification, trusted_ancestor),
+            Err(super::Error::BadAncestry(
+                grandpa::votes_ancestries::Error::UnusedHeaders { num_unused: 1 }
+            ))
+        ));
+    }
+
+    justification.votes_ancestries.clear();
+    verify(&justification, Some((target_hash, TARGET_NUMBER))).unwrap();
+    assert!(matches!(
+        verify(&justification, Some(trusted)),
+        Err(super::Error::NotDescendantOfTrusted)
+    ));
+}
+
+#[test]
+fn authorities_set() {
+    let (authorities, justification) = build(10, 7);
+    let set = super::AuthoritiesSet::new(authorities.iter().map(|a| &a[..]), [0; 16]);
+    assert_eq!(set.len(), 10);
+
+    let with_set = |justification: &decode::GrandpaJustification| {
+        super::verify_with_stats(super::Config {
+            authorities_set: Some(&set),
+            record_transcript: true,
+            ..config(&authorities, justification)
+        })
+    };
+    let without_set = |justification: &decode::GrandpaJustification| {
+        super::verify_with_stats(super::Config {
+            record_transcript: true,
+            ..config(&authorities, justification)
+        })
+    };
+
+    // The outcome, including the randomness used, is the same as without the set.
+    assert_eq!(
+        with_set(&justification).unwrap(),
+        without_set(&justification).unwrap()
+    );
+
+    let (_, mut duplicate) = build(10, 7);
+    duplicate.precommits[6] = duplicate.precommits[2].clone();
+    match with_set(&duplicate) {
+        Err(super::Error::DuplicateSignature {
+            authority_index: 2,
+            first_precommit_index: 2,
+            second_precommit_index: 6,
+            ..
+        }) => {}
+        _ => panic!(),
+    }
+
+    let (_, foreign) = build(11, 11);
+    assert!(matches!(
+        with_set(&foreign),
+        Err(super::Error::NotAuthority { .. })
+    ));
+
+    // The same set can be used for multiple verifications.
+    assert_eq!(with_set(&justification).unwrap().num_signatures, 7);
+}
+
+#[test]
+fn authorities_set_weights_and_indices() {
+    let (authorities, justification) = build(4, 1);
+
+    // When a public key is duplicated, only its last entry is kept.
+    let set = super::AuthoritiesSet::new(
+        [(7, &authorities[0][..], 1), (8, &authorities[0][..], 100)]
+            .into_iter()
+            .chain((1..).zip(&authorities[1..]).map(|(n, a)| (n, &a[..], 1))),
+        [0; 16],
+    );
+    assert_eq!(set.len(), 4);
+
+    // `authorities_list` is ignored in favour of the set. With unit weights, a single
+    // signature wouldn't be enough.
+    let success = super::verify_with_stats(super::Config {
+        authorities_set: Some(&set),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+    assert_eq!(success.num_signatures, 1);
+    assert_eq!(success.num_authorities, 4);
+    assert_eq!(success.non_signers, authorities[1..].to_vec());
+}
+
+#[test]
+fn duplicate_authorities() {
+    let (authorities, justification) = build(10, 7);
+
+    // The last authority is repeated several times. Repeated entries must not count towards
+    // the total weight, otherwise 7 signatures out of 10 distinct authorities wouldn't be
+    // enough.
+    let repeated = authorities
+        .iter()
+        .chain(iter::repeat_n(&authorities[9], 5))
+        .collect::<Vec<_>>();
+
+    let encoded = encode(&justification);
+    let success = super::verify_scale_encoded(
+        &encoded,
+        4,
+        SET_ID,
+        repeated.iter().map(|a| &a[..]),
+        Some([0; 32]),
+    )
+    .unwrap();
+    assert_eq!(success.num_authorities, 10);
+
+    let set = super::AuthoritiesSet::new(repeated.iter().map(|a| &a[..]), [0; 16]);
+    assert_eq!(set.len(), 10);
+    super::verify(super::Config {
+        authorities_set: Some(&set),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+
+    // Same with weights. Only the weight of the last entry of a public key is taken into
+    // account.
+    let weighted = || {
+        authorities
+            .iter()
+            .map(|a| (&a[..], 10))
+            .chain(iter::repeat_n((&authorities[9][..], 1000), 2))
+            .chain(iter::once((&authorities[9][..], 10)))
+    };
+    super::verify_scale_encoded(&encoded, 4, SET_ID, weighted(), Some([0; 32])).unwrap();
+    let set = super::AuthoritiesSet::new(weighted(), [0; 16]);
+    super::verify(super::Config {
+        authorities_set: Some(&set),
+        ..config(&authorities, &justification)
+    })
+    .unwrap();
+}
+
+#[test]
+fn verify_precommits() {
+    let (authorities, justification) = build(10, 7);
+    let (_, other_target) = build_with_target(10, 7, [0xbb; 32]);
+
+    let verify = |precommits: &[decode::Precommit], round: u64| {
+        super::verify_precommits(
+            precommits.iter().map(Into::into),
+            round,
+            SET_ID,
+            authorities.iter().map(|a| &a[..]),
+            4,
+            [0; 32],
+        )
+    };
+
+    verify(&justification.precommits, ROUND).unwrap();
+
+    // The blocks voted on aren't checked.
+    let mixed = justification.precommits[..4]
+        .iter()
+        .chain(&other_target.precommits[4..])
+        .cloned()
+        .collect::<Vec<_>>();
+    verify(&mixed, ROUND).unwrap();
+
+    assert!(matches!(
+        verify(&justification.precommits[..6], ROUND),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+    assert!(matches!(
+        verify(&justification.precommits, ROUND + 1),
+        Err(super::Error::BadSignature)
+    ));
+    assert!(matches!(
+        verify(&justification.precommits, 0),
+        Err(super::Error::InvalidRound)
+    ));
+
+    let mut duplicate = justification.precommits.clone();
+    duplicate[6] = duplicate[2].clone();
+    assert!(matches!(
+        verify(&duplicate, ROUND),
+        Err(super::Error::DuplicateSignature {
+            authority_index: 2,
+            ..
+        })
+    ));
+
+    let (_, foreign) = build(11, 11);
+    assert!(matches!(
+        verify(&foreign.precommits, ROUND),
+        Err(super::Error::NotAuthority { .. })
+    ));
+}
+
+#[test]
+fn verification_items() {
+    let (authorities, mut justification) = build(10, 7);
+    justification.precommits[3].signature[0] ^= 1;
+
+    let justification_ref = decode::GrandpaJustificationRef::from(&justification);
+    let items = justification_ref
+        .verification_items(SET_ID, 4)
+        .collect::<Vec<_>>();
+    assert_eq!(items.len(), 7);
+
+    for (index, (public_key, signature, message)) in items.into_iter().enumerate() {
+        assert_eq!(public_key, &authorities[index]);
+        let valid = ed25519_zebra::VerificationKey::try_from(*public_key)
+            .unwrap()
+            .verify(&ed25519_zebra::Signature::from(*signature), &message)
+            .is_ok();
+        assert_eq!(valid, index != 3);
+    }
+}
+
+#[test]
+fn ghost_descendant_of_target() {
+    let (authorities, mut justification) = build(10, 7);
+
+    // All the authorities vote for a child of the target. The target of the justification is
+    // then an ancestor of the supermajority GHOST rather than the GHOST itself, which is valid.
+    let child = header::Header {
+        parent_hash: TARGET_HASH,
+        number: TARGET_NUMBER + 1,
+        extrinsics_root: [0; 32],
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+    let child_hash = child.hash(4);
+    let mut msg = vec![1u8];
+    msg.extend_from_slice(&child_hash);
+    msg.extend_from_slice(&u32::try_from(TARGET_NUMBER + 1).unwrap().to_le_bytes());
+    msg.extend_from_slice(&ROUND.to_le_bytes());
+    msg.extend_from_slice(&SET_ID.to_le_bytes());
+    for (n, precommit) in justification.precommits.iter_mut().enumerate() {
+        *precommit = decode::Precommit {
+            target_hash: child_hash,
+            target_number: TARGET_NUMBER + 1,
+            signature: ed25519_zebra::SigningKey::from([u8::try_from(n).unwrap(); 32])
+                .sign(&msg)
+                .into(),
+            authority_public_key: authorities[n],
+        };
+    }
+    justification.votes_ancestries.push(child);
+
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.target.hash, TARGET_HASH);
+    assert_eq!(success.ghost.hash, child_hash);
+    assert_eq!(success.ghost.number, TARGET_NUMBER + 1);
+
+    // Without enough votes, no block has a supermajority.
+    justification.precommits.truncate(6);
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::NotEnoughSignatures)
+    ));
+}
+
+#[test]
+fn ghost_forks() {
+    let (authorities, mut justification) = build(10, 10);
+
+    let block = |parent_hash, number, extrinsics_root| header::Header {
+        parent_hash,
+        number,
+        extrinsics_root,
+        state_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+    let vote = |n: usize, target_hash: [u8; 32], target_number: u64| {
+        let mut msg = vec![1u8];
+        msg.extend_from_slice(&target_hash);
+        msg.extend_from_slice(&u32::try_from(target_number).unwrap().to_le_bytes());
+        msg.extend_from_slice(&ROUND.to_le_bytes());
+        msg.extend_from_slice(&SET_ID.to_le_bytes());
+        decode::Precommit {
+            target_hash,
+            target_number,
+            signature: ed25519_zebra::SigningKey::from([u8::try_from(n).unwrap(); 32])
+                .sign(&msg)
+                .into(),
+            authority_public_key: authorities[n],
+        }
+    };
+
+    // Two children of the target.
+    let child_a = block(TARGET_HASH, TARGET_NUMBER + 1, [1; 32]);
+    let child_b = block(TARGET_HASH, TARGET_NUMBER + 1, [2; 32]);
+    let (child_a_hash, child_b_hash) = (child_a.hash(4), child_b.hash(4));
+
+    // The votes are split between the two children, neither of which has a supermajority. The
+    // target is then the GHOST.
+    for n in 0..10 {
+        let hash = if n < 4 { child_a_hash } else { child_b_hash };
+        justification.precommits[n] = vote(n, hash, TARGET_NUMBER + 1);
+    }
+    justification.votes_ancestries = vec![child_a.clone(), child_b.clone()];
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.ghost.hash, TARGET_HASH);
+    assert_eq!(success.ghost.number, TARGET_NUMBER);
+
+    // One child has a supermajority.
+    for n in 4..7 {
+        justification.precommits[n] = vote(n, child_a_hash, TARGET_NUMBER + 1);
+    }
+    let success = super::verify_with_stats(config(&authorities, &justification)).unwrap();
+    assert_eq!(success.ghost.hash, child_a_hash);
+
+    // Part of the votes are for a sibling of the target. All the authorities have signed, but
+    // only 6 out of 10 have voted for the target or one of its descendants.
+    let sibling_hash = block([0xbb; 32], TARGET_NUMBER, [3; 32]).hash(4);
+    for n in 0..4 {
+        justification.precommits[n] = vote(n, sibling_hash, TARGET_NUMBER);
+    }
+    for n in 4..10 {
+        justification.precommits[n] = vote(n, child_b_hash, TARGET_NUMBER + 1);
+    }
+    justification.votes_ancestries = vec![child_b];
+
+    assert!(matches!(
+        super::verify(config(&authorities, &justification)),
+        Err(super::Error::BadAncestry(
+            grandpa::votes_ancestries::Error::NotDescendant
+        ))
+    ));
+
+    let errors = match super::verify(super::Config {
+        collect_all_errors: true,
+        ..config(&authorities, &justification)
+    }) {
+        Err(super::Error::Multiple(errors)) => errors,
+        _ => panic!(),
+    };
+    assert_eq!(errors.len(), 5);
+    assert!(errors[..4]
+        .iter()
+        .all(|error| matches!(error, super::Error::BadAncestry(_))));
+    assert!(matches!(errors[4], super::Error::NoSupermajorityGhost));
+}
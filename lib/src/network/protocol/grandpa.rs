@@ -20,10 +20,13 @@
 use crate::finality::{grandpa::commit::decode, justification::decode::PrecommitRef};
 
 use alloc::vec::Vec;
-use core::{cmp, iter, mem};
+use core::iter;
 use nom::Finish as _;
 
-pub use crate::finality::grandpa::commit::decode::{CommitMessageRef, UnsignedPrecommitRef};
+pub use crate::finality::grandpa::{
+    commit::decode::{CommitMessageRef, UnsignedPrecommitRef},
+    neighbor_packet::NeighborPacket,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GrandpaNotificationRef<'a> {
@@ -77,42 +80,6 @@ pub struct PrimaryProposeRef<'a> {
     pub target_number: u64,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct NeighborPacket {
-    pub round_number: u64,
-    pub set_id: u64,
-    pub commit_finalized_height: u64,
-}
-
-impl NeighborPacket {
-    /// Returns an iterator to list of buffers which, when concatenated, produces the SCALE
-    /// encoding of that object.
-    pub fn scale_encoding(
-        &self,
-        block_number_bytes: usize,
-    ) -> impl Iterator<Item = impl AsRef<[u8]> + Clone> + Clone {
-        let mut commit_finalized_height = Vec::with_capacity(cmp::max(
-            block_number_bytes,
-            mem::size_of_val(&self.commit_finalized_height),
-        ));
-        commit_finalized_height.extend(self.commit_finalized_height.to_le_bytes());
-        // TODO: unclear what to do if the block number doesn't fit in `block_number_bytes`
-        debug_assert!(!commit_finalized_height
-            .iter()
-            .skip(block_number_bytes)
-            .any(|b| *b != 0));
-        commit_finalized_height.resize(block_number_bytes, 0);
-
-        [
-            either::Right(either::Left([1u8])),
-            either::Left(self.round_number.to_le_bytes()),
-            either::Left(self.set_id.to_le_bytes()),
-            either::Right(either::Right(commit_finalized_height)),
-        ]
-        .into_iter()
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CatchUpRequest {
     pub round_number: u64,
@@ -328,21 +295,7 @@ fn neighbor_packet<'a>(
 ) -> impl FnMut(&'a [u8]) -> nom::IResult<&[u8], NeighborPacket> {
     nom::error::context(
         "neighbor_packet",
-        nom::combinator::map(
-            nom::sequence::preceded(
-                nom::bytes::streaming::tag(&[1]),
-                nom::sequence::tuple((
-                    nom::number::streaming::le_u64,
-                    nom::number::streaming::le_u64,
-                    crate::util::nom_varsize_number_decode_u64(block_number_bytes),
-                )),
-            ),
-            |(round_number, set_id, commit_finalized_height)| NeighborPacket {
-                round_number,
-                set_id,
-                commit_finalized_height,
-            },
-        ),
+        crate::finality::grandpa::neighbor_packet::neighbor_packet(block_number_bytes),
     )
 }
 
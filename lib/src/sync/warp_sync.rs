@@ -1490,8 +1490,21 @@ impl<TSrc, TRq> VerifyWarpSyncFragment<TSrc, TRq> {
             authorities_list: finalized_triggered_authorities
                 .iter()
                 .map(|a| &a.public_key[..]),
+            authorities_list_len_hint: Some(finalized_triggered_authorities.len()),
+            authorities_set: None,
+            allowed_foreign_keys: &[],
             authorities_set_id: *after_finalized_block_authorities_set_id,
-            randomness_seed,
+            randomness_seed: Some(randomness_seed),
+            map_hash_seed: None,
+            skip_threshold_check: false,
+            expected_set_id_range: None,
+            target_header: None,
+            trusted_ancestor: None,
+            collect_all_errors: false,
+            header_hash_fn: None,
+            stop_at_threshold: false,
+            record_transcript: false,
+            verify_signatures: true,
         }) {
             if let Some(SourceId(source_id)) = fragments_to_verify.downloaded_source {
                 self.inner.sources[source_id].finalized_block_height = Err(());
@@ -167,7 +167,7 @@ fn longevity_works_non_finalized() {
         &[1; 32],
         Ok(validate::ValidTransaction {
             longevity: NonZeroU64::new(2).unwrap(),
-            priority: 1,
+            priority: validate::Priority(1),
             propagate: true,
             provides: Vec::new(),
             requires: Vec::new(),
@@ -227,7 +227,7 @@ fn longevity_works_finalized() {
         &[1; 32],
         Ok(validate::ValidTransaction {
             longevity: NonZeroU64::new(2).unwrap(),
-            priority: 1,
+            priority: validate::Priority(1),
             propagate: true,
             provides: Vec::new(),
             requires: Vec::new(),
@@ -280,7 +280,7 @@ fn longevity_works_finalized_base() {
         &[0; 32],
         Ok(validate::ValidTransaction {
             longevity: NonZeroU64::new(2).unwrap(),
-            priority: 1,
+            priority: validate::Priority(1),
             propagate: true,
             provides: Vec::new(),
             requires: Vec::new(),
@@ -93,7 +93,7 @@ use hashbrown::HashSet;
 
 mod tests;
 
-pub use super::validate::ValidTransaction;
+pub use super::validate::{Priority, ValidTransaction};
 
 /// Identifier of a transaction stored within the [`Pool`].
 ///
@@ -148,7 +148,7 @@ pub struct Pool<TTx> {
 
     /// Validated transaction ids (i.e. indices within [`Pool::transactions`]) that are includable
     /// in the chain, indexed by the priority value provided by the validation.
-    includable: BTreeSet<(u64, TransactionId)>,
+    includable: BTreeSet<(Priority, TransactionId)>,
 
     /// Validated transaction ids (i.e. indices within [`Pool::transactions`]) indexed by the
     /// block height at which their validation expires.
@@ -19,7 +19,7 @@
 
 use core::num::NonZeroU64;
 
-use super::{Config, Pool, ValidTransaction};
+use super::{Config, Pool, Priority, ValidTransaction};
 
 #[test]
 fn basic_includable() {
@@ -39,7 +39,7 @@ fn basic_includable() {
         1,
         ValidTransaction {
             longevity: NonZeroU64::new(16).unwrap(),
-            priority: 0,
+            priority: Priority(0),
             propagate: true,
             provides: Vec::new(),
             requires: Vec::new(),
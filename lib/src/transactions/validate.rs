@@ -22,24 +22,41 @@ use crate::{
     header, util,
 };
 
-use alloc::{borrow::ToOwned as _, vec::Vec};
-use core::{iter, num::NonZeroU64};
+use alloc::{
+    borrow::ToOwned as _,
+    string::{String, ToString as _},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{cmp, iter, mem, num::NonZeroU64};
 
 pub use runtime_host::{Nibble, TrieEntryVersion};
 
 mod tests;
 
 /// Configuration for a transaction validation process.
-pub struct Config<'a, TTx> {
+///
+/// The `TRuntime` parameter is the way the runtime is held. It is a [`host::HostVmPrototype`]
+/// when using [`validate_transaction`], and a `&host::HostVmPrototype` when using
+/// [`validate_transaction_borrowed`].
+pub struct Config<'a, TTx, TRuntime = host::HostVmPrototype> {
     /// Runtime used to get the validate the transaction. Must be built using the Wasm code found
     /// at the `:code` key of the block storage.
-    pub runtime: host::HostVmPrototype,
+    pub runtime: TRuntime,
 
     /// Header of the block to verify the transaction against, in SCALE encoding.
     /// The runtime of this block must be the one in [`Config::runtime`].
     pub scale_encoded_header: &'a [u8],
 
     /// Number of bytes used to encode the block number in the header.
+    ///
+    /// Must be between 1 and 8 included, otherwise the validation fails with
+    /// [`Error::InvalidBlockNumberBytes`].
+    ///
+    /// If 0, the number of bytes is detected by finding a value with which
+    /// [`Config::scale_encoded_header`] can be decoded, and the validation fails with
+    /// [`Error::BlockNumberBytesUnknown`] if there is none. This detection is best-effort, and
+    /// passing the actual value should be preferred whenever it is known.
     pub block_number_bytes: usize,
 
     /// SCALE-encoded transaction.
@@ -58,6 +75,200 @@ pub struct Config<'a, TTx> {
     /// >           "off", `1` for "error", `2` for "warn", `3` for "info", `4` for "debug",
     /// >           and `5` for "trace".
     pub max_log_level: u32,
+
+    /// If `true`, then [`Query::Finished::storage_changes`] will contain the changes to the
+    /// storage performed by the runtime during the validation, and the changes to the trie
+    /// are calculated. Passing `false` requires fewer calculations and fewer storage accesses.
+    pub calculate_trie_changes: bool,
+
+    /// If `Some`, the validation fails with [`Error::ExecutionLimitExceeded`] if the execution is
+    /// interrupted more than this number of times.
+    ///
+    /// The execution of the runtime is interrupted every time the runtime needs some information
+    /// that isn't immediately available, such as a storage value, or calls a host function that
+    /// performs an expensive operation, such as a signature verification. The number of such
+    /// interruptions is used as a proxy for the execution time.
+    ///
+    /// > **Note**: The amount of computation performed between two interruptions entirely
+    /// >           depends on the runtime. This limit is consequently only a rough protection
+    /// >           against transactions whose validation takes a long time.
+    pub max_wasm_steps: Option<NonZeroU64>,
+
+    /// If `Some`, contains the changes to the storage performed by a call to
+    /// `Core_initialize_block` that the caller has already performed on top of the block whose
+    /// header is [`Config::scale_encoded_header`].
+    ///
+    /// Runtimes that support version 2 of the transaction validation API require calling
+    /// `Core_initialize_block` before validating the transaction. If this field is `Some`, this
+    /// call is skipped and the validation is performed on top of these changes instead. This is
+    /// for example useful when authoring a block, as `Core_initialize_block` has already been
+    /// called in that situation.
+    ///
+    /// This field is ignored for runtimes that support version 3 of the transaction validation
+    /// API, as `Core_initialize_block` is never called for them.
+    pub pre_initialized_changes: Option<storage_diff::TrieDiff>,
+
+    /// If `Some`, the cache is consulted whenever the runtime reads a storage value, and
+    /// [`Query::StorageGet`] is only returned if the value isn't in the cache.
+    ///
+    /// Since the storage is identical when validating multiple transactions against the same
+    /// block, this can considerably reduce the number of storage values that must be fetched.
+    /// The cache must only contain values of the storage of the block whose header is
+    /// [`Config::scale_encoded_header`].
+    pub storage_cache: Option<Arc<dyn StorageCache>>,
+
+    /// If `true`, a transaction whose list of provided tags ([`ValidTransaction::provides`]) is
+    /// empty is considered as valid. If `false`, the validation fails with
+    /// [`Error::EmptyProvidedTags`] instead.
+    ///
+    /// Substrate requires runtimes to always provide at least one tag. This should only be set
+    /// to `true` when working with experimental runtimes that don't respect this requirement.
+    pub allow_empty_provides: bool,
+
+    /// If `true`, the writes to the offchain storage performed by the runtime during the
+    /// validation are reported in [`Query::Finished::offchain_writes`]. If `false`, they are
+    /// silently ignored.
+    pub capture_offchain_writes: bool,
+
+    /// If `Some`, this function is called every time the runtime calls a host function that
+    /// interrupts the execution, before this call is handled. This includes storage reads that
+    /// are answered from [`Config::storage_cache`] and don't lead to a [`Query::StorageGet`].
+    ///
+    /// This is meant to be used for profiling which host functions a runtime calls during the
+    /// validation, and doesn't influence the outcome of the validation.
+    pub on_host_call: Option<HostCallHook>,
+}
+
+/// Function called when the runtime calls a host function. See [`Config::on_host_call`].
+pub type HostCallHook = alloc::boxed::Box<dyn FnMut(&HostCallInfo) + Send>;
+
+/// Storage values known in advance. See [`Config::storage_cache`].
+pub trait StorageCache: Send + Sync {
+    /// Returns the value of the given key of the storage, or `None` if it isn't known.
+    ///
+    /// If `child_trie` is `Some`, the value must be read from the given child trie. If
+    /// `Some(None)` is returned, the key is known to have no value.
+    fn get(
+        &self,
+        child_trie: Option<&[u8]>,
+        key: &[u8],
+    ) -> Option<Option<(Vec<u8>, TrieEntryVersion)>>;
+}
+
+impl<'a, TTx, TRuntime> Config<'a, TTx, TRuntime> {
+    /// Returns a [`ConfigBuilder`] that makes it possible to build a [`Config`] without having
+    /// to specify the fields that have a sensible default value.
+    ///
+    /// The default values are:
+    ///
+    /// - 4 for [`Config::block_number_bytes`].
+    /// - [`TransactionSource::External`] for [`Config::source`].
+    /// - 0 for [`Config::max_log_level`].
+    /// - `false` for [`Config::calculate_trie_changes`].
+    /// - `None` for [`Config::max_wasm_steps`].
+    /// - `None` for [`Config::pre_initialized_changes`].
+    /// - `None` for [`Config::storage_cache`].
+    /// - `false` for [`Config::allow_empty_provides`].
+    /// - `false` for [`Config::capture_offchain_writes`].
+    /// - `None` for [`Config::on_host_call`].
+    pub fn builder(
+        runtime: TRuntime,
+        scale_encoded_header: &'a [u8],
+        scale_encoded_transaction: TTx,
+    ) -> ConfigBuilder<'a, TTx, TRuntime> {
+        ConfigBuilder {
+            config: Config {
+                runtime,
+                scale_encoded_header,
+                block_number_bytes: 4,
+                scale_encoded_transaction,
+                source: TransactionSource::External,
+                max_log_level: 0,
+                calculate_trie_changes: false,
+                max_wasm_steps: None,
+                pre_initialized_changes: None,
+                storage_cache: None,
+                allow_empty_provides: false,
+                capture_offchain_writes: false,
+                on_host_call: None,
+            },
+        }
+    }
+}
+
+/// Builder for a [`Config`]. See [`Config::builder`].
+pub struct ConfigBuilder<'a, TTx, TRuntime = host::HostVmPrototype> {
+    config: Config<'a, TTx, TRuntime>,
+}
+
+impl<'a, TTx, TRuntime> ConfigBuilder<'a, TTx, TRuntime> {
+    /// Sets [`Config::block_number_bytes`].
+    pub fn block_number_bytes(mut self, block_number_bytes: usize) -> Self {
+        self.config.block_number_bytes = block_number_bytes;
+        self
+    }
+
+    /// Sets [`Config::source`].
+    pub fn source(mut self, source: TransactionSource) -> Self {
+        self.config.source = source;
+        self
+    }
+
+    /// Sets [`Config::max_log_level`].
+    pub fn max_log_level(mut self, max_log_level: u32) -> Self {
+        self.config.max_log_level = max_log_level;
+        self
+    }
+
+    /// Sets [`Config::calculate_trie_changes`].
+    pub fn calculate_trie_changes(mut self, calculate_trie_changes: bool) -> Self {
+        self.config.calculate_trie_changes = calculate_trie_changes;
+        self
+    }
+
+    /// Sets [`Config::max_wasm_steps`].
+    pub fn max_wasm_steps(mut self, max_wasm_steps: Option<NonZeroU64>) -> Self {
+        self.config.max_wasm_steps = max_wasm_steps;
+        self
+    }
+
+    /// Sets [`Config::pre_initialized_changes`].
+    pub fn pre_initialized_changes(
+        mut self,
+        pre_initialized_changes: Option<storage_diff::TrieDiff>,
+    ) -> Self {
+        self.config.pre_initialized_changes = pre_initialized_changes;
+        self
+    }
+
+    /// Sets [`Config::storage_cache`].
+    pub fn storage_cache(mut self, storage_cache: Option<Arc<dyn StorageCache>>) -> Self {
+        self.config.storage_cache = storage_cache;
+        self
+    }
+
+    /// Sets [`Config::allow_empty_provides`].
+    pub fn allow_empty_provides(mut self, allow_empty_provides: bool) -> Self {
+        self.config.allow_empty_provides = allow_empty_provides;
+        self
+    }
+
+    /// Sets [`Config::capture_offchain_writes`].
+    pub fn capture_offchain_writes(mut self, capture_offchain_writes: bool) -> Self {
+        self.config.capture_offchain_writes = capture_offchain_writes;
+        self
+    }
+
+    /// Sets [`Config::on_host_call`].
+    pub fn on_host_call(mut self, on_host_call: Option<HostCallHook>) -> Self {
+        self.config.on_host_call = on_host_call;
+        self
+    }
+
+    /// Returns the [`Config`] that has been built.
+    pub fn build(self) -> Config<'a, TTx, TRuntime> {
+        self.config
+    }
 }
 
 /// Source of the transaction.
@@ -82,6 +293,70 @@ pub enum TransactionSource {
     External,
 }
 
+impl TransactionSource {
+    /// Returns the byte that encodes this source in the parameters of the
+    /// `TaggedTransactionQueue_validate_transaction` runtime call.
+    ///
+    /// See also [`validate_transaction_runtime_parameters_v3`].
+    pub fn scale_byte(&self) -> u8 {
+        match self {
+            TransactionSource::InBlock => 0,
+            TransactionSource::Local => 1,
+            TransactionSource::External => 2,
+        }
+    }
+
+    /// Decodes the byte that encodes a source in the parameters of the
+    /// `TaggedTransactionQueue_validate_transaction` runtime call.
+    ///
+    /// Returns `None` if the byte doesn't correspond to any source. This is the inverse of
+    /// [`TransactionSource::scale_byte`].
+    pub fn from_scale_byte(byte: u8) -> Option<TransactionSource> {
+        match byte {
+            0 => Some(TransactionSource::InBlock),
+            1 => Some(TransactionSource::Local),
+            2 => Some(TransactionSource::External),
+            _ => None,
+        }
+    }
+}
+
+/// Priority of a transaction, as found in [`ValidTransaction::priority`].
+///
+/// Runtimes are free to return any value, including values close to [`u64::MAX`]. Summing
+/// priorities, for example in order to compute the priority of a group of transactions, should
+/// be done through [`Priority::saturating_add`] or the [`core::iter::Sum`] implementation, both
+/// of which saturate instead of overflowing.
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, derive_more::Display,
+)]
+pub struct Priority(pub u64);
+
+impl Priority {
+    /// Adds `other` to `self`, returning [`u64::MAX`] in case of overflow.
+    pub fn saturating_add(self, other: Priority) -> Priority {
+        Priority(self.0.saturating_add(other.0))
+    }
+}
+
+impl From<u64> for Priority {
+    fn from(value: u64) -> Priority {
+        Priority(value)
+    }
+}
+
+impl From<Priority> for u64 {
+    fn from(priority: Priority) -> u64 {
+        priority.0
+    }
+}
+
+impl core::iter::Sum for Priority {
+    fn sum<I: Iterator<Item = Priority>>(iter: I) -> Priority {
+        iter.fold(Priority(0), Priority::saturating_add)
+    }
+}
+
 /// Information concerning a valid transaction.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ValidTransaction {
@@ -90,7 +365,7 @@ pub struct ValidTransaction {
     /// Priority determines the ordering of two transactions that have all
     /// [their required tags](ValidTransaction::requires) satisfied. Transactions with a higher
     /// priority should be included first.
-    pub priority: u64,
+    pub priority: Priority,
 
     /// Transaction dependencies.
     ///
@@ -112,7 +387,7 @@ pub struct ValidTransaction {
     /// Two transactions that have a provided tag in common are mutually exclusive, and cannot be
     /// both included in the same chain of blocks.
     ///
-    /// Guaranteed to never be empty.
+    /// Guaranteed to never be empty, unless [`Config::allow_empty_provides`] was `true`.
     // TODO: better type than `Vec<Vec<u8>>`? I feel like this could be a single `Vec<u8>` that is decoded on the fly?
     pub provides: Vec<Vec<u8>>,
 
@@ -140,16 +415,179 @@ pub struct ValidTransaction {
     pub propagate: bool,
 }
 
+impl ValidTransaction {
+    /// Returns a BLAKE2 hash of the list of tags that the transaction provides
+    /// (see [`ValidTransaction::provides`]).
+    ///
+    /// The tags are sorted before being hashed, meaning that the order in which the runtime has
+    /// returned them doesn't influence the result. Two transactions that provide the same tags
+    /// are mutually exclusive, and this fingerprint can thus be used as a key in order to index
+    /// transactions.
+    pub fn provides_fingerprint(&self) -> [u8; 32] {
+        let mut provides = self.provides.iter().collect::<Vec<_>>();
+        provides.sort_unstable();
+
+        let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+        hash.update(util::encode_scale_compact_usize(provides.len()).as_ref());
+        for tag in provides {
+            // Each tag is prefixed with its length in order to avoid ambiguities.
+            hash.update(util::encode_scale_compact_usize(tag.len()).as_ref());
+            hash.update(tag);
+        }
+
+        <[u8; 32]>::try_from(hash.finalize().as_bytes()).unwrap()
+    }
+
+    /// Returns `true` if `self` and `other`, typically the outcomes of validating the same
+    /// transaction against two different blocks, differ in a way that matters.
+    ///
+    /// The two are considered as materially different if any of the following is true:
+    ///
+    /// - The list of tags in [`ValidTransaction::requires`] is different, ignoring their order.
+    /// - The list of tags in [`ValidTransaction::provides`] is different, ignoring their order.
+    /// - The value of [`ValidTransaction::propagate`] is different.
+    /// - The values of [`ValidTransaction::priority`] differ by more than
+    ///   [`ValidTransaction::PRIORITY_DRIFT_PERCENT`] percent of the highest of the two.
+    ///
+    /// The value of [`ValidTransaction::longevity`] is ignored, as it naturally decreases as
+    /// new blocks are produced.
+    pub fn differs_materially(&self, other: &ValidTransaction) -> bool {
+        fn same_tags(a: &[Vec<u8>], b: &[Vec<u8>]) -> bool {
+            let mut a = a.iter().collect::<Vec<_>>();
+            let mut b = b.iter().collect::<Vec<_>>();
+            a.sort_unstable();
+            b.sort_unstable();
+            a == b
+        }
+
+        let priority_drift = self.priority.0.abs_diff(other.priority.0);
+        let priority_max_drift = u128::from(self.priority.max(other.priority).0)
+            * u128::from(Self::PRIORITY_DRIFT_PERCENT)
+            / 100;
+
+        !same_tags(&self.requires, &other.requires)
+            || !same_tags(&self.provides, &other.provides)
+            || self.propagate != other.propagate
+            || u128::from(priority_drift) > priority_max_drift
+    }
+
+    /// Returns `true` if the transaction, whose validation has been performed with the given
+    /// source, should be gossiped to other nodes.
+    ///
+    /// This is the case if [`ValidTransaction::propagate`] is `true` and the transaction doesn't
+    /// come from [`TransactionSource::Local`]. Transactions produced by the node itself, for
+    /// example by an off-chain worker, are meant to be included by this node only.
+    pub fn should_propagate(&self, source: TransactionSource) -> bool {
+        self.propagate && !matches!(source, TransactionSource::Local)
+    }
+
+    /// Decodes each tag of [`ValidTransaction::provides`] using the given function.
+    ///
+    /// The content of tags is opaque to this crate, but callers that know how a specific runtime
+    /// builds its tags (for example as a SCALE-encoded tuple containing an account and a nonce)
+    /// can use this function in order to obtain a typed view of them. Returns the first error
+    /// returned by `decode`, if any.
+    pub fn provides_as<T, E>(
+        &self,
+        decode: impl FnMut(&[u8]) -> Result<T, E>,
+    ) -> Result<Vec<T>, E> {
+        self.provides
+            .iter()
+            .map(|tag| &tag[..])
+            .map(decode)
+            .collect()
+    }
+
+    /// Decodes each tag of [`ValidTransaction::requires`] using the given function.
+    ///
+    /// See [`ValidTransaction::provides_as`].
+    pub fn requires_as<T, E>(
+        &self,
+        decode: impl FnMut(&[u8]) -> Result<T, E>,
+    ) -> Result<Vec<T>, E> {
+        self.requires
+            .iter()
+            .map(|tag| &tag[..])
+            .map(decode)
+            .collect()
+    }
+
+    /// Maximum difference, in percent, between the priorities of two [`ValidTransaction`]s
+    /// below which [`ValidTransaction::differs_materially`] considers them as identical.
+    pub const PRIORITY_DRIFT_PERCENT: u8 = 10;
+}
+
+/// Wraps around a reference to a [`ValidTransaction`] and orders it by
+/// [priority](ValidTransaction::priority).
+///
+/// Transactions with the same priority are ordered by their [`ValidTransaction::provides`],
+/// then their [`ValidTransaction::requires`], [`ValidTransaction::longevity`], and
+/// [`ValidTransaction::propagate`]. The ordering is thus deterministic, and two values compare
+/// equal only if the transactions are equal.
+///
+/// This makes it possible to store transactions in a [`alloc::collections::BinaryHeap`], which
+/// always yields the transaction with the highest priority first:
+///
+/// ```
+/// use smoldot::transactions::validate::{ByPriority, Priority, ValidTransaction};
+/// use std::{collections::BinaryHeap, num::NonZeroU64};
+///
+/// let transactions = [5, 20, 1].map(|priority| ValidTransaction {
+///     priority: Priority(priority),
+///     requires: Vec::new(),
+///     provides: vec![vec![priority as u8]],
+///     longevity: NonZeroU64::new(64).unwrap(),
+///     propagate: true,
+/// });
+///
+/// let mut heap = transactions.iter().map(ByPriority).collect::<BinaryHeap<_>>();
+/// assert_eq!(heap.pop().unwrap().0.priority, Priority(20));
+/// assert_eq!(heap.pop().unwrap().0.priority, Priority(5));
+/// assert_eq!(heap.pop().unwrap().0.priority, Priority(1));
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct ByPriority<'a>(pub &'a ValidTransaction);
+
+impl<'a> Ord for ByPriority<'a> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.0
+            .priority
+            .cmp(&other.0.priority)
+            .then_with(|| self.0.provides.cmp(&other.0.provides))
+            .then_with(|| self.0.requires.cmp(&other.0.requires))
+            .then_with(|| self.0.longevity.cmp(&other.0.longevity))
+            .then_with(|| self.0.propagate.cmp(&other.0.propagate))
+    }
+}
+
+impl<'a> PartialOrd for ByPriority<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> PartialEq for ByPriority<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl<'a> Eq for ByPriority<'a> {}
+
 /// An invalid transaction validity.
 #[derive(Debug, derive_more::Display, Clone, PartialEq, Eq)]
 pub enum InvalidTransaction {
     /// The call of the transaction is not expected.
+    #[display(fmt = "Call (code: {})", "self.as_code()")]
     Call,
     /// General error to do with the inability to pay some fees (e.g. account balance too low).
+    #[display(fmt = "Payment (code: {})", "self.as_code()")]
     Payment,
     /// General error to do with the transaction not yet being valid (e.g. nonce too high).
+    #[display(fmt = "Future (code: {})", "self.as_code()")]
     Future,
     /// General error to do with the transaction being outdated (e.g. nonce too low).
+    #[display(fmt = "Stale (code: {})", "self.as_code()")]
     Stale,
     /// General error to do with the transaction's proofs (e.g. signature).
     ///
@@ -160,24 +598,73 @@ pub enum InvalidTransaction {
     /// data will only be used to generate the signature, but will not be part of the transaction
     /// itself. As the verifying side does not know which additional data was used while signing
     /// it will only be able to assume a bad signature and cannot express a more meaningful error.
+    #[display(fmt = "BadProof (code: {})", "self.as_code()")]
     BadProof,
     /// The transaction birth block is ancient.
+    #[display(fmt = "AncientBirthBlock (code: {})", "self.as_code()")]
     AncientBirthBlock,
     /// The transaction would exhaust the resources of current block.
     ///
     /// The transaction might be valid, but there are not enough resources
     /// left in the current block.
+    #[display(fmt = "ExhaustsResources (code: {})", "self.as_code()")]
     ExhaustsResources,
     /// Any other custom invalid validity that is not covered by this enum.
-    #[display(fmt = "Other reason (code: {_0})")]
+    #[display(fmt = "Other reason (code: {}, custom code: {_0})", "self.as_code()")]
     Custom(u8),
     /// An extrinsic with a Mandatory dispatch resulted in Error. This is indicative of either a
     /// malicious validator or a buggy `provide_inherent`. In any case, it can result in dangerously
     /// overweight blocks and therefore if found, invalidates the block.
+    #[display(fmt = "BadMandatory (code: {})", "self.as_code()")]
     BadMandatory,
     /// A transaction with a mandatory dispatch. This is invalid; only inherent extrinsics are
     /// allowed to have mandatory dispatches.
+    #[display(fmt = "MandatoryDispatch (code: {})", "self.as_code()")]
     MandatoryDispatch,
+    /// The runtime has returned a reason that is unknown to this implementation, most likely
+    /// because it has been introduced in a newer version of Substrate. Contains the code of the
+    /// reason.
+    #[display(fmt = "Unknown reason (code: {})", "self.as_code()")]
+    Unknown(u8),
+}
+
+impl InvalidTransaction {
+    /// Returns the code that identifies this reason in the SCALE encoding used by Substrate.
+    ///
+    /// Note that the code of [`InvalidTransaction::Custom`] is always 7, and doesn't include
+    /// the value it contains.
+    pub fn as_code(&self) -> u8 {
+        match self {
+            InvalidTransaction::Call => 0,
+            InvalidTransaction::Payment => 1,
+            InvalidTransaction::Future => 2,
+            InvalidTransaction::Stale => 3,
+            InvalidTransaction::BadProof => 4,
+            InvalidTransaction::AncientBirthBlock => 5,
+            InvalidTransaction::ExhaustsResources => 6,
+            InvalidTransaction::Custom(_) => 7,
+            InvalidTransaction::BadMandatory => 8,
+            InvalidTransaction::MandatoryDispatch => 9,
+            InvalidTransaction::Unknown(code) => *code,
+        }
+    }
+
+    /// Returns an iterator to all the variants of [`InvalidTransaction`], except for
+    /// [`InvalidTransaction::Custom`] and [`InvalidTransaction::Unknown`] which contain a code.
+    pub fn all_known() -> impl ExactSizeIterator<Item = InvalidTransaction> + Clone {
+        [
+            InvalidTransaction::Call,
+            InvalidTransaction::Payment,
+            InvalidTransaction::Future,
+            InvalidTransaction::Stale,
+            InvalidTransaction::BadProof,
+            InvalidTransaction::AncientBirthBlock,
+            InvalidTransaction::ExhaustsResources,
+            InvalidTransaction::BadMandatory,
+            InvalidTransaction::MandatoryDispatch,
+        ]
+        .into_iter()
+    }
 }
 
 /// An unknown transaction validity.
@@ -192,36 +679,200 @@ pub enum UnknownTransaction {
     Custom(u8),
 }
 
+impl UnknownTransaction {
+    /// Returns an iterator to all the variants of [`UnknownTransaction`], except for
+    /// [`UnknownTransaction::Custom`] which contains a code.
+    pub fn all_known() -> impl ExactSizeIterator<Item = UnknownTransaction> + Clone {
+        [
+            UnknownTransaction::CannotLookup,
+            UnknownTransaction::NoUnsignedValidator,
+        ]
+        .into_iter()
+    }
+}
+
 /// Problem encountered during a call to [`validate_transaction`].
 #[derive(Debug, derive_more::Display, Clone)]
 pub enum Error {
     /// Error while decoding the block header against which to make the call.
     #[display(fmt = "Failed to decode block header: {_0}")]
     InvalidHeader(header::Error),
+    /// [`Config::block_number_bytes`] is larger than 8.
+    InvalidBlockNumberBytes,
+    /// [`Config::block_number_bytes`] is 0, and no number of bytes with which the header can be
+    /// decoded has been found.
+    BlockNumberBytesUnknown,
     /// Transaction validation API version unrecognized.
     UnknownApiVersion,
+    /// The runtime uses version 2 of the transaction validation API, which requires calling
+    /// `Core_initialize_block`, but doesn't support the `Core` API or supports an incompatible
+    /// version of it.
+    #[display(fmt = "Runtime doesn't support a compatible version of the Core API")]
+    MissingCoreApi,
     /// Error while starting the Wasm virtual machine.
     #[display(fmt = "{_0}")]
     WasmStart(host::StartErr),
     /// Error while running the Wasm virtual machine.
+    ///
+    /// If the runtime has trapped, [`Error::RuntimeTrap`] is returned instead.
     #[display(fmt = "{_0}")]
     WasmVmReadWrite(runtime_host::ErrorDetail),
     /// Error while running the Wasm virtual machine.
+    ///
+    /// If the runtime has trapped, [`Error::RuntimeTrap`] is returned instead.
     #[display(fmt = "{_0}")]
     WasmVmReadOnly(runtime_host::ErrorDetail),
+    /// The runtime has trapped, for example because it has executed an `unreachable`
+    /// instruction or has run out of memory.
+    ///
+    /// Contrary to [`Error::WasmVmReadWrite`] and [`Error::WasmVmReadOnly`], this typically
+    /// indicates that the runtime is buggy or incompatible with this implementation.
+    #[display(fmt = "Runtime has trapped: {message}")]
+    RuntimeTrap {
+        /// Runtime function that was being executed.
+        stage: WasmStage,
+        /// Message describing the trap.
+        message: String,
+        /// Concatenation of all the log messages printed by the runtime.
+        logs: String,
+    },
     /// Error while decoding the output of the runtime.
-    OutputDecodeError(DecodeError),
+    #[display(fmt = "{error}")]
+    OutputDecodeError {
+        /// Error that happened.
+        error: DecodeError,
+        /// Output of the runtime that couldn't be decoded.
+        output: Vec<u8>,
+    },
     /// The list of provided tags ([`ValidTransaction::provides`]) is empty. It is mandatory for
     /// the runtime to always provide a non-empty list of tags. This error is consequently a bug
-    /// in the runtime.
+    /// in the runtime. Never returned if [`Config::allow_empty_provides`] is `true`.
     EmptyProvidedTags,
     /// Runtime called a forbidden host function.
     ForbiddenHostCall,
+    /// The execution has been interrupted more than [`Config::max_wasm_steps`] times.
+    ExecutionLimitExceeded,
+    /// The transaction is empty. Only returned by [`pre_check_transaction`].
+    EmptyTransaction,
+    /// The transaction doesn't start with a valid length prefix, or this length prefix is larger
+    /// than the rest of the transaction. Only returned by [`pre_check_transaction`].
+    InvalidTransactionLength,
+}
+
+impl Error {
+    /// If this error happened while running the Wasm virtual machine, returns which runtime
+    /// function was being executed.
+    ///
+    /// Returns `Some` only for [`Error::WasmVmReadWrite`], [`Error::WasmVmReadOnly`], and
+    /// [`Error::RuntimeTrap`].
+    pub fn wasm_error_stage(&self) -> Option<WasmStage> {
+        match self {
+            Error::WasmVmReadWrite(_) => Some(WasmStage::InitializeBlock),
+            Error::WasmVmReadOnly(_) => Some(WasmStage::Validation),
+            Error::RuntimeTrap { stage, .. } => Some(*stage),
+            _ => None,
+        }
+    }
+
+    /// Builds an [`Error`] from an error that happened while running the Wasm virtual machine.
+    fn from_wasm_vm_error(detail: runtime_host::ErrorDetail, stage: WasmStage) -> Self {
+        match detail {
+            runtime_host::ErrorDetail::WasmVm {
+                error: error @ (host::Error::Trap(_) | host::Error::OutOfMemory { .. }),
+                logs,
+            } => Error::RuntimeTrap {
+                stage,
+                message: error.to_string(),
+                logs,
+            },
+            detail => match stage {
+                WasmStage::InitializeBlock => Error::WasmVmReadWrite(detail),
+                WasmStage::Validation => Error::WasmVmReadOnly(detail),
+            },
+        }
+    }
+}
+
+/// Runtime function during whose execution an [`Error`] happened. See
+/// [`Error::wasm_error_stage`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WasmStage {
+    /// Error happened while executing `Core_initialize_block`, which is only called for runtimes
+    /// that support version 2 of the transaction validation API.
+    InitializeBlock,
+    /// Error happened while executing `TaggedTransactionQueue_validate_transaction`.
+    Validation,
+}
+
+/// Information about a call to a host function. See [`Config::on_host_call`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostCallInfo {
+    /// Kind of host function that has been called.
+    pub kind: HostCallKind,
+    /// Runtime function that has called the host function.
+    pub stage: WasmStage,
+}
+
+/// Kind of host function call. See [`HostCallInfo::kind`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HostCallKind {
+    /// Reading a storage value.
+    StorageGet,
+    /// Obtaining the Merkle value of the closest descendant of a trie node.
+    ClosestDescendantMerkleValue,
+    /// Fetching the key that follows a given one.
+    NextKey,
+    /// Verifying a signature.
+    SignatureVerification,
+    /// Writing an offchain storage value.
+    OffchainStorageSet,
+    /// Calling a function that can only be called within the context of an offchain worker.
+    /// This leads to [`Error::ForbiddenHostCall`].
+    Offchain,
 }
 
 /// Error that can happen during the decoding.
-#[derive(Debug, derive_more::Display, Clone)]
-pub struct DecodeError();
+#[derive(Debug, derive_more::Display, Clone, PartialEq, Eq)]
+#[display(fmt = "Failed to decode {field}")]
+pub struct DecodeError {
+    /// Part of the output that couldn't be decoded.
+    pub field: DecodeErrorField,
+}
+
+/// Part of the output of the runtime that couldn't be decoded. See [`DecodeError`].
+#[derive(Debug, derive_more::Display, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeErrorField {
+    /// Tag indicating whether the transaction is valid.
+    #[display(fmt = "validity tag")]
+    ValidityTag,
+    /// Tag indicating whether the transaction is valid has a value other than `0` or `1`.
+    ///
+    /// Contrary to the other variants, this doesn't indicate a truncated or corrupted output,
+    /// but most likely that the runtime and the client disagree on the format of the output.
+    #[display(fmt = "validity tag (unexpected value {_0})")]
+    UnknownValidityTag(u8),
+    /// [`ValidTransaction::priority`].
+    #[display(fmt = "priority")]
+    Priority,
+    /// [`ValidTransaction::requires`].
+    #[display(fmt = "required tags")]
+    Requires,
+    /// [`ValidTransaction::provides`].
+    #[display(fmt = "provided tags")]
+    Provides,
+    /// [`ValidTransaction::longevity`].
+    #[display(fmt = "longevity")]
+    Longevity,
+    /// [`ValidTransaction::propagate`].
+    #[display(fmt = "propagate flag")]
+    Propagate,
+    /// [`TransactionValidityError`] returned in case the transaction isn't valid.
+    #[display(fmt = "transaction validity error")]
+    ValidityError,
+    /// The output contains more data than expected.
+    #[display(fmt = "trailing data")]
+    TrailingData,
+}
 
 /// Errors that can occur while checking the validity of a transaction.
 #[derive(Debug, derive_more::Display, Clone, PartialEq, Eq)]
@@ -234,6 +885,125 @@ pub enum TransactionValidityError {
     Unknown(UnknownTransaction),
 }
 
+impl TransactionValidityError {
+    /// Returns `true` if the transaction might become valid later without being modified, in
+    /// which case it makes sense to keep it and validate it again later. Returns `false` if the
+    /// transaction should be discarded.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            // The transaction isn't valid yet, but will likely become valid once the
+            // transactions it depends on (for example transactions with a lower nonce) have
+            // been included.
+            TransactionValidityError::Invalid(InvalidTransaction::Future) => true,
+            // The transaction is outdated, for example because its nonce has already been used.
+            // It will never become valid again.
+            TransactionValidityError::Invalid(InvalidTransaction::Stale) => false,
+            // The signature or the proofs of the transaction are wrong. Validating it again will
+            // lead to the same outcome.
+            TransactionValidityError::Invalid(InvalidTransaction::BadProof) => false,
+            // While the balance of an account might increase in the future, there is no way to
+            // know when, and keeping the transaction around could be abused.
+            TransactionValidityError::Invalid(InvalidTransaction::Payment) => false,
+            // Problems with the content of the transaction itself.
+            TransactionValidityError::Invalid(
+                InvalidTransaction::Call
+                | InvalidTransaction::AncientBirthBlock
+                | InvalidTransaction::BadMandatory
+                | InvalidTransaction::MandatoryDispatch,
+            ) => false,
+            // The transaction doesn't fit in the current block. It is considered as invalid,
+            // as there is no guarantee that it will ever fit in a block.
+            TransactionValidityError::Invalid(InvalidTransaction::ExhaustsResources) => false,
+            // The meaning of these reasons is unknown. They are considered as definitive in
+            // order to be conservative.
+            TransactionValidityError::Invalid(
+                InvalidTransaction::Custom(_) | InvalidTransaction::Unknown(_),
+            ) => false,
+            // Looking up information required to validate the transaction has failed. This is
+            // typically a transient problem.
+            TransactionValidityError::Unknown(UnknownTransaction::CannotLookup) => true,
+            // The runtime doesn't know how to validate this unsigned transaction, and no
+            // amount of waiting will change that.
+            TransactionValidityError::Unknown(UnknownTransaction::NoUnsignedValidator) => false,
+            // The meaning of this reason is unknown. It is considered as definitive in order to
+            // be conservative.
+            TransactionValidityError::Unknown(UnknownTransaction::Custom(_)) => false,
+        }
+    }
+
+    /// Returns `true` if the transaction itself is fine but can't be included in the next
+    /// block, in which case it should be kept in the queue of pending transactions and
+    /// considered again when building a later block. Returns `false` if the transaction should
+    /// be dropped from the queue.
+    ///
+    /// This is the case for [`InvalidTransaction::Future`], where the transaction must wait for
+    /// the transactions it depends on, and for [`InvalidTransaction::ExhaustsResources`], where
+    /// the block being built is full.
+    ///
+    /// > **Note**: Contrary to [`TransactionValidityError::is_retriable`], which indicates
+    /// >           whether validating the transaction again later might lead to a different
+    /// >           outcome, this function indicates whether the transaction is worth keeping
+    /// >           around for inclusion in a later block. In particular, a transaction whose
+    /// >           validity couldn't be determined ([`UnknownTransaction::CannotLookup`]) should
+    /// >           be validated again, but shouldn't be requeued as if it was known to be valid.
+    pub fn should_requeue(&self) -> bool {
+        matches!(
+            self,
+            TransactionValidityError::Invalid(
+                InvalidTransaction::Future | InvalidTransaction::ExhaustsResources
+            )
+        )
+    }
+
+    /// Returns a short explanation of the error, suitable for being shown to an end user.
+    ///
+    /// Contrary to the [`core::fmt::Display`] implementation, the message doesn't contain any
+    /// technical detail. The meaning of [`InvalidTransaction::Custom`],
+    /// [`InvalidTransaction::Unknown`], and [`UnknownTransaction::Custom`] depends on the chain,
+    /// and a generic message is returned for them.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            TransactionValidityError::Invalid(InvalidTransaction::Call) => {
+                "The transaction performs an operation that isn't allowed"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::Payment) => {
+                "Insufficient balance to pay fees"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::Future) => {
+                "The transaction isn't valid yet, for example because a previous transaction of \
+                 the same account is still pending"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::Stale) => {
+                "The transaction is outdated, for example because it has already been submitted"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::BadProof) => {
+                "The signature of the transaction is invalid"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::AncientBirthBlock) => {
+                "The transaction has expired"
+            }
+            TransactionValidityError::Invalid(InvalidTransaction::ExhaustsResources) => {
+                "The transaction is too large or too expensive to fit in a block"
+            }
+            TransactionValidityError::Invalid(
+                InvalidTransaction::BadMandatory | InvalidTransaction::MandatoryDispatch,
+            ) => "The transaction is reserved to block authors",
+            TransactionValidityError::Invalid(
+                InvalidTransaction::Custom(_) | InvalidTransaction::Unknown(_),
+            ) => "The transaction has been rejected by the chain",
+            TransactionValidityError::Unknown(UnknownTransaction::CannotLookup) => {
+                "The information needed to check the transaction couldn't be found"
+            }
+            TransactionValidityError::Unknown(UnknownTransaction::NoUnsignedValidator) => {
+                "The chain doesn't accept this unsigned transaction"
+            }
+            TransactionValidityError::Unknown(UnknownTransaction::Custom(_)) => {
+                "The validity of the transaction couldn't be determined"
+            }
+        }
+    }
+}
+
 /// Produces the input to pass to the `TaggedTransactionQueue_validate_transaction` runtime call.
 pub fn validate_transaction_runtime_parameters_v2<'a>(
     scale_encoded_transaction: impl Iterator<Item = impl AsRef<[u8]> + 'a> + Clone + 'a,
@@ -259,13 +1029,7 @@ fn validate_transaction_runtime_parameters_inner<'a>(
     // The `TaggedTransactionQueue_validate_transaction` function expects a SCALE-encoded
     // `(source, tx, block_hash)`. The encoding is performed manually in order to avoid
     // performing redundant data copies.
-    let source = match source {
-        TransactionSource::InBlock => &[0],
-        TransactionSource::Local => &[1],
-        TransactionSource::External => &[2],
-    };
-
-    iter::once(source)
+    iter::once([source.scale_byte()])
         .map(either::Left)
         .chain(
             scale_encoded_transaction
@@ -283,29 +1047,207 @@ pub const VALIDATION_FUNCTION_NAME: &str = "TaggedTransactionQueue_validate_tran
 pub fn decode_validate_transaction_return_value(
     scale_encoded: &[u8],
 ) -> Result<Result<ValidTransaction, TransactionValidityError>, DecodeError> {
-    match nom::combinator::all_consuming(transaction_validity)(scale_encoded) {
-        Ok((_, data)) => Ok(data),
-        Err(_) => Err(DecodeError()),
+    match decode_validate_transaction_return_value_partial(scale_encoded)? {
+        (data, []) => Ok(data),
+        (_, _) => Err(DecodeError {
+            field: DecodeErrorField::TrailingData,
+        }),
+    }
+}
+
+/// Attempt to decode the return value of the  `TaggedTransactionQueue_validate_transaction`
+/// runtime call.
+///
+/// Contrary to [`decode_validate_transaction_return_value`], doesn't return an error if the
+/// slice is too long but returns the remainder.
+pub fn decode_validate_transaction_return_value_partial(
+    scale_encoded: &[u8],
+) -> Result<(Result<ValidTransaction, TransactionValidityError>, &[u8]), DecodeError> {
+    // The tag is checked separately, in order to report its value.
+    if let Some(tag @ 2..) = scale_encoded.first().copied() {
+        return Err(DecodeError {
+            field: DecodeErrorField::UnknownValidityTag(tag),
+        });
+    }
+
+    match transaction_validity(scale_encoded) {
+        Ok((remainder, data)) => Ok((data, remainder)),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(DecodeError {
+            // Only the tag at the very beginning isn't covered by a more precise context.
+            field: err.field.unwrap_or(DecodeErrorField::ValidityTag),
+        }),
+        // All the parsers are wrapped in `nom::combinator::complete`.
+        Err(nom::Err::Incomplete(_)) => unreachable!(),
+    }
+}
+
+/// Attempt to decode the return value of the  `TaggedTransactionQueue_validate_transaction`
+/// runtime call.
+///
+/// Contrary to [`decode_validate_transaction_return_value`], doesn't return an error if the
+/// slice is too long but returns the number of bytes at the start of `scale_encoded` that the
+/// return value occupies. This is convenient when the return value is embedded in a larger
+/// buffer.
+pub fn decode_validate_transaction_return_value_framed(
+    scale_encoded: &[u8],
+) -> Result<(Result<ValidTransaction, TransactionValidityError>, usize), DecodeError> {
+    let (data, remainder) = decode_validate_transaction_return_value_partial(scale_encoded)?;
+    Ok((data, scale_encoded.len() - remainder.len()))
+}
+
+/// Performs some cheap checks on the given SCALE-encoded transaction, in order to reject
+/// obviously malformed transactions without starting a virtual machine.
+///
+/// Returns [`Error::EmptyTransaction`] if the transaction is empty, and
+/// [`Error::InvalidTransactionLength`] if the transaction doesn't start with a SCALE-compact
+/// length or if this length is larger than the rest of the transaction.
+///
+/// A transaction that passes these checks must still be validated with
+/// [`validate_transaction`]. This function doesn't need to be called before
+/// [`validate_transaction`], but makes it possible to avoid the cost of the validation when
+/// receiving garbage.
+pub fn pre_check_transaction(scale_encoded_transaction: &[u8]) -> Result<(), Error> {
+    if scale_encoded_transaction.is_empty() {
+        return Err(Error::EmptyTransaction);
+    }
+
+    match crate::util::nom_scale_compact_usize::<nom::error::Error<&[u8]>>(
+        scale_encoded_transaction,
+    ) {
+        Ok((rest, length)) if length <= rest.len() => Ok(()),
+        _ => Err(Error::InvalidTransactionLength),
+    }
+}
+
+/// Returns the version of the transaction validation API that the given runtime supports, or
+/// `None` if the runtime doesn't support any version that [`validate_transaction`] is capable of
+/// using.
+///
+/// If this function returns `None`, then [`validate_transaction`] always fails with
+/// [`Error::UnknownApiVersion`].
+pub fn supported_validation_api_version(runtime: &host::HostVmPrototype) -> Option<u32> {
+    match runtime
+        .runtime_version()
+        .decode()
+        .apis
+        .find_version("TaggedTransactionQueue")
+    {
+        Some(v @ (2 | 3)) => Some(v),
+        _ => None,
     }
 }
 
 /// Validates a transaction by calling `TaggedTransactionQueue_validate_transaction`.
 pub fn validate_transaction(
-    config: Config<impl ExactSizeIterator<Item = impl AsRef<[u8]> + Clone> + Clone>,
+    mut config: Config<impl ExactSizeIterator<Item = impl AsRef<[u8]> + Clone> + Clone>,
 ) -> Query {
     // The parameters of the function, and whether to call `Core_initialize_block` beforehand,
     // depend on the API version.
-    let api_version = config
+    let [api_version, core_api_version] = config
         .runtime
         .runtime_version()
         .decode()
         .apis
-        .find_version("TaggedTransactionQueue");
+        .find_versions(["TaggedTransactionQueue", "Core"]);
+
+    let transaction_encoded_len = config
+        .scale_encoded_transaction
+        .clone()
+        .map(|chunk| chunk.as_ref().len())
+        .sum();
+
+    // The number of bytes only influences the way the digest items of the header are decoded and
+    // re-encoded. Any value with which the header can be decoded leads to the same result.
+    if config.block_number_bytes == 0 {
+        match (1..=8).find(|n| header::decode(config.scale_encoded_header, *n).is_ok()) {
+            Some(n) => config.block_number_bytes = n,
+            None => {
+                return Query::Finished {
+                    result: Err(Error::BlockNumberBytesUnknown),
+                    virtual_machine: config.runtime,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len,
+                    api_version,
+                }
+            }
+        }
+    }
+
+    // An obviously wrong number of bytes would otherwise lead to a corrupted header being passed
+    // to the runtime.
+    if config.block_number_bytes > 8 {
+        return Query::Finished {
+            result: Err(Error::InvalidBlockNumberBytes),
+            virtual_machine: config.runtime,
+            storage_changes: None,
+            offchain_writes: Vec::new(),
+            transaction_encoded_len,
+            api_version,
+        };
+    }
 
     match api_version {
+        Some(2) if config.pre_initialized_changes.is_some() => {
+            // In version 2, we need to call `Core_initialize_block` beforehand, but the caller
+            // has already done so.
+            let vm = runtime_host::run(runtime_host::Config {
+                virtual_machine: config.runtime,
+                function_to_call: VALIDATION_FUNCTION_NAME,
+                parameter: validate_transaction_runtime_parameters_v2(
+                    config.scale_encoded_transaction,
+                    config.source,
+                ),
+                storage_main_trie_changes: config.pre_initialized_changes.unwrap(),
+                max_log_level: config.max_log_level,
+                calculate_trie_changes: config.calculate_trie_changes,
+            });
+
+            match vm {
+                Ok(vm) => Query::from_step2(
+                    vm,
+                    Stage2 {
+                        calculate_trie_changes: config.calculate_trie_changes,
+                        remaining_steps: config.max_wasm_steps.map(|n| n.get()),
+                        api_version: 2,
+                        storage_accesses: Vec::new(),
+                        storage_cache: config.storage_cache,
+                        allow_empty_provides: config.allow_empty_provides,
+                        offchain_writes: if config.capture_offchain_writes {
+                            Some(Vec::new())
+                        } else {
+                            None
+                        },
+                        transaction_encoded_len,
+                        on_host_call: config.on_host_call,
+                    },
+                ),
+                Err((err, virtual_machine)) => Query::Finished {
+                    result: Err(Error::WasmStart(err)),
+                    virtual_machine,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len,
+                    api_version,
+                },
+            }
+        }
         Some(2) => {
             // In version 2, we need to call `Core_initialize_block` beforehand.
 
+            // Make sure that `Core_initialize_block` can actually be called, as otherwise the
+            // call below would fail with a confusing error.
+            if !matches!(core_api_version, Some(1..=5)) {
+                return Query::Finished {
+                    result: Err(Error::MissingCoreApi),
+                    virtual_machine: config.runtime,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len,
+                    api_version,
+                };
+            }
+
             // The `Core_initialize_block` function called below expects a partially-initialized
             // SCALE-encoded header. Importantly, passing the entire header will lead to different code
             // paths in the runtime and not match what Substrate does.
@@ -316,6 +1258,10 @@ pub fn validate_transaction(
                         return Query::Finished {
                             result: Err(Error::InvalidHeader(err)),
                             virtual_machine: config.runtime,
+                            storage_changes: None,
+                            offchain_writes: Vec::new(),
+                            transaction_encoded_len,
+                            api_version,
                         }
                     }
                 };
@@ -348,6 +1294,18 @@ pub fn validate_transaction(
                     },
                 ),
                 max_log_level: config.max_log_level,
+                calculate_trie_changes: config.calculate_trie_changes,
+                remaining_steps: config.max_wasm_steps.map(|n| n.get()),
+                storage_accesses: Vec::new(),
+                storage_cache: config.storage_cache,
+                allow_empty_provides: config.allow_empty_provides,
+                offchain_writes: if config.capture_offchain_writes {
+                    Some(Vec::new())
+                } else {
+                    None
+                },
+                transaction_encoded_len,
+                on_host_call: config.on_host_call,
             };
 
             match vm {
@@ -355,6 +1313,10 @@ pub fn validate_transaction(
                 Err((err, virtual_machine)) => Query::Finished {
                     result: Err(Error::WasmStart(err)),
                     virtual_machine,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len,
+                    api_version,
                 },
             }
         }
@@ -371,24 +1333,87 @@ pub fn validate_transaction(
                 ),
                 storage_main_trie_changes: storage_diff::TrieDiff::empty(),
                 max_log_level: config.max_log_level,
-                calculate_trie_changes: false,
+                calculate_trie_changes: config.calculate_trie_changes,
             });
 
             match vm {
-                Ok(vm) => Query::from_step2(vm, Stage2 {}),
+                Ok(vm) => Query::from_step2(
+                    vm,
+                    Stage2 {
+                        calculate_trie_changes: config.calculate_trie_changes,
+                        remaining_steps: config.max_wasm_steps.map(|n| n.get()),
+                        api_version: 3,
+                        storage_accesses: Vec::new(),
+                        storage_cache: config.storage_cache,
+                        allow_empty_provides: config.allow_empty_provides,
+                        offchain_writes: if config.capture_offchain_writes {
+                            Some(Vec::new())
+                        } else {
+                            None
+                        },
+                        transaction_encoded_len,
+                        on_host_call: config.on_host_call,
+                    },
+                ),
                 Err((err, virtual_machine)) => Query::Finished {
                     result: Err(Error::WasmStart(err)),
                     virtual_machine,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len,
+                    api_version,
                 },
             }
         }
         _ => Query::Finished {
             result: Err(Error::UnknownApiVersion),
             virtual_machine: config.runtime,
+            storage_changes: None,
+            offchain_writes: Vec::new(),
+            transaction_encoded_len,
+            api_version,
         },
     }
 }
 
+/// Similar to [`validate_transaction`], but borrows the runtime instead of taking ownership of it.
+///
+/// This is convenient when the same [`host::HostVmPrototype`] is kept around in order to
+/// validate multiple transactions.
+///
+/// > **Note**: Because the virtual machine requires ownership of the runtime, the runtime is
+/// >           cloned, which allocates the memory necessary for the clone to run. The
+/// >           [`Query::Finished::virtual_machine`] that is eventually returned is this clone,
+/// >           and can simply be dropped.
+pub fn validate_transaction_borrowed(
+    config: Config<
+        impl ExactSizeIterator<Item = impl AsRef<[u8]> + Clone> + Clone,
+        &host::HostVmPrototype,
+    >,
+) -> Query {
+    validate_transaction(Config {
+        runtime: config.runtime.clone(),
+        scale_encoded_header: config.scale_encoded_header,
+        block_number_bytes: config.block_number_bytes,
+        scale_encoded_transaction: config.scale_encoded_transaction,
+        source: config.source,
+        max_log_level: config.max_log_level,
+        calculate_trie_changes: config.calculate_trie_changes,
+        max_wasm_steps: config.max_wasm_steps,
+        pre_initialized_changes: config.pre_initialized_changes,
+        storage_cache: config.storage_cache,
+        allow_empty_provides: config.allow_empty_provides,
+        capture_offchain_writes: config.capture_offchain_writes,
+        on_host_call: config.on_host_call,
+    })
+}
+
+/// Main trie key that has been read during the validation, and its value.
+pub type StorageAccess = (Vec<u8>, Option<Vec<u8>>);
+
+/// Offchain storage key that has been written during the validation, and its new value.
+pub type OffchainWrite = (Vec<u8>, Option<Vec<u8>>);
+
 /// Current state of the operation.
 #[must_use]
 pub enum Query {
@@ -401,6 +1426,33 @@ pub enum Query {
         result: Result<Result<ValidTransaction, TransactionValidityError>, Error>,
         /// Virtual machine initially passed through the configuration.
         virtual_machine: host::HostVmPrototype,
+        /// Changes to the storage performed during the validation.
+        ///
+        /// Always `None` if [`Config::calculate_trie_changes`] was `false` or if the runtime
+        /// call has failed.
+        storage_changes: Option<runtime_host::StorageChanges>,
+        /// Writes to the offchain storage performed during the validation. `None` as a value
+        /// means that the key has been cleared.
+        ///
+        /// The writes performed by `Core_initialize_block`, if it has been called, come first.
+        /// The writes performed during a single runtime call are ordered by key, and only the
+        /// last write of each key is reported.
+        ///
+        /// Always empty if [`Config::capture_offchain_writes`] was `false` or if the runtime
+        /// call has failed.
+        offchain_writes: Vec<OffchainWrite>,
+        /// Number of bytes of the SCALE-encoded transaction that has been validated, as passed
+        /// through [`Config::scale_encoded_transaction`].
+        ///
+        /// Can be used, alongside with [`ValidTransaction::priority`], in order to decide which
+        /// transactions to include in a block whose size is limited.
+        transaction_encoded_len: usize,
+        /// Version of the `TaggedTransactionQueue` API of the runtime, or `None` if the runtime
+        /// doesn't support this API.
+        ///
+        /// If `Some(2)`, `Core_initialize_block` has been called prior to the validation. If
+        /// `Some(3)`, it hasn't. Any other value leads to [`Error::UnknownApiVersion`].
+        api_version: Option<u32>,
     },
     /// Loading a storage value is required in order to continue.
     StorageGet(StorageGet),
@@ -409,15 +1461,50 @@ pub enum Query {
     ClosestDescendantMerkleValue(ClosestDescendantMerkleValue),
     /// Fetching the key that follows a given one is required in order to continue.
     NextKey(NextKey),
+    /// Verifying whether a signature is correct is required in order to continue.
+    SignatureVerification(SignatureVerification),
 }
 
 impl Query {
+    /// Builds a [`Query::StorageGet`] that isn't backed by any virtual machine.
+    ///
+    /// Calling [`StorageGet::inject_value`] on the returned query calls `on_value` with the
+    /// injected value, and returns whatever `on_value` returns. This makes it possible to test
+    /// code that answers storage requests without having to build a
+    /// [`host::HostVmPrototype`]. Mock queries can be chained by returning another mock query
+    /// from `on_value`.
+    ///
+    /// # Panic
+    ///
+    /// [`Query::into_prototype`] and [`Query::into_prototype_and_accessed`] panic if called on
+    /// the returned query, as there isn't any virtual machine to return.
+    ///
+    #[cfg(any(test, feature = "testing"))]
+    pub fn mock_storage_get(
+        key: Vec<u8>,
+        on_value: impl FnOnce(Option<Vec<u8>>) -> Query + Send + 'static,
+    ) -> Query {
+        Query::StorageGet(StorageGet(StorageGetInner::Mock(
+            key,
+            alloc::boxed::Box::new(on_value),
+        )))
+    }
+
     /// Cancels execution of the virtual machine and returns back the prototype.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the query has been built with [`Query::mock_storage_get`].
+    ///
     pub fn into_prototype(self) -> host::HostVmPrototype {
         match self {
             Query::Finished {
                 virtual_machine, ..
             } => virtual_machine,
+            #[cfg(any(test, feature = "testing"))]
+            Query::StorageGet(StorageGet(StorageGetInner::Mock(..))) => {
+                panic!("mock query isn't backed by a virtual machine")
+            }
             Query::StorageGet(StorageGet(StorageGetInner::Stage1(inner, _))) => {
                 runtime_host::RuntimeHostVm::StorageGet(inner).into_prototype()
             }
@@ -436,21 +1523,94 @@ impl Query {
             Query::NextKey(NextKey(NextKeyInner::Stage2(inner, _))) => {
                 runtime_host::RuntimeHostVm::NextKey(inner).into_prototype()
             }
+            Query::SignatureVerification(SignatureVerification(
+                SignatureVerificationInner::Stage1(inner, _),
+            )) => runtime_host::RuntimeHostVm::SignatureVerification(inner).into_prototype(),
+            Query::SignatureVerification(SignatureVerification(
+                SignatureVerificationInner::Stage2(inner, _),
+            )) => runtime_host::RuntimeHostVm::SignatureVerification(inner).into_prototype(),
         }
     }
 
-    fn from_step1(mut inner: runtime_host::RuntimeHostVm, info: Stage1) -> Self {
+    /// Cancels execution of the virtual machine and returns back the prototype, alongside with
+    /// the list of main trie storage values that have been injected with
+    /// [`StorageGet::inject_value`] so far.
+    ///
+    /// This list can for example be used in order to populate a cache.
+    ///
+    /// If the validation is already [`Query::Finished`], the list is always empty.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the query has been built with [`Query::mock_storage_get`].
+    ///
+    pub fn into_prototype_and_accessed(mut self) -> (host::HostVmPrototype, Vec<StorageAccess>) {
+        let accessed = match &mut self {
+            Query::Finished { .. } => Vec::new(),
+            #[cfg(any(test, feature = "testing"))]
+            Query::StorageGet(StorageGet(StorageGetInner::Mock(..))) => Vec::new(),
+            Query::StorageGet(StorageGet(StorageGetInner::Stage1(_, stage1)))
+            | Query::ClosestDescendantMerkleValue(ClosestDescendantMerkleValue(
+                MerkleValueInner::Stage1(_, stage1),
+            ))
+            | Query::NextKey(NextKey(NextKeyInner::Stage1(_, stage1)))
+            | Query::SignatureVerification(SignatureVerification(
+                SignatureVerificationInner::Stage1(_, stage1),
+            )) => mem::take(&mut stage1.storage_accesses),
+            Query::StorageGet(StorageGet(StorageGetInner::Stage2(_, stage2)))
+            | Query::ClosestDescendantMerkleValue(ClosestDescendantMerkleValue(
+                MerkleValueInner::Stage2(_, stage2),
+            ))
+            | Query::NextKey(NextKey(NextKeyInner::Stage2(_, stage2)))
+            | Query::SignatureVerification(SignatureVerification(
+                SignatureVerificationInner::Stage2(_, stage2),
+            )) => mem::take(&mut stage2.storage_accesses),
+        };
+
+        (self.into_prototype(), accessed)
+    }
+
+    fn from_step1(mut inner: runtime_host::RuntimeHostVm, mut info: Stage1) -> Self {
         loop {
+            if !matches!(inner, runtime_host::RuntimeHostVm::Finished(_))
+                && !consume_step(&mut info.remaining_steps)
+            {
+                return Query::Finished {
+                    result: Err(Error::ExecutionLimitExceeded),
+                    virtual_machine: inner.into_prototype(),
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(2),
+                };
+            }
+
+            report_host_call(&mut info.on_host_call, &inner, WasmStage::InitializeBlock);
+
             break match inner {
                 runtime_host::RuntimeHostVm::Finished(Ok(success)) => {
                     // No output expected from `Core_initialize_block`.
                     if !success.virtual_machine.value().as_ref().is_empty() {
+                        let output = success.virtual_machine.value().as_ref().to_vec();
                         return Query::Finished {
-                            result: Err(Error::OutputDecodeError(DecodeError())),
+                            result: Err(Error::OutputDecodeError {
+                                error: DecodeError {
+                                    field: DecodeErrorField::TrailingData,
+                                },
+                                output,
+                            }),
                             virtual_machine: success.virtual_machine.into_prototype(),
+                            storage_changes: None,
+                            offchain_writes: Vec::new(),
+                            transaction_encoded_len: info.transaction_encoded_len,
+                            api_version: Some(2),
                         };
                     }
 
+                    if let Some(offchain_writes) = &mut info.offchain_writes {
+                        record_offchain_writes(offchain_writes, &success.storage_changes);
+                    }
+
                     let vm = runtime_host::run(runtime_host::Config {
                         virtual_machine: success.virtual_machine.into_prototype(),
                         function_to_call: VALIDATION_FUNCTION_NAME,
@@ -460,23 +1620,58 @@ impl Query {
                         ),
                         storage_main_trie_changes: success.storage_changes.into_main_trie_diff(),
                         max_log_level: info.max_log_level,
-                        calculate_trie_changes: false,
+                        calculate_trie_changes: info.calculate_trie_changes,
                     });
 
                     match vm {
-                        Ok(vm) => Query::from_step2(vm, Stage2 {}),
+                        Ok(vm) => Query::from_step2(
+                            vm,
+                            Stage2 {
+                                calculate_trie_changes: info.calculate_trie_changes,
+                                remaining_steps: info.remaining_steps,
+                                api_version: 2,
+                                storage_accesses: info.storage_accesses,
+                                storage_cache: info.storage_cache,
+                                allow_empty_provides: info.allow_empty_provides,
+                                offchain_writes: info.offchain_writes,
+                                transaction_encoded_len: info.transaction_encoded_len,
+                                on_host_call: info.on_host_call,
+                            },
+                        ),
                         Err((err, virtual_machine)) => Query::Finished {
                             result: Err(Error::WasmStart(err)),
                             virtual_machine,
+                            storage_changes: None,
+                            offchain_writes: Vec::new(),
+                            transaction_encoded_len: info.transaction_encoded_len,
+                            api_version: Some(2),
                         },
                     }
                 }
                 runtime_host::RuntimeHostVm::Finished(Err(err)) => Query::Finished {
-                    result: Err(Error::WasmVmReadWrite(err.detail)),
+                    result: Err(Error::from_wasm_vm_error(
+                        err.detail,
+                        WasmStage::InitializeBlock,
+                    )),
                     virtual_machine: err.prototype,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(2),
                 },
-                runtime_host::RuntimeHostVm::StorageGet(i) => {
-                    Query::StorageGet(StorageGet(StorageGetInner::Stage1(i, info)))
+                runtime_host::RuntimeHostVm::StorageGet(get) => {
+                    match cached_storage_value(info.storage_cache.as_deref(), &get) {
+                        Some(value) => {
+                            record_storage_access(&mut info.storage_accesses, &get, &value);
+                            inner = get.inject_value(
+                                value
+                                    .as_ref()
+                                    .map(|(value, version)| (iter::once(value), *version)),
+                            );
+                            continue;
+                        }
+                        None => Query::StorageGet(StorageGet(StorageGetInner::Stage1(get, info))),
+                    }
                 }
                 runtime_host::RuntimeHostVm::ClosestDescendantMerkleValue(inner) => {
                     Query::ClosestDescendantMerkleValue(ClosestDescendantMerkleValue(
@@ -487,41 +1682,75 @@ impl Query {
                     Query::NextKey(NextKey(NextKeyInner::Stage1(inner, info)))
                 }
                 runtime_host::RuntimeHostVm::SignatureVerification(sig) => {
-                    inner = sig.verify_and_resume();
-                    continue;
+                    Query::SignatureVerification(SignatureVerification(
+                        SignatureVerificationInner::Stage1(sig, info),
+                    ))
                 }
                 runtime_host::RuntimeHostVm::OffchainStorageSet(req) => {
-                    // Ignore the offchain storage write.
+                    // Offchain storage writes are ignored unless they must be captured.
+                    if let Some(offchain_writes) = &mut info.offchain_writes {
+                        offchain_writes.push((
+                            req.key().as_ref().to_vec(),
+                            req.value().map(|value| value.as_ref().to_vec()),
+                        ));
+                    }
                     inner = req.resume();
                     continue;
                 }
                 runtime_host::RuntimeHostVm::Offchain(ctx) => Query::Finished {
                     result: Err(Error::ForbiddenHostCall),
                     virtual_machine: ctx.into_prototype(),
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(2),
                 },
             };
         }
     }
 
-    fn from_step2(mut inner: runtime_host::RuntimeHostVm, info: Stage2) -> Self {
+    fn from_step2(mut inner: runtime_host::RuntimeHostVm, mut info: Stage2) -> Self {
         loop {
+            if !matches!(inner, runtime_host::RuntimeHostVm::Finished(_))
+                && !consume_step(&mut info.remaining_steps)
+            {
+                return Query::Finished {
+                    result: Err(Error::ExecutionLimitExceeded),
+                    virtual_machine: inner.into_prototype(),
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(info.api_version),
+                };
+            }
+
+            report_host_call(&mut info.on_host_call, &inner, WasmStage::Validation);
+
             break match inner {
                 runtime_host::RuntimeHostVm::Finished(Ok(success)) => {
                     // This decoding is done in multiple steps in order to solve borrow checking
                     // errors.
                     let result = {
                         let output = success.virtual_machine.value();
-                        decode_validate_transaction_return_value(output.as_ref())
-                            .map_err(Error::OutputDecodeError)
+                        decode_validate_transaction_return_value(output.as_ref()).map_err(|error| {
+                            Error::OutputDecodeError {
+                                error,
+                                output: output.as_ref().to_vec(),
+                            }
+                        })
                     };
 
                     let result = match result {
                         Ok(res) => {
                             if let Ok(res) = res.as_ref() {
-                                if res.provides.is_empty() {
+                                if res.provides.is_empty() && !info.allow_empty_provides {
                                     return Query::Finished {
                                         result: Err(Error::EmptyProvidedTags),
                                         virtual_machine: success.virtual_machine.into_prototype(),
+                                        storage_changes: None,
+                                        offchain_writes: Vec::new(),
+                                        transaction_encoded_len: info.transaction_encoded_len,
+                                        api_version: Some(info.api_version),
                                     };
                                 }
                             }
@@ -531,21 +1760,52 @@ impl Query {
                             return Query::Finished {
                                 result: Err(err),
                                 virtual_machine: success.virtual_machine.into_prototype(),
+                                storage_changes: None,
+                                offchain_writes: Vec::new(),
+                                transaction_encoded_len: info.transaction_encoded_len,
+                                api_version: Some(info.api_version),
                             }
                         }
                     };
 
+                    if let Some(offchain_writes) = &mut info.offchain_writes {
+                        record_offchain_writes(offchain_writes, &success.storage_changes);
+                    }
+
                     Query::Finished {
                         result: Ok(result),
                         virtual_machine: success.virtual_machine.into_prototype(),
+                        storage_changes: if info.calculate_trie_changes {
+                            Some(success.storage_changes)
+                        } else {
+                            None
+                        },
+                        offchain_writes: info.offchain_writes.take().unwrap_or_default(),
+                        transaction_encoded_len: info.transaction_encoded_len,
+                        api_version: Some(info.api_version),
                     }
                 }
                 runtime_host::RuntimeHostVm::Finished(Err(err)) => Query::Finished {
-                    result: Err(Error::WasmVmReadOnly(err.detail)),
+                    result: Err(Error::from_wasm_vm_error(err.detail, WasmStage::Validation)),
                     virtual_machine: err.prototype,
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(info.api_version),
                 },
-                runtime_host::RuntimeHostVm::StorageGet(i) => {
-                    Query::StorageGet(StorageGet(StorageGetInner::Stage2(i, info)))
+                runtime_host::RuntimeHostVm::StorageGet(get) => {
+                    match cached_storage_value(info.storage_cache.as_deref(), &get) {
+                        Some(value) => {
+                            record_storage_access(&mut info.storage_accesses, &get, &value);
+                            inner = get.inject_value(
+                                value
+                                    .as_ref()
+                                    .map(|(value, version)| (iter::once(value), *version)),
+                            );
+                            continue;
+                        }
+                        None => Query::StorageGet(StorageGet(StorageGetInner::Stage2(get, info))),
+                    }
                 }
                 runtime_host::RuntimeHostVm::ClosestDescendantMerkleValue(inner) => {
                     Query::ClosestDescendantMerkleValue(ClosestDescendantMerkleValue(
@@ -556,33 +1816,125 @@ impl Query {
                     Query::NextKey(NextKey(NextKeyInner::Stage2(inner, info)))
                 }
                 runtime_host::RuntimeHostVm::SignatureVerification(sig) => {
-                    inner = sig.verify_and_resume();
-                    continue;
+                    Query::SignatureVerification(SignatureVerification(
+                        SignatureVerificationInner::Stage2(sig, info),
+                    ))
                 }
                 runtime_host::RuntimeHostVm::OffchainStorageSet(req) => {
-                    // Ignore the offchain storage write.
+                    // Offchain storage writes are ignored unless they must be captured.
+                    if let Some(offchain_writes) = &mut info.offchain_writes {
+                        offchain_writes.push((
+                            req.key().as_ref().to_vec(),
+                            req.value().map(|value| value.as_ref().to_vec()),
+                        ));
+                    }
                     inner = req.resume();
                     continue;
                 }
                 runtime_host::RuntimeHostVm::Offchain(ctx) => Query::Finished {
                     result: Err(Error::ForbiddenHostCall),
                     virtual_machine: ctx.into_prototype(),
+                    storage_changes: None,
+                    offchain_writes: Vec::new(),
+                    transaction_encoded_len: info.transaction_encoded_len,
+                    api_version: Some(info.api_version),
                 },
             };
         }
     }
 }
 
+/// Calls the given [`HostCallHook`], if any, if `inner` corresponds to a call to a host function.
+fn report_host_call(
+    on_host_call: &mut Option<HostCallHook>,
+    inner: &runtime_host::RuntimeHostVm,
+    stage: WasmStage,
+) {
+    let Some(on_host_call) = on_host_call else {
+        return;
+    };
+
+    let kind = match inner {
+        runtime_host::RuntimeHostVm::Finished(_) => return,
+        runtime_host::RuntimeHostVm::StorageGet(_) => HostCallKind::StorageGet,
+        runtime_host::RuntimeHostVm::ClosestDescendantMerkleValue(_) => {
+            HostCallKind::ClosestDescendantMerkleValue
+        }
+        runtime_host::RuntimeHostVm::NextKey(_) => HostCallKind::NextKey,
+        runtime_host::RuntimeHostVm::SignatureVerification(_) => {
+            HostCallKind::SignatureVerification
+        }
+        runtime_host::RuntimeHostVm::OffchainStorageSet(_) => HostCallKind::OffchainStorageSet,
+        runtime_host::RuntimeHostVm::Offchain(_) => HostCallKind::Offchain,
+    };
+
+    on_host_call(&HostCallInfo { kind, stage });
+}
+
+/// Decreases the number of remaining steps by one. Returns `false` if no step remains.
+fn consume_step(remaining_steps: &mut Option<u64>) -> bool {
+    match remaining_steps {
+        None => true,
+        Some(0) => false,
+        Some(n) => {
+            *n -= 1;
+            true
+        }
+    }
+}
+
 struct Stage1 {
     /// Same value as [`Config::source`].
     transaction_source: TransactionSource,
-    /// Same value as [`Config::scale_encoded_transaction`].
+    /// Same value as [`Config::scale_encoded_transaction`], concatenated.
+    ///
+    /// The chunks can't be kept as they are, as they borrow from the [`Config`] while [`Query`]
+    /// doesn't have any lifetime or type parameter. Adding such a parameter to [`Query`] and all
+    /// the types it contains would only avoid this copy, as the runtime host copies the
+    /// transaction into the memory of the virtual machine regardless.
     scale_encoded_transaction: Vec<u8>,
     /// Same value as [`Config::max_log_level`].
     max_log_level: u32,
+    /// Same value as [`Config::calculate_trie_changes`].
+    calculate_trie_changes: bool,
+    /// Number of interruptions of the execution still allowed. See [`Config::max_wasm_steps`].
+    remaining_steps: Option<u64>,
+    /// List of main trie storage values that have been injected so far.
+    storage_accesses: Vec<StorageAccess>,
+    /// Same value as [`Config::storage_cache`].
+    storage_cache: Option<Arc<dyn StorageCache>>,
+    /// Same value as [`Config::allow_empty_provides`].
+    allow_empty_provides: bool,
+    /// Writes to the offchain storage performed so far, or `None` if
+    /// [`Config::capture_offchain_writes`] is `false`.
+    offchain_writes: Option<Vec<OffchainWrite>>,
+    /// See [`Query::Finished::transaction_encoded_len`].
+    transaction_encoded_len: usize,
+    /// Same value as [`Config::on_host_call`].
+    on_host_call: Option<HostCallHook>,
 }
 
-struct Stage2 {}
+struct Stage2 {
+    /// Same value as [`Config::calculate_trie_changes`].
+    calculate_trie_changes: bool,
+    /// Number of interruptions of the execution still allowed. See [`Config::max_wasm_steps`].
+    remaining_steps: Option<u64>,
+    /// Version of the `TaggedTransactionQueue` API of the runtime.
+    api_version: u32,
+    /// List of main trie storage values that have been injected so far.
+    storage_accesses: Vec<StorageAccess>,
+    /// Same value as [`Config::storage_cache`].
+    storage_cache: Option<Arc<dyn StorageCache>>,
+    /// Same value as [`Config::allow_empty_provides`].
+    allow_empty_provides: bool,
+    /// Writes to the offchain storage performed so far, or `None` if
+    /// [`Config::capture_offchain_writes`] is `false`.
+    offchain_writes: Option<Vec<OffchainWrite>>,
+    /// See [`Query::Finished::transaction_encoded_len`].
+    transaction_encoded_len: usize,
+    /// Same value as [`Config::on_host_call`].
+    on_host_call: Option<HostCallHook>,
+}
 
 /// Loading a storage value is required in order to continue.
 #[must_use]
@@ -591,15 +1943,24 @@ pub struct StorageGet(StorageGetInner);
 enum StorageGetInner {
     Stage1(runtime_host::StorageGet, Stage1),
     Stage2(runtime_host::StorageGet, Stage2),
+    /// Built through [`Query::mock_storage_get`].
+    #[cfg(any(test, feature = "testing"))]
+    Mock(
+        Vec<u8>,
+        alloc::boxed::Box<dyn FnOnce(Option<Vec<u8>>) -> Query + Send>,
+    ),
 }
 
 impl StorageGet {
     /// Returns the key whose value must be passed to [`StorageGet::inject_value`].
     pub fn key(&'_ self) -> impl AsRef<[u8]> + '_ {
-        match &self.0 {
-            StorageGetInner::Stage1(inner, _) => either::Left(inner.key()),
-            StorageGetInner::Stage2(inner, _) => either::Right(inner.key()),
-        }
+        let key: either::Either<either::Either<_, _>, &[u8]> = match &self.0 {
+            StorageGetInner::Stage1(inner, _) => either::Left(either::Left(inner.key())),
+            StorageGetInner::Stage2(inner, _) => either::Left(either::Right(inner.key())),
+            #[cfg(any(test, feature = "testing"))]
+            StorageGetInner::Mock(key, _) => either::Right(key),
+        };
+        key
     }
 
     /// If `Some`, read from the given child trie. If `None`, read from the main trie.
@@ -607,6 +1968,8 @@ impl StorageGet {
         match &self.0 {
             StorageGetInner::Stage1(inner, _) => inner.child_trie().map(either::Left),
             StorageGetInner::Stage2(inner, _) => inner.child_trie().map(either::Right),
+            #[cfg(any(test, feature = "testing"))]
+            StorageGetInner::Mock(..) => None,
         }
     }
 
@@ -615,17 +1978,74 @@ impl StorageGet {
         self,
         value: Option<(impl Iterator<Item = impl AsRef<[u8]>>, TrieEntryVersion)>,
     ) -> Query {
+        // The value is copied in order to be kept in the list of storage accesses.
+        // See [`Query::into_prototype_and_accessed`].
+        let value = value.map(|(value, version)| {
+            let value = value.fold(Vec::new(), |mut a, b| {
+                a.extend_from_slice(b.as_ref());
+                a
+            });
+            (value, version)
+        });
+        let value_to_inject = value
+            .as_ref()
+            .map(|(value, version)| (iter::once(value), *version));
+
         match self.0 {
-            StorageGetInner::Stage1(inner, stage) => {
-                Query::from_step1(inner.inject_value(value), stage)
+            StorageGetInner::Stage1(inner, mut stage) => {
+                record_storage_access(&mut stage.storage_accesses, &inner, &value);
+                Query::from_step1(inner.inject_value(value_to_inject), stage)
             }
-            StorageGetInner::Stage2(inner, stage) => {
-                Query::from_step2(inner.inject_value(value), stage)
+            StorageGetInner::Stage2(inner, mut stage) => {
+                record_storage_access(&mut stage.storage_accesses, &inner, &value);
+                Query::from_step2(inner.inject_value(value_to_inject), stage)
             }
+            #[cfg(any(test, feature = "testing"))]
+            StorageGetInner::Mock(_, on_value) => on_value(value.map(|(value, _)| value)),
         }
     }
 }
 
+/// Looks up the value requested by the given [`runtime_host::StorageGet`] in the cache, if any.
+fn cached_storage_value(
+    storage_cache: Option<&dyn StorageCache>,
+    get: &runtime_host::StorageGet,
+) -> Option<Option<(Vec<u8>, TrieEntryVersion)>> {
+    let child_trie = get.child_trie();
+    storage_cache?.get(
+        child_trie.as_ref().map(|child_trie| child_trie.as_ref()),
+        get.key().as_ref(),
+    )
+}
+
+/// Adds the value about to be injected in the given [`runtime_host::StorageGet`] to the list of
+/// storage accesses, if it concerns the main trie.
+fn record_storage_access(
+    storage_accesses: &mut Vec<StorageAccess>,
+    get: &runtime_host::StorageGet,
+    value: &Option<(Vec<u8>, TrieEntryVersion)>,
+) {
+    if get.child_trie().is_none() {
+        storage_accesses.push((
+            get.key().as_ref().to_vec(),
+            value.as_ref().map(|(value, _)| value.clone()),
+        ));
+    }
+}
+
+/// Adds the writes to the offchain storage performed through the offchain indexing host
+/// functions to the given list.
+fn record_offchain_writes(
+    offchain_writes: &mut Vec<OffchainWrite>,
+    storage_changes: &runtime_host::StorageChanges,
+) {
+    offchain_writes.extend(
+        storage_changes
+            .offchain_storage_changes_iter_ordered()
+            .map(|(key, value)| (key.to_vec(), value.map(|value| value.to_vec()))),
+    );
+}
+
 /// Obtaining the Merkle value of the closest descendant of a trie node is required in order
 /// to continue.
 #[must_use]
@@ -752,22 +2172,121 @@ impl NextKey {
     }
 }
 
+/// Verifying whether a signature is correct is required in order to continue.
+#[must_use]
+pub struct SignatureVerification(SignatureVerificationInner);
+
+enum SignatureVerificationInner {
+    Stage1(runtime_host::SignatureVerification, Stage1),
+    Stage2(runtime_host::SignatureVerification, Stage2),
+}
+
+impl SignatureVerification {
+    /// Returns the message that the signature is expected to sign.
+    pub fn message(&'_ self) -> impl AsRef<[u8]> + '_ {
+        match &self.0 {
+            SignatureVerificationInner::Stage1(inner, _) => either::Left(inner.message()),
+            SignatureVerificationInner::Stage2(inner, _) => either::Right(inner.message()),
+        }
+    }
+
+    /// Returns the signature.
+    ///
+    /// > **Note**: Be aware that this signature is untrusted input and might not be part of the
+    /// >           set of valid signatures.
+    pub fn signature(&'_ self) -> impl AsRef<[u8]> + '_ {
+        match &self.0 {
+            SignatureVerificationInner::Stage1(inner, _) => either::Left(inner.signature()),
+            SignatureVerificationInner::Stage2(inner, _) => either::Right(inner.signature()),
+        }
+    }
+
+    /// Returns the public key the signature is against.
+    ///
+    /// > **Note**: Be aware that this public key is untrusted input and might not be part of the
+    /// >           set of valid public keys.
+    pub fn public_key(&'_ self) -> impl AsRef<[u8]> + '_ {
+        match &self.0 {
+            SignatureVerificationInner::Stage1(inner, _) => either::Left(inner.public_key()),
+            SignatureVerificationInner::Stage2(inner, _) => either::Right(inner.public_key()),
+        }
+    }
+
+    /// Verify the signature. Returns `true` if it is valid.
+    pub fn is_valid(&self) -> bool {
+        match &self.0 {
+            SignatureVerificationInner::Stage1(inner, _) => inner.is_valid(),
+            SignatureVerificationInner::Stage2(inner, _) => inner.is_valid(),
+        }
+    }
+
+    /// Verify the signature and resume execution.
+    ///
+    /// This is the default behavior, and should be used unless the signature is verified by
+    /// other means.
+    pub fn verify_and_resume(self) -> Query {
+        match self.0 {
+            SignatureVerificationInner::Stage1(inner, stage1) => {
+                Query::from_step1(inner.verify_and_resume(), stage1)
+            }
+            SignatureVerificationInner::Stage2(inner, stage2) => {
+                Query::from_step2(inner.verify_and_resume(), stage2)
+            }
+        }
+    }
+
+    /// Resume the execution assuming that the signature is valid.
+    ///
+    /// This can be used in situations where the signature has been verified by other means,
+    /// for example by using hardware acceleration.
+    pub fn resume_success(self) -> Query {
+        match self.0 {
+            SignatureVerificationInner::Stage1(inner, stage1) => {
+                Query::from_step1(inner.resume_success(), stage1)
+            }
+            SignatureVerificationInner::Stage2(inner, stage2) => {
+                Query::from_step2(inner.resume_success(), stage2)
+            }
+        }
+    }
+
+    /// Resume the execution assuming that the signature is invalid.
+    ///
+    /// This can be used in situations where the signature has been verified by other means,
+    /// for example by using hardware acceleration.
+    pub fn resume_failed(self) -> Query {
+        match self.0 {
+            SignatureVerificationInner::Stage1(inner, stage1) => {
+                Query::from_step1(inner.resume_failed(), stage1)
+            }
+            SignatureVerificationInner::Stage2(inner, stage2) => {
+                Query::from_step2(inner.resume_failed(), stage2)
+            }
+        }
+    }
+}
+
 // `nom` parser functions can be found below.
 
 fn transaction_validity(
     bytes: &[u8],
-) -> nom::IResult<&[u8], Result<ValidTransaction, TransactionValidityError>> {
+) -> nom::IResult<&[u8], Result<ValidTransaction, TransactionValidityError>, FieldError> {
+    // Once the tag has been decoded, the parsing is cut in order for the error to be reported
+    // with the context of the field that has failed to decode.
     nom::error::context(
         "transaction validity",
         nom::branch::alt((
             nom::combinator::map(
-                nom::sequence::preceded(nom::bytes::streaming::tag(&[0]), valid_transaction),
+                nom::sequence::preceded(
+                    nom::bytes::complete::tag(&[0]),
+                    nom::combinator::cut(valid_transaction),
+                ),
                 Ok,
             ),
             nom::combinator::map(
                 nom::sequence::preceded(
-                    nom::bytes::streaming::tag(&[1]),
-                    transaction_validity_error,
+                    nom::bytes::complete::tag(&[1]),
+                    nom::combinator::cut(nom::combinator::complete(transaction_validity_error)),
                 ),
                 Err,
             ),
@@ -775,16 +2294,31 @@ fn transaction_validity(
     )(bytes)
 }
 
-fn valid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], ValidTransaction> {
+fn valid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], ValidTransaction, FieldError> {
     nom::error::context(
         "valid transaction",
         nom::combinator::map(
             nom::sequence::tuple((
-                nom::number::streaming::le_u64,
-                tags,
-                tags,
-                nom::combinator::map_opt(nom::number::streaming::le_u64, NonZeroU64::new),
-                util::nom_bool_decode,
+                nom::error::context(
+                    "priority",
+                    nom::combinator::complete(nom::combinator::map(
+                        nom::number::streaming::le_u64,
+                        Priority,
+                    )),
+                ),
+                nom::error::context("requires", nom::combinator::complete(tags)),
+                nom::error::context("provides", nom::combinator::complete(tags)),
+                nom::error::context(
+                    "longevity",
+                    nom::combinator::complete(nom::combinator::map_opt(
+                        nom::number::streaming::le_u64,
+                        NonZeroU64::new,
+                    )),
+                ),
+                nom::error::context(
+                    "propagate",
+                    nom::combinator::complete(util::nom_bool_decode),
+                ),
             )),
             |(priority, requires, provides, longevity, propagate)| ValidTransaction {
                 priority,
@@ -797,7 +2331,9 @@ fn valid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], ValidTransaction> {
     )(bytes)
 }
 
-fn transaction_validity_error(bytes: &[u8]) -> nom::IResult<&[u8], TransactionValidityError> {
+fn transaction_validity_error(
+    bytes: &[u8],
+) -> nom::IResult<&[u8], TransactionValidityError, FieldError> {
     nom::error::context(
         "transaction validity error",
         nom::branch::alt((
@@ -813,7 +2349,7 @@ fn transaction_validity_error(bytes: &[u8]) -> nom::IResult<&[u8], TransactionVa
     )(bytes)
 }
 
-fn invalid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], InvalidTransaction> {
+fn invalid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], InvalidTransaction, FieldError> {
     nom::error::context(
         "invalid transaction",
         nom::branch::alt((
@@ -851,11 +2387,18 @@ fn invalid_transaction(bytes: &[u8]) -> nom::IResult<&[u8], InvalidTransaction>
             nom::combinator::map(nom::bytes::streaming::tag(&[9]), |_| {
                 InvalidTransaction::MandatoryDispatch
             }),
+            nom::combinator::map_opt(nom::bytes::streaming::take(1u32), |n: &[u8]| {
+                if n[0] >= 10 {
+                    Some(InvalidTransaction::Unknown(n[0]))
+                } else {
+                    None
+                }
+            }),
         )),
     )(bytes)
 }
 
-fn unknown_transaction(bytes: &[u8]) -> nom::IResult<&[u8], UnknownTransaction> {
+fn unknown_transaction(bytes: &[u8]) -> nom::IResult<&[u8], UnknownTransaction, FieldError> {
     nom::error::context(
         "unknown transaction",
         nom::branch::alt((
@@ -876,15 +2419,61 @@ fn unknown_transaction(bytes: &[u8]) -> nom::IResult<&[u8], UnknownTransaction>
     )(bytes)
 }
 
-fn tags(bytes: &[u8]) -> nom::IResult<&[u8], Vec<Vec<u8>>> {
-    nom::combinator::flat_map(crate::util::nom_scale_compact_usize, |num_elems| {
-        nom::multi::many_m_n(
-            num_elems,
-            num_elems,
-            nom::combinator::map(
-                nom::multi::length_data(crate::util::nom_scale_compact_usize),
-                |tag| tag.to_owned(),
-            ),
-        )
-    })(bytes)
+fn tags(bytes: &[u8]) -> nom::IResult<&[u8], Vec<Vec<u8>>, FieldError> {
+    let (bytes, num_elems) = crate::util::nom_scale_compact_usize(bytes)?;
+
+    // Each tag is prefixed with its length, and thus occupies at least one byte. Checking this
+    // beforehand avoids allocating memory based on an untrusted number.
+    if num_elems > bytes.len() {
+        return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+            bytes,
+            nom::error::ErrorKind::TooLarge,
+        )));
+    }
+
+    nom::multi::many_m_n(
+        num_elems,
+        num_elems,
+        nom::combinator::map(
+            nom::multi::length_data(crate::util::nom_scale_compact_usize),
+            |tag| tag.to_owned(),
+        ),
+    )(bytes)
+}
+
+/// Error type used by the parsers above. Holds the innermost context that corresponds to a
+/// [`DecodeErrorField`].
+#[derive(Debug)]
+struct FieldError {
+    /// `None` if the error hasn't happened within any context corresponding to a field.
+    field: Option<DecodeErrorField>,
+}
+
+impl<I> nom::error::ParseError<I> for FieldError {
+    fn from_error_kind(_: I, _: nom::error::ErrorKind) -> Self {
+        FieldError { field: None }
+    }
+
+    fn append(_: I, _: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<I> nom::error::ContextError<I> for FieldError {
+    fn add_context(_: I, context: &'static str, mut other: Self) -> Self {
+        // Contexts are added from the innermost to the outermost. Only the innermost one is
+        // kept.
+        if other.field.is_none() {
+            other.field = match context {
+                "priority" => Some(DecodeErrorField::Priority),
+                "requires" => Some(DecodeErrorField::Requires),
+                "provides" => Some(DecodeErrorField::Provides),
+                "longevity" => Some(DecodeErrorField::Longevity),
+                "propagate" => Some(DecodeErrorField::Propagate),
+                "transaction validity error" => Some(DecodeErrorField::ValidityError),
+                _ => None,
+            };
+        }
+        other
+    }
 }
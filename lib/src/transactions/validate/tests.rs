@@ -18,7 +18,7 @@
 #![cfg(test)]
 
 use crate::{executor, header, trie::proof_decode};
-use core::iter;
+use core::{iter, num::NonZeroU64};
 
 #[test]
 fn validate_from_proof() {
@@ -40,6 +40,7 @@ fn validate_from_proof() {
     .unwrap();
 
     let scale_encoded_header = hex::decode(test.block_header).unwrap();
+    let transaction_bytes = hex::decode(test.transaction_bytes).unwrap();
 
     let main_trie_root = header::decode(&scale_encoded_header, 4).unwrap().state_root;
 
@@ -47,14 +48,30 @@ fn validate_from_proof() {
         runtime,
         scale_encoded_header: &scale_encoded_header,
         block_number_bytes: 4,
-        scale_encoded_transaction: iter::once(&hex::decode(test.transaction_bytes).unwrap()),
+        scale_encoded_transaction: iter::once(&transaction_bytes),
         source: super::TransactionSource::External,
         max_log_level: 0,
+        calculate_trie_changes: false,
+        max_wasm_steps: None,
+        pre_initialized_changes: None,
+        storage_cache: None,
+        allow_empty_provides: false,
+        capture_offchain_writes: false,
+        on_host_call: None,
     });
 
     loop {
         match validation_in_progress {
-            super::Query::Finished { result: Ok(_), .. } => return, // Success,
+            super::Query::Finished {
+                result: Ok(_),
+                api_version,
+                transaction_encoded_len,
+                ..
+            } => {
+                assert_eq!(api_version, Some(3));
+                assert_eq!(transaction_encoded_len, transaction_bytes.len());
+                return; // Success
+            }
             super::Query::Finished { result: Err(_), .. } => panic!(),
             super::Query::StorageGet(get) => {
                 let value = call_proof
@@ -81,8 +98,1305 @@ fn validate_from_proof() {
                     .unwrap();
                 validation_in_progress = mv.inject_merkle_value(value);
             }
+            super::Query::SignatureVerification(sig) => {
+                validation_in_progress = sig.verify_and_resume();
+            }
+        }
+    }
+}
+
+#[test]
+fn on_host_call() {
+    let test: Test = serde_json::from_str(include_str!("./test-fixture.json")).unwrap();
+
+    let runtime = executor::host::HostVmPrototype::new(executor::host::Config {
+        module: hex::decode(&test.runtime_code).unwrap(),
+        heap_pages: executor::DEFAULT_HEAP_PAGES,
+        allow_unresolved_imports: true,
+        exec_hint: executor::vm::ExecHint::Oneshot,
+    })
+    .unwrap();
+
+    let call_proof = proof_decode::decode_and_verify_proof(proof_decode::Config {
+        proof: hex::decode(&test.call_proof).unwrap(),
+    })
+    .unwrap();
+
+    let scale_encoded_header = hex::decode(test.block_header).unwrap();
+    let transaction_bytes = hex::decode(test.transaction_bytes).unwrap();
+
+    let main_trie_root = header::decode(&scale_encoded_header, 4).unwrap().state_root;
+
+    let host_calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let mut validation_in_progress = super::validate_transaction(
+        super::Config::builder(
+            runtime,
+            &scale_encoded_header,
+            iter::once(&transaction_bytes),
+        )
+        .on_host_call(Some(Box::new({
+            let host_calls = host_calls.clone();
+            move |info: &super::HostCallInfo| host_calls.lock().unwrap().push(info.clone())
+        })))
+        .build(),
+    );
+
+    // Since no storage cache is used, each call to a host function must lead to a query.
+    let mut queries = Vec::new();
+    loop {
+        match validation_in_progress {
+            super::Query::Finished { result, .. } => {
+                result.unwrap().unwrap();
+                break;
+            }
+            super::Query::StorageGet(get) => {
+                queries.push(super::HostCallKind::StorageGet);
+                let value = call_proof
+                    .storage_value(main_trie_root, get.key().as_ref())
+                    .unwrap();
+                validation_in_progress =
+                    get.inject_value(value.map(|(val, ver)| (iter::once(val), ver)));
+            }
+            super::Query::NextKey(nk) => {
+                queries.push(super::HostCallKind::NextKey);
+                let next_key = call_proof
+                    .next_key(
+                        main_trie_root,
+                        &nk.key().collect::<Vec<_>>(),
+                        nk.or_equal(),
+                        &nk.prefix().collect::<Vec<_>>(),
+                        nk.branch_nodes(),
+                    )
+                    .unwrap();
+                validation_in_progress = nk.inject_key(next_key.map(|k| k.iter().copied()));
+            }
+            super::Query::ClosestDescendantMerkleValue(mv) => {
+                queries.push(super::HostCallKind::ClosestDescendantMerkleValue);
+                let value = call_proof
+                    .closest_descendant_merkle_value(main_trie_root, &mv.key().collect::<Vec<_>>())
+                    .unwrap();
+                validation_in_progress = mv.inject_merkle_value(value);
+            }
+            super::Query::SignatureVerification(sig) => {
+                queries.push(super::HostCallKind::SignatureVerification);
+                validation_in_progress = sig.verify_and_resume();
+            }
+        }
+    }
+
+    let host_calls = host_calls.lock().unwrap();
+    assert!(!queries.is_empty());
+    assert_eq!(
+        host_calls.iter().map(|info| info.kind).collect::<Vec<_>>(),
+        queries
+    );
+    // The runtime of the fixture supports version 3 of the API, in which case
+    // `Core_initialize_block` isn't called.
+    assert!(host_calls
+        .iter()
+        .all(|info| info.stage == super::WasmStage::Validation));
+}
+
+#[test]
+fn max_wasm_steps() {
+    let test: Test = serde_json::from_str(include_str!("./test-fixture.json")).unwrap();
+
+    let runtime = executor::host::HostVmPrototype::new(executor::host::Config {
+        module: hex::decode(&test.runtime_code).unwrap(),
+        heap_pages: executor::DEFAULT_HEAP_PAGES,
+        allow_unresolved_imports: true,
+        exec_hint: executor::vm::ExecHint::Oneshot,
+    })
+    .unwrap();
+
+    let call_proof = proof_decode::decode_and_verify_proof(proof_decode::Config {
+        proof: hex::decode(&test.call_proof).unwrap(),
+    })
+    .unwrap();
+
+    let scale_encoded_header = hex::decode(test.block_header).unwrap();
+
+    let main_trie_root = header::decode(&scale_encoded_header, 4).unwrap().state_root;
+
+    let mut validation_in_progress = super::validate_transaction(super::Config {
+        runtime,
+        scale_encoded_header: &scale_encoded_header,
+        block_number_bytes: 4,
+        scale_encoded_transaction: iter::once(&hex::decode(test.transaction_bytes).unwrap()),
+        source: super::TransactionSource::External,
+        max_log_level: 0,
+        calculate_trie_changes: false,
+        max_wasm_steps: Some(NonZeroU64::new(2).unwrap()),
+        pre_initialized_changes: None,
+        storage_cache: None,
+        allow_empty_provides: false,
+        capture_offchain_writes: false,
+        on_host_call: None,
+    });
+
+    let mut num_steps = 0;
+    loop {
+        match validation_in_progress {
+            super::Query::Finished {
+                result: Err(super::Error::ExecutionLimitExceeded),
+                ..
+            } => break,
+            super::Query::Finished { .. } => panic!(),
+            super::Query::StorageGet(get) => {
+                let value = call_proof
+                    .storage_value(main_trie_root, get.key().as_ref())
+                    .unwrap();
+                validation_in_progress =
+                    get.inject_value(value.map(|(val, ver)| (iter::once(val), ver)));
+            }
+            super::Query::NextKey(nk) => {
+                let next_key = call_proof
+                    .next_key(
+                        main_trie_root,
+                        &nk.key().collect::<Vec<_>>(),
+                        nk.or_equal(),
+                        &nk.prefix().collect::<Vec<_>>(),
+                        nk.branch_nodes(),
+                    )
+                    .unwrap();
+                validation_in_progress = nk.inject_key(next_key.map(|k| k.iter().copied()));
+            }
+            super::Query::ClosestDescendantMerkleValue(mv) => {
+                let value = call_proof
+                    .closest_descendant_merkle_value(main_trie_root, &mv.key().collect::<Vec<_>>())
+                    .unwrap();
+                validation_in_progress = mv.inject_merkle_value(value);
+            }
+            super::Query::SignatureVerification(sig) => {
+                validation_in_progress = sig.verify_and_resume();
+            }
         }
+
+        num_steps += 1;
+    }
+
+    assert_eq!(num_steps, 2);
+}
+
+#[test]
+fn into_prototype_and_accessed() {
+    let test: Test = serde_json::from_str(include_str!("./test-fixture.json")).unwrap();
+
+    let runtime = executor::host::HostVmPrototype::new(executor::host::Config {
+        module: hex::decode(&test.runtime_code).unwrap(),
+        heap_pages: executor::DEFAULT_HEAP_PAGES,
+        allow_unresolved_imports: true,
+        exec_hint: executor::vm::ExecHint::Oneshot,
+    })
+    .unwrap();
+
+    let call_proof = proof_decode::decode_and_verify_proof(proof_decode::Config {
+        proof: hex::decode(&test.call_proof).unwrap(),
+    })
+    .unwrap();
+
+    let scale_encoded_header = hex::decode(test.block_header).unwrap();
+
+    let main_trie_root = header::decode(&scale_encoded_header, 4).unwrap().state_root;
+
+    let mut validation_in_progress = super::validate_transaction(super::Config {
+        runtime,
+        scale_encoded_header: &scale_encoded_header,
+        block_number_bytes: 4,
+        scale_encoded_transaction: iter::once(&hex::decode(test.transaction_bytes).unwrap()),
+        source: super::TransactionSource::External,
+        max_log_level: 0,
+        calculate_trie_changes: false,
+        max_wasm_steps: None,
+        pre_initialized_changes: None,
+        storage_cache: None,
+        allow_empty_provides: false,
+        capture_offchain_writes: false,
+        on_host_call: None,
+    });
+
+    // Inject a single storage value, then cancel the validation.
+    let (key, value) = loop {
+        match validation_in_progress {
+            super::Query::StorageGet(get) => {
+                let key = get.key().as_ref().to_vec();
+                let value = call_proof.storage_value(main_trie_root, &key).unwrap();
+                let value_copy = value.map(|(val, _)| val.to_vec());
+                validation_in_progress =
+                    get.inject_value(value.map(|(val, ver)| (iter::once(val), ver)));
+                break (key, value_copy);
+            }
+            super::Query::NextKey(nk) => {
+                let next_key = call_proof
+                    .next_key(
+                        main_trie_root,
+                        &nk.key().collect::<Vec<_>>(),
+                        nk.or_equal(),
+                        &nk.prefix().collect::<Vec<_>>(),
+                        nk.branch_nodes(),
+                    )
+                    .unwrap();
+                validation_in_progress = nk.inject_key(next_key.map(|k| k.iter().copied()));
+            }
+            super::Query::ClosestDescendantMerkleValue(mv) => {
+                validation_in_progress = mv.resume_unknown();
+            }
+            super::Query::SignatureVerification(sig) => {
+                validation_in_progress = sig.verify_and_resume();
+            }
+            super::Query::Finished { .. } => panic!(),
+        }
+    };
+
+    let (_, accessed) = validation_in_progress.into_prototype_and_accessed();
+    assert_eq!(accessed, vec![(key, value)]);
+}
+
+#[test]
+fn storage_cache() {
+    struct ProofCache {
+        proof: proof_decode::DecodedTrieProof<Vec<u8>>,
+        main_trie_root: [u8; 32],
+    }
+
+    impl super::StorageCache for ProofCache {
+        fn get(
+            &self,
+            child_trie: Option<&[u8]>,
+            key: &[u8],
+        ) -> Option<Option<(Vec<u8>, super::TrieEntryVersion)>> {
+            if child_trie.is_some() {
+                return None;
+            }
+
+            let value = self.proof.storage_value(&self.main_trie_root, key).ok()?;
+            Some(value.map(|(value, version)| (value.to_vec(), version)))
+        }
+    }
+
+    let test: Test = serde_json::from_str(include_str!("./test-fixture.json")).unwrap();
+
+    let runtime = executor::host::HostVmPrototype::new(executor::host::Config {
+        module: hex::decode(&test.runtime_code).unwrap(),
+        heap_pages: executor::DEFAULT_HEAP_PAGES,
+        allow_unresolved_imports: true,
+        exec_hint: executor::vm::ExecHint::Oneshot,
+    })
+    .unwrap();
+
+    let call_proof = || {
+        proof_decode::decode_and_verify_proof(proof_decode::Config {
+            proof: hex::decode(&test.call_proof).unwrap(),
+        })
+        .unwrap()
+    };
+
+    let scale_encoded_header = hex::decode(&test.block_header).unwrap();
+
+    let main_trie_root = *header::decode(&scale_encoded_header, 4).unwrap().state_root;
+
+    let config = super::Config::builder(
+        runtime,
+        &scale_encoded_header,
+        iter::once(hex::decode(&test.transaction_bytes).unwrap()),
+    )
+    .storage_cache(Some(alloc::sync::Arc::new(ProofCache {
+        proof: call_proof(),
+        main_trie_root,
+    })))
+    .build();
+
+    // All the storage values are found in the cache.
+    let call_proof = call_proof();
+    let mut validation_in_progress = super::validate_transaction(config);
+    loop {
+        match validation_in_progress {
+            super::Query::Finished { result: Ok(_), .. } => break,
+            super::Query::Finished { result: Err(_), .. } => panic!(),
+            super::Query::StorageGet(_) => panic!(),
+            super::Query::NextKey(nk) => {
+                let next_key = call_proof
+                    .next_key(
+                        &main_trie_root,
+                        &nk.key().collect::<Vec<_>>(),
+                        nk.or_equal(),
+                        &nk.prefix().collect::<Vec<_>>(),
+                        nk.branch_nodes(),
+                    )
+                    .unwrap();
+                validation_in_progress = nk.inject_key(next_key.map(|k| k.iter().copied()));
+            }
+            super::Query::ClosestDescendantMerkleValue(mv) => {
+                validation_in_progress = mv.resume_unknown();
+            }
+            super::Query::SignatureVerification(sig) => {
+                validation_in_progress = sig.verify_and_resume();
+            }
+        }
+    }
+}
+
+#[test]
+fn decode_unknown_invalid_transaction() {
+    assert_eq!(
+        super::decode_validate_transaction_return_value(&[1, 0, 9]).unwrap(),
+        Err(super::TransactionValidityError::Invalid(
+            super::InvalidTransaction::MandatoryDispatch
+        ))
+    );
+    assert_eq!(
+        super::decode_validate_transaction_return_value(&[1, 0, 10]).unwrap(),
+        Err(super::TransactionValidityError::Invalid(
+            super::InvalidTransaction::Unknown(10)
+        ))
+    );
+    assert_eq!(
+        super::decode_validate_transaction_return_value(&[1, 0, 255]).unwrap(),
+        Err(super::TransactionValidityError::Invalid(
+            super::InvalidTransaction::Unknown(255)
+        ))
+    );
+}
+
+#[test]
+fn decode_partial() {
+    let mut encoded = vec![0];
+    encoded.extend_from_slice(&5u64.to_le_bytes());
+    encoded.push(0);
+    encoded.extend_from_slice(&[1 << 2, 1 << 2, 0xaa]);
+    encoded.extend_from_slice(&64u64.to_le_bytes());
+    encoded.push(1);
+
+    let with_remainder = [&encoded[..], &[1, 2, 3]].concat();
+    let (result, remainder) =
+        super::decode_validate_transaction_return_value_partial(&with_remainder).unwrap();
+    assert_eq!(remainder, &[1, 2, 3]);
+    let valid = result.unwrap();
+    assert_eq!(valid.priority, super::Priority(5));
+    assert!(valid.requires.is_empty());
+    assert_eq!(valid.provides, vec![vec![0xaa]]);
+    assert!(super::decode_validate_transaction_return_value(&encoded).is_ok());
+
+    // Every truncation of the input fails to decode without panicking.
+    for len in 0..encoded.len() {
+        assert!(super::decode_validate_transaction_return_value_partial(&encoded[..len]).is_err());
+    }
+
+    // Number of tags that can't possibly fit in the input.
+    let mut encoded = vec![0];
+    encoded.extend_from_slice(&5u64.to_le_bytes());
+    encoded.extend_from_slice(crate::util::encode_scale_compact_usize(1 << 29).as_ref());
+    assert!(super::decode_validate_transaction_return_value_partial(&encoded).is_err());
+}
+
+#[test]
+fn by_priority() {
+    let transaction = |priority: u64, provides: u8| super::ValidTransaction {
+        priority: super::Priority(priority),
+        requires: Vec::new(),
+        provides: vec![vec![provides]],
+        longevity: NonZeroU64::new(64).unwrap(),
+        propagate: true,
+    };
+
+    let low = transaction(1, 9);
+    let high = transaction(2, 0);
+    let high_other = transaction(2, 1);
+    assert!(super::ByPriority(&high) > super::ByPriority(&low));
+
+    // Ties are broken by the provided tags.
+    assert!(super::ByPriority(&high_other) > super::ByPriority(&high));
+    assert_eq!(super::ByPriority(&high), super::ByPriority(&high.clone()));
+
+    // Equality matches the equality of the transactions.
+    let mut not_propagated = high.clone();
+    not_propagated.propagate = false;
+    assert_ne!(super::ByPriority(&high), super::ByPriority(&not_propagated));
+}
+
+#[test]
+fn decode_framed() {
+    // An invalid transaction followed with a valid one, as if embedded in a larger buffer.
+    let mut buffer = vec![1, 0, 3];
+    buffer.push(0);
+    buffer.extend_from_slice(&5u64.to_le_bytes());
+    buffer.extend_from_slice(&[0, 1 << 2, 1 << 2, 0xaa]);
+    buffer.extend_from_slice(&64u64.to_le_bytes());
+    buffer.push(1);
+
+    let (first, consumed) =
+        super::decode_validate_transaction_return_value_framed(&buffer).unwrap();
+    assert_eq!(consumed, 3);
+    assert_eq!(
+        first,
+        Err(super::TransactionValidityError::Invalid(
+            super::InvalidTransaction::Stale
+        ))
+    );
+
+    let (second, consumed2) =
+        super::decode_validate_transaction_return_value_framed(&buffer[consumed..]).unwrap();
+    assert_eq!(consumed + consumed2, buffer.len());
+    assert_eq!(second.unwrap().priority, super::Priority(5));
+
+    // The strict version still rejects the trailing data.
+    assert_eq!(
+        super::decode_validate_transaction_return_value(&buffer)
+            .unwrap_err()
+            .field,
+        super::DecodeErrorField::TrailingData
+    );
+}
+
+#[test]
+fn decode_error_field() {
+    let mut encoded = vec![0];
+    encoded.extend_from_slice(&5u64.to_le_bytes());
+    encoded.push(0);
+    encoded.extend_from_slice(&[1 << 2, 1 << 2, 0xaa]);
+    encoded.extend_from_slice(&64u64.to_le_bytes());
+    encoded.push(1);
+
+    let field = |bytes: &[u8]| {
+        super::decode_validate_transaction_return_value(bytes)
+            .unwrap_err()
+            .field
+    };
+
+    for (len, expected) in [
+        (0, super::DecodeErrorField::ValidityTag),
+        (1, super::DecodeErrorField::Priority),
+        (8, super::DecodeErrorField::Priority),
+        (9, super::DecodeErrorField::Requires),
+        (10, super::DecodeErrorField::Provides),
+        (12, super::DecodeErrorField::Provides),
+        (13, super::DecodeErrorField::Longevity),
+        (21, super::DecodeErrorField::Propagate),
+    ] {
+        assert_eq!(field(&encoded[..len]), expected);
+    }
+
+    assert_eq!(
+        field(&[&encoded[..], &[0]].concat()),
+        super::DecodeErrorField::TrailingData
+    );
+    assert_eq!(field(&[2]), super::DecodeErrorField::UnknownValidityTag(2));
+    assert_eq!(
+        field(&[&[2], &encoded[1..]].concat()),
+        super::DecodeErrorField::UnknownValidityTag(2)
+    );
+    assert_eq!(
+        field(&[0xff]),
+        super::DecodeErrorField::UnknownValidityTag(0xff)
+    );
+    assert_eq!(
+        super::decode_validate_transaction_return_value(&[2])
+            .unwrap_err()
+            .to_string(),
+        "Failed to decode validity tag (unexpected value 2)"
+    );
+    assert_eq!(field(&[1, 5]), super::DecodeErrorField::ValidityError);
+
+    // A longevity of 0 is invalid.
+    encoded[13..21].copy_from_slice(&0u64.to_le_bytes());
+    assert_eq!(field(&encoded), super::DecodeErrorField::Longevity);
+}
+
+#[test]
+fn oversized_tags_counts() {
+    let field = |bytes: &[u8]| {
+        super::decode_validate_transaction_return_value(bytes)
+            .unwrap_err()
+            .field
+    };
+
+    let mut prefix = vec![0];
+    prefix.extend_from_slice(&5u64.to_le_bytes());
+
+    // Number of tags that doesn't fit in the remaining input. Decoding must fail without
+    // attempting to allocate that many elements.
+    for count in [
+        &[0xff, 0xff, 0xff, 0xff][..],
+        &[0x03, 0xff, 0xff, 0xff, 0xff][..],
+        &[0x13, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f][..],
+    ] {
+        let mut encoded = prefix.clone();
+        encoded.extend_from_slice(count);
+        encoded.extend_from_slice(&[0; 16]);
+        assert_eq!(field(&encoded), super::DecodeErrorField::Requires);
+        assert!(super::decode_validate_transaction_return_value_partial(&encoded).is_err());
+    }
+
+    // Length of a single tag that doesn't fit in the remaining input.
+    let mut encoded = prefix.clone();
+    encoded.push(1 << 2);
+    encoded.extend_from_slice(&[0x03, 0xff, 0xff, 0xff, 0xff]);
+    encoded.extend_from_slice(&[0; 16]);
+    assert_eq!(field(&encoded), super::DecodeErrorField::Requires);
+}
+
+#[test]
+fn decode_random_inputs() {
+    use rand::{Rng as _, SeedableRng as _};
+
+    // Deterministic fuzzing of the decoding functions, biased towards inputs that start with a
+    // valid prefix. The only check is that decoding doesn't panic.
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(0);
+    for _ in 0..20000 {
+        let mut encoded = vec![0];
+        encoded.extend_from_slice(&rng.gen::<u64>().to_le_bytes());
+        let len = rng.gen_range(0..48);
+        encoded.extend((0..len).map(|_| rng.gen::<u8>()));
+        let start = rng.gen_range(0..=9);
+
+        let _ = super::decode_validate_transaction_return_value(&encoded[start..]);
+        let _ = super::decode_validate_transaction_return_value_partial(&encoded[start..]);
+    }
+}
+
+#[test]
+fn all_known_variants() {
+    let mut num_invalid = 0;
+    let mut num_unknown = 0;
+
+    for code in 0..=u8::MAX {
+        match super::decode_validate_transaction_return_value(&[1, 0, code]) {
+            Ok(Err(super::TransactionValidityError::Invalid(
+                super::InvalidTransaction::Custom(_) | super::InvalidTransaction::Unknown(_),
+            )))
+            | Err(_) => {}
+            Ok(Err(super::TransactionValidityError::Invalid(err))) => {
+                assert!(super::InvalidTransaction::all_known().any(|e| e == err));
+                num_invalid += 1;
+            }
+            _ => panic!(),
+        }
+
+        match super::decode_validate_transaction_return_value(&[1, 1, code]) {
+            Ok(Err(super::TransactionValidityError::Unknown(
+                super::UnknownTransaction::Custom(_),
+            )))
+            | Err(_) => {}
+            Ok(Err(super::TransactionValidityError::Unknown(err))) => {
+                assert!(super::UnknownTransaction::all_known().any(|e| e == err));
+                num_unknown += 1;
+            }
+            _ => panic!(),
+        }
+    }
+
+    assert_eq!(num_invalid, super::InvalidTransaction::all_known().len());
+    assert_eq!(num_unknown, super::UnknownTransaction::all_known().len());
+}
+
+#[test]
+fn supported_validation_api_version() {
+    for (apis, expected) in [
+        (&[("TaggedTransactionQueue", 2)][..], Some(2)),
+        (&[("TaggedTransactionQueue", 3)][..], Some(3)),
+        (&[("TaggedTransactionQueue", 1)][..], None),
+        (&[("Core", 4)][..], None),
+    ] {
+        assert_eq!(
+            super::supported_validation_api_version(&minimal_runtime("", apis)),
+            expected
+        );
+    }
+}
+
+#[test]
+fn runtime_trap() {
+    let runtime = minimal_runtime(
+        r#"(func (export "TaggedTransactionQueue_validate_transaction")
+            (param i32 i32) (result i64)
+            unreachable)"#,
+        &[("TaggedTransactionQueue", 3)],
+    );
+
+    match super::validate_transaction(
+        super::Config::builder(runtime, &dummy_header(), iter::once(&[0u8][..])).build(),
+    ) {
+        super::Query::Finished {
+            result: Err(err @ super::Error::RuntimeTrap { .. }),
+            ..
+        } => {
+            assert_eq!(err.wasm_error_stage(), Some(super::WasmStage::Validation));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn wasm_error_stage() {
+    assert_eq!(
+        super::Error::WasmVmReadWrite(executor::runtime_host::ErrorDetail::LogsTooLong)
+            .wasm_error_stage(),
+        Some(super::WasmStage::InitializeBlock)
+    );
+    assert_eq!(
+        super::Error::WasmVmReadOnly(executor::runtime_host::ErrorDetail::LogsTooLong)
+            .wasm_error_stage(),
+        Some(super::WasmStage::Validation)
+    );
+    assert_eq!(super::Error::MissingCoreApi.wasm_error_stage(), None);
+}
+
+#[test]
+fn provides_fingerprint() {
+    let valid = |provides: Vec<Vec<u8>>| super::ValidTransaction {
+        priority: super::Priority(0),
+        requires: Vec::new(),
+        provides,
+        longevity: NonZeroU64::new(1).unwrap(),
+        propagate: true,
+    };
+
+    assert_eq!(
+        valid(vec![vec![1], vec![2, 3]]).provides_fingerprint(),
+        valid(vec![vec![2, 3], vec![1]]).provides_fingerprint()
+    );
+    assert_ne!(
+        valid(vec![vec![1], vec![2, 3]]).provides_fingerprint(),
+        valid(vec![vec![1, 2], vec![3]]).provides_fingerprint()
+    );
+    assert_ne!(
+        valid(vec![vec![1]]).provides_fingerprint(),
+        valid(vec![vec![1], vec![1]]).provides_fingerprint()
+    );
+}
+
+#[test]
+fn differs_materially() {
+    let base = super::ValidTransaction {
+        priority: super::Priority(1000),
+        requires: vec![vec![1], vec![2]],
+        provides: vec![vec![3]],
+        longevity: NonZeroU64::new(64).unwrap(),
+        propagate: true,
+    };
+
+    assert!(!base.differs_materially(&base));
+    assert!(!base.differs_materially(&super::ValidTransaction {
+        priority: super::Priority(1050),
+        requires: vec![vec![2], vec![1]],
+        longevity: NonZeroU64::new(12).unwrap(),
+        ..base.clone()
+    }));
+    assert!(base.differs_materially(&super::ValidTransaction {
+        priority: super::Priority(1500),
+        ..base.clone()
+    }));
+    assert!(base.differs_materially(&super::ValidTransaction {
+        requires: vec![vec![1]],
+        ..base.clone()
+    }));
+    assert!(base.differs_materially(&super::ValidTransaction {
+        provides: vec![vec![4]],
+        ..base.clone()
+    }));
+    assert!(base.differs_materially(&super::ValidTransaction {
+        propagate: false,
+        ..base.clone()
+    }));
+}
+
+#[test]
+fn transaction_source_scale_byte() {
+    for source in [
+        super::TransactionSource::InBlock,
+        super::TransactionSource::Local,
+        super::TransactionSource::External,
+    ] {
+        assert_eq!(
+            super::TransactionSource::from_scale_byte(source.scale_byte()),
+            Some(source)
+        );
+
+        let parameters = super::validate_transaction_runtime_parameters_v3(
+            iter::once(&[0xaa]),
+            source,
+            &[0; 32],
+        )
+        .fold(Vec::new(), |mut out, chunk| {
+            out.extend_from_slice(chunk.as_ref());
+            out
+        });
+        assert_eq!(parameters[0], source.scale_byte());
+    }
+
+    assert_eq!(super::TransactionSource::from_scale_byte(3), None);
+}
+
+#[test]
+fn should_propagate() {
+    let transaction = super::ValidTransaction {
+        priority: super::Priority(1000),
+        requires: Vec::new(),
+        provides: vec![vec![1]],
+        longevity: NonZeroU64::new(64).unwrap(),
+        propagate: true,
+    };
+
+    assert!(transaction.should_propagate(super::TransactionSource::External));
+    assert!(transaction.should_propagate(super::TransactionSource::InBlock));
+    assert!(!transaction.should_propagate(super::TransactionSource::Local));
+
+    let transaction = super::ValidTransaction {
+        propagate: false,
+        ..transaction
+    };
+    assert!(!transaction.should_propagate(super::TransactionSource::External));
+}
+
+#[test]
+fn typed_tags() {
+    // Tags made of an account and a little-endian nonce.
+    let tag = |account: u8, nonce: u32| {
+        let mut tag = vec![account; 32];
+        tag.extend_from_slice(&nonce.to_le_bytes());
+        tag
+    };
+    let decode = |tag: &[u8]| -> Result<([u8; 32], u32), ()> {
+        if tag.len() != 36 {
+            return Err(());
+        }
+        Ok((
+            <[u8; 32]>::try_from(&tag[..32]).unwrap(),
+            u32::from_le_bytes(<[u8; 4]>::try_from(&tag[32..]).unwrap()),
+        ))
+    };
+
+    let transaction = super::ValidTransaction {
+        priority: super::Priority(1000),
+        requires: vec![tag(1, 4)],
+        provides: vec![tag(1, 5), tag(2, 0)],
+        longevity: NonZeroU64::new(64).unwrap(),
+        propagate: true,
+    };
+    assert_eq!(
+        transaction.provides_as(decode),
+        Ok(vec![([1; 32], 5), ([2; 32], 0)])
+    );
+    assert_eq!(transaction.requires_as(decode), Ok(vec![([1; 32], 4)]));
+
+    let transaction = super::ValidTransaction {
+        provides: vec![tag(1, 5), vec![0]],
+        ..transaction
+    };
+    assert_eq!(transaction.provides_as(decode), Err(()));
+}
+
+#[test]
+fn is_retriable() {
+    assert!(
+        super::TransactionValidityError::Invalid(super::InvalidTransaction::Future).is_retriable()
+    );
+    assert!(
+        super::TransactionValidityError::Unknown(super::UnknownTransaction::CannotLookup)
+            .is_retriable()
+    );
+    assert!(
+        !super::TransactionValidityError::Invalid(super::InvalidTransaction::Stale).is_retriable()
+    );
+    assert!(
+        !super::TransactionValidityError::Invalid(super::InvalidTransaction::BadProof)
+            .is_retriable()
+    );
+    assert!(
+        !super::TransactionValidityError::Invalid(super::InvalidTransaction::Payment)
+            .is_retriable()
+    );
+}
+
+#[test]
+fn should_requeue() {
+    for reason in super::InvalidTransaction::all_known() {
+        assert_eq!(
+            super::TransactionValidityError::Invalid(reason.clone()).should_requeue(),
+            matches!(
+                reason,
+                super::InvalidTransaction::Future | super::InvalidTransaction::ExhaustsResources
+            )
+        );
+    }
+    for reason in super::UnknownTransaction::all_known() {
+        assert!(!super::TransactionValidityError::Unknown(reason).should_requeue());
+    }
+
+    // Contrary to `is_retriable`, a block being full isn't a reason to validate again.
+    let exhausts_resources =
+        super::TransactionValidityError::Invalid(super::InvalidTransaction::ExhaustsResources);
+    assert!(exhausts_resources.should_requeue());
+    assert!(!exhausts_resources.is_retriable());
+}
+
+#[test]
+fn user_message() {
+    assert_eq!(
+        super::TransactionValidityError::Invalid(super::InvalidTransaction::Payment).user_message(),
+        "Insufficient balance to pay fees"
+    );
+
+    // Each known reason has a dedicated message, distinct from the generic ones.
+    let generic = [
+        super::TransactionValidityError::Invalid(super::InvalidTransaction::Custom(3))
+            .user_message(),
+        super::TransactionValidityError::Unknown(super::UnknownTransaction::Custom(3))
+            .user_message(),
+    ];
+    for error in super::InvalidTransaction::all_known()
+        .map(super::TransactionValidityError::Invalid)
+        .chain(super::UnknownTransaction::all_known().map(super::TransactionValidityError::Unknown))
+    {
+        assert!(!generic.contains(&error.user_message()));
+    }
+}
+
+#[test]
+fn invalid_transaction_display_code() {
+    assert_eq!(
+        super::InvalidTransaction::Stale.to_string(),
+        "Stale (code: 3)"
+    );
+    assert_eq!(
+        super::InvalidTransaction::Custom(42).to_string(),
+        "Other reason (code: 7, custom code: 42)"
+    );
+    assert_eq!(
+        super::InvalidTransaction::Unknown(12).to_string(),
+        "Unknown reason (code: 12)"
+    );
+
+    for reason in super::InvalidTransaction::all_known() {
+        let expected = format!("(code: {})", reason.as_code());
+        assert!(reason.to_string().ends_with(&expected));
+    }
+}
+
+#[test]
+fn missing_core_api() {
+    let runtime = runtime_without_core_api();
+    let scale_encoded_header = dummy_header();
+
+    match super::validate_transaction(super::Config {
+        runtime,
+        scale_encoded_header: &scale_encoded_header,
+        block_number_bytes: 4,
+        scale_encoded_transaction: iter::once(&[0u8][..]),
+        source: super::TransactionSource::External,
+        max_log_level: 0,
+        calculate_trie_changes: false,
+        max_wasm_steps: None,
+        pre_initialized_changes: None,
+        storage_cache: None,
+        allow_empty_provides: false,
+        capture_offchain_writes: false,
+        on_host_call: None,
+    }) {
+        super::Query::Finished {
+            result: Err(super::Error::MissingCoreApi),
+            api_version: Some(2),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn invalid_block_number_bytes() {
+    let scale_encoded_header = dummy_header();
+
+    let config = super::Config::builder(
+        runtime_without_core_api(),
+        &scale_encoded_header,
+        iter::once(&[0u8][..]),
+    )
+    .block_number_bytes(9)
+    .build();
+
+    match super::validate_transaction(config) {
+        super::Query::Finished {
+            result: Err(super::Error::InvalidBlockNumberBytes),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn transaction_encoded_len() {
+    let scale_encoded_header = dummy_header();
+
+    // The length is reported even if the validation fails early, and the chunks of the
+    // transaction are summed.
+    let config = super::Config::builder(
+        runtime_without_core_api(),
+        &scale_encoded_header,
+        [&[0u8; 5][..], &[1u8; 7][..]].into_iter(),
+    )
+    .block_number_bytes(9)
+    .build();
+
+    match super::validate_transaction(config) {
+        super::Query::Finished {
+            transaction_encoded_len,
+            ..
+        } => assert_eq!(transaction_encoded_len, 12),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn detect_block_number_bytes() {
+    // The number of bytes is detected from the header, and the validation goes on.
+    let scale_encoded_header = dummy_header();
+    let config = super::Config::builder(
+        runtime_without_core_api(),
+        &scale_encoded_header,
+        iter::once(&[0u8][..]),
+    )
+    .block_number_bytes(0)
+    .build();
+    match super::validate_transaction(config) {
+        super::Query::Finished {
+            result: Err(super::Error::MissingCoreApi),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+
+    // The header can't be decoded no matter the number of bytes.
+    let config =
+        super::Config::builder(runtime_without_core_api(), &[0; 5], iter::once(&[0u8][..]))
+            .block_number_bytes(0)
+            .build();
+    match super::validate_transaction(config) {
+        super::Query::Finished {
+            result: Err(super::Error::BlockNumberBytesUnknown),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn allow_empty_provides() {
+    // Runtime that considers every transaction as valid, without any provided tag. The output
+    // is written at offset 4096 and is 20 bytes long: a priority of 0, no required
+    // or provided tag, a longevity of 64, and `propagate` set to `true`.
+    let runtime = minimal_runtime(
+        r#"(func (export "TaggedTransactionQueue_validate_transaction")
+            (param i32 i32) (result i64)
+            (i64.store (i32.const 4096) (i64.const 0))
+            (i64.store (i32.const 4099) (i64.const 0))
+            (i64.store (i32.const 4107) (i64.const 64))
+            (i32.store8 (i32.const 4115) (i32.const 1))
+            (i64.const 0x0000001400001000))"#,
+        &[("TaggedTransactionQueue", 3)],
+    );
+
+    match super::validate_transaction(
+        super::Config::builder(runtime.clone(), &dummy_header(), iter::once(&[0u8][..])).build(),
+    ) {
+        super::Query::Finished {
+            result: Err(super::Error::EmptyProvidedTags),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+
+    match super::validate_transaction(
+        super::Config::builder(runtime, &dummy_header(), iter::once(&[0u8][..]))
+            .allow_empty_provides(true)
+            .build(),
+    ) {
+        super::Query::Finished {
+            result: Ok(Ok(valid)),
+            ..
+        } => {
+            assert!(valid.provides.is_empty());
+            assert!(valid.propagate);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn capture_offchain_writes() {
+    // Same runtime as in `allow_empty_provides`, except that it also sets the offchain storage
+    // key `foo` to `bar` and clears the offchain storage key `baz`.
+    let runtime = minimal_runtime(
+        r#"(import "env" "ext_offchain_index_set_version_1"
+            (func $index_set (param i64 i64)))
+        (import "env" "ext_offchain_index_clear_version_1"
+            (func $index_clear (param i64)))
+        (func (export "TaggedTransactionQueue_validate_transaction")
+            (param i32 i32) (result i64)
+            (i32.store (i32.const 8192) (i32.const 0x6f6f66))
+            (i32.store (i32.const 8200) (i32.const 0x726162))
+            (i32.store (i32.const 8208) (i32.const 0x7a6162))
+            (call $index_set (i64.const 0x0000000300002000) (i64.const 0x0000000300002008))
+            (call $index_clear (i64.const 0x0000000300002010))
+            (i64.store (i32.const 4096) (i64.const 0))
+            (i64.store (i32.const 4099) (i64.const 0))
+            (i64.store (i32.const 4107) (i64.const 64))
+            (i32.store8 (i32.const 4115) (i32.const 1))
+            (i64.const 0x0000001400001000))"#,
+        &[("TaggedTransactionQueue", 3)],
+    );
+
+    match super::validate_transaction(
+        super::Config::builder(runtime.clone(), &dummy_header(), iter::once(&[0u8][..]))
+            .allow_empty_provides(true)
+            .build(),
+    ) {
+        super::Query::Finished {
+            result: Ok(Ok(_)),
+            offchain_writes,
+            ..
+        } => assert!(offchain_writes.is_empty()),
+        _ => panic!(),
+    }
+
+    match super::validate_transaction(
+        super::Config::builder(runtime, &dummy_header(), iter::once(&[0u8][..]))
+            .allow_empty_provides(true)
+            .capture_offchain_writes(true)
+            .build(),
+    ) {
+        super::Query::Finished {
+            result: Ok(Ok(_)),
+            offchain_writes,
+            ..
+        } => {
+            assert_eq!(
+                offchain_writes,
+                vec![
+                    (b"baz".to_vec(), None),
+                    (b"foo".to_vec(), Some(b"bar".to_vec()))
+                ]
+            );
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn pre_check_transaction() {
+    assert!(matches!(
+        super::pre_check_transaction(&[]),
+        Err(super::Error::EmptyTransaction)
+    ));
+
+    // Length prefixes of 3 followed by 3 and 4 bytes. Trailing data is tolerated.
+    assert!(super::pre_check_transaction(&[3 << 2, 1, 2, 3]).is_ok());
+    assert!(super::pre_check_transaction(&[3 << 2, 1, 2, 3, 4]).is_ok());
+    assert!(super::pre_check_transaction(&[0]).is_ok());
+
+    // Length prefix larger than the rest of the transaction.
+    assert!(matches!(
+        super::pre_check_transaction(&[3 << 2, 1, 2]),
+        Err(super::Error::InvalidTransactionLength)
+    ));
+    assert!(matches!(
+        super::pre_check_transaction(&[0xff, 0xff, 0xff, 0xff]),
+        Err(super::Error::InvalidTransactionLength)
+    ));
+
+    // Truncated length prefix.
+    assert!(matches!(
+        super::pre_check_transaction(&[0b01]),
+        Err(super::Error::InvalidTransactionLength)
+    ));
+}
+
+#[test]
+fn config_builder() {
+    let scale_encoded_header = dummy_header();
+
+    let config = super::Config::builder(
+        runtime_without_core_api(),
+        &scale_encoded_header,
+        iter::once(&[0u8][..]),
+    )
+    .source(super::TransactionSource::Local)
+    .max_wasm_steps(NonZeroU64::new(10))
+    .build();
+
+    assert_eq!(config.block_number_bytes, 4);
+    assert_eq!(config.source, super::TransactionSource::Local);
+    assert_eq!(config.max_log_level, 0);
+    assert!(!config.calculate_trie_changes);
+    assert_eq!(config.max_wasm_steps, NonZeroU64::new(10));
+
+    match super::validate_transaction(config) {
+        super::Query::Finished {
+            result: Err(super::Error::MissingCoreApi),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn pre_initialized_changes() {
+    // Since `Core_initialize_block` isn't called, the missing `Core` API isn't a problem. The
+    // validation function, however, doesn't exist either.
+    match super::validate_transaction(
+        super::Config::builder(
+            runtime_without_core_api(),
+            &dummy_header(),
+            iter::once(&[0u8][..]),
+        )
+        .pre_initialized_changes(Some(executor::storage_diff::TrieDiff::empty()))
+        .build(),
+    ) {
+        super::Query::Finished {
+            result: Err(super::Error::WasmStart(_)),
+            api_version: Some(2),
+            ..
+        } => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn borrowed_runtime() {
+    let runtime = runtime_without_core_api();
+    let scale_encoded_header = dummy_header();
+
+    // The same runtime can be used multiple times in a row.
+    for _ in 0..2 {
+        match super::validate_transaction_borrowed(
+            super::Config::builder(&runtime, &scale_encoded_header, iter::once(&[0u8][..])).build(),
+        ) {
+            super::Query::Finished {
+                result: Err(super::Error::MissingCoreApi),
+                ..
+            } => {}
+            _ => panic!(),
+        }
+    }
+}
+
+#[test]
+fn priority_saturates() {
+    let high = super::Priority(u64::MAX - 5);
+    assert_eq!(
+        high.saturating_add(super::Priority(3)),
+        super::Priority(u64::MAX - 2)
+    );
+    assert_eq!(high.saturating_add(high), super::Priority(u64::MAX));
+
+    let sum: super::Priority = [high, super::Priority(10), super::Priority(1)]
+        .into_iter()
+        .sum();
+    assert_eq!(sum, super::Priority(u64::MAX));
+
+    assert!(super::Priority(2) > super::Priority(1));
+    assert_eq!(u64::from(super::Priority::from(42)), 42);
+}
+
+#[test]
+fn mock_storage_get() {
+    let query = super::Query::mock_storage_get(b"foo".to_vec(), |value| {
+        assert_eq!(value.as_deref(), Some(&b"bar"[..]));
+        super::Query::mock_storage_get(b"baz".to_vec(), |value| {
+            assert!(value.is_none());
+            super::Query::mock_storage_get(Vec::new(), |_| unreachable!())
+        })
+    });
+
+    let super::Query::StorageGet(get) = query else {
+        panic!()
+    };
+    assert!(get.child_trie().is_none());
+    assert_eq!(get.key().as_ref(), b"foo");
+
+    let super::Query::StorageGet(get) = get.inject_value(Some((
+        [&b"b"[..], &b"ar"[..]].into_iter(),
+        super::TrieEntryVersion::V1,
+    ))) else {
+        panic!()
+    };
+    assert_eq!(get.key().as_ref(), b"baz");
+
+    let super::Query::StorageGet(get) =
+        get.inject_value(None::<(iter::Empty<Vec<u8>>, super::TrieEntryVersion)>)
+    else {
+        panic!()
+    };
+    assert!(get.key().as_ref().is_empty());
+}
+
+/// Builds a minimal runtime that pretends to support version 2 of `TaggedTransactionQueue`, but
+/// doesn't support `Core`.
+fn runtime_without_core_api() -> executor::host::HostVmPrototype {
+    minimal_runtime("", &[("TaggedTransactionQueue", 2)])
+}
+
+/// Builds a minimal runtime containing the given additional Wasm items, and that pretends to
+/// support the given list of runtime APIs.
+fn minimal_runtime(items: &str, apis: &[(&str, u32)]) -> executor::host::HostVmPrototype {
+    let mut module = wat::parse_str(format!(
+        r#"
+    (module
+        (import "env" "memory" (memory 0))
+        {items}
+        (global (export "__heap_base") i32 (i32.const 0))
+    )
+    "#
+    ))
+    .unwrap();
+
+    let mut runtime_version = Vec::new();
+    for name in ["foo", "bar"] {
+        runtime_version
+            .extend_from_slice(crate::util::encode_scale_compact_usize(name.len()).as_ref());
+        runtime_version.extend_from_slice(name.as_bytes());
+    }
+    runtime_version.extend_from_slice(&[0; 12]);
+    runtime_version.extend_from_slice(crate::util::encode_scale_compact_usize(0).as_ref());
+    runtime_version.extend_from_slice(&[0; 5]);
+
+    let mut runtime_apis = Vec::new();
+    for (api_name, version) in apis {
+        runtime_apis.extend_from_slice(&executor::host::runtime_version::hash_api_name(api_name));
+        runtime_apis.extend_from_slice(&version.to_le_bytes());
+    }
+
+    for (section_name, content) in [
+        (&b"runtime_version"[..], runtime_version),
+        (&b"runtime_apis"[..], runtime_apis),
+    ] {
+        let mut section = Vec::new();
+        section.extend(crate::util::leb128::encode_usize(section_name.len()));
+        section.extend_from_slice(section_name);
+        section.extend_from_slice(&content);
+        module.push(0);
+        module.extend(crate::util::leb128::encode_usize(section.len()));
+        module.extend_from_slice(&section);
+    }
+
+    executor::host::HostVmPrototype::new(executor::host::Config {
+        module: &module,
+        heap_pages: executor::DEFAULT_HEAP_PAGES,
+        allow_unresolved_imports: false,
+        exec_hint: executor::vm::ExecHint::Oneshot,
+    })
+    .unwrap()
+}
+
+fn dummy_header() -> Vec<u8> {
+    header::HeaderRef {
+        parent_hash: &[0; 32],
+        number: 1,
+        extrinsics_root: &[0; 32],
+        state_root: &[0; 32],
+        digest: header::DigestRef::empty(),
     }
+    .scale_encoding_vec(4)
 }
 
 // Serde structs used to decode the test fixtures.
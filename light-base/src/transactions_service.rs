@@ -1322,6 +1322,13 @@ async fn validate_transaction<TPlat: PlatformRef>(
         scale_encoded_transaction: iter::once(scale_encoded_transaction),
         source,
         max_log_level: 0,
+        calculate_trie_changes: false,
+        max_wasm_steps: None,
+        pre_initialized_changes: None,
+        storage_cache: None,
+        allow_empty_provides: false,
+        capture_offchain_writes: false,
+        on_host_call: None,
     });
 
     loop {
@@ -1329,6 +1336,7 @@ async fn validate_transaction<TPlat: PlatformRef>(
             validate::Query::Finished {
                 result: Ok(Ok(success)),
                 virtual_machine,
+                ..
             } => {
                 runtime_call_lock.unlock(virtual_machine);
                 break Ok(success);
@@ -1336,6 +1344,7 @@ async fn validate_transaction<TPlat: PlatformRef>(
             validate::Query::Finished {
                 result: Ok(Err(invalid)),
                 virtual_machine,
+                ..
             } => {
                 runtime_call_lock.unlock(virtual_machine);
                 break Err(ValidationError::InvalidOrError(InvalidOrError::Invalid(
@@ -1345,6 +1354,7 @@ async fn validate_transaction<TPlat: PlatformRef>(
             validate::Query::Finished {
                 result: Err(error),
                 virtual_machine,
+                ..
             } => {
                 runtime_call_lock.unlock(virtual_machine);
                 break Err(ValidationError::InvalidOrError(
@@ -1412,6 +1422,9 @@ async fn validate_transaction<TPlat: PlatformRef>(
                 };
                 validation_in_progress = nk.inject_key(next_key.map(|k| k.iter().copied()));
             }
+            validate::Query::SignatureVerification(sig) => {
+                validation_in_progress = sig.verify_and_resume();
+            }
         }
     }
 }